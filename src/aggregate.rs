@@ -0,0 +1,344 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io;
+
+use query::Query;
+use relation::Relation;
+use schema::{Schema, AttrType};
+use tuple::Tuple;
+
+/// Function that summarises the values of an attribute, as in SQL.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Function {
+    /// Number of tuples for `COUNT(*)`, or of values that aren't NULL.
+    Count,
+    Min,
+    Max,
+    /// Sum of an `int` or `float` attribute's values.
+    Sum,
+    /// Mean of an `int` or `float` attribute's values, as a float.
+    Avg,
+}
+
+/// Aggregate over the values of the attribute at an index, or over whole tuples for `COUNT(*)`.
+/// Only `COUNT` applies to whole tuples, and NULL values are ignored.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Aggregate {
+    pub function: Function,
+    pub attr: Option<usize>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum ParseError {
+    /// An aggregate that isn't written as `<function>(<attribute>)`.
+    InvalidAggregate(String),
+    UnknownFunction(String),
+    UnknownAttribute(String),
+    /// A `SUM` or `AVG` of an attribute that isn't an `int` or `float`.
+    NotNumeric(String),
+}
+
+impl Function {
+    /// Parse a function's name, in any case.
+    pub fn parse(name: &str) -> Option<Function> {
+        match name.to_ascii_lowercase().as_str() {
+            "count" => Some(Function::Count),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "sum" => Some(Function::Sum),
+            "avg" => Some(Function::Avg),
+            _ => None
+        }
+    }
+}
+
+impl Aggregate {
+    /// Parse an aggregate like `count(*)`, `MIN(age)` or `sum(1)`, with the attribute
+    /// given by name or by position from 0.
+    pub fn parse(input: &str, schema: &Schema) -> Result<Aggregate, ParseError> {
+        let invalid = || ParseError::InvalidAggregate(input.to_string());
+        let input = input.trim();
+        let open = try!(input.find('(').ok_or_else(invalid));
+        let arg = try!(input[open + 1..].strip_suffix(')').ok_or_else(invalid)).trim();
+        let name = input[..open].trim();
+        let function = try!(Function::parse(name)
+            .ok_or_else(|| ParseError::UnknownFunction(name.to_string())));
+        let attr = match (function, arg) {
+            (Function::Count, "*") => None,
+            _ => Some(try!(parse_attr(arg, schema)))
+        };
+        if let (Function::Sum, Some(i)) | (Function::Avg, Some(i)) = (function, attr) {
+            if !is_numeric(schema.attrs[i].attr_type) {
+                return Err(ParseError::NotNumeric(schema.attrs[i].name.clone()));
+            }
+        }
+        Ok(Aggregate { function: function, attr: attr })
+    }
+
+    /// Parse comma-separated aggregates.
+    pub fn parse_list(input: &str, schema: &Schema) -> Result<Vec<Aggregate>, ParseError> {
+        input.split(',').map(|aggregate| Aggregate::parse(aggregate, schema)).collect()
+    }
+}
+
+/// Parse an attribute by name or by position from 0.
+pub fn parse_attr(input: &str, schema: &Schema) -> Result<usize, ParseError> {
+    let input = input.trim();
+    let index = match input.parse::<usize>() {
+        Ok(i) if i < schema.attrs.len() => Some(i),
+        Ok(_) => None,
+        Err(_) => schema.index_of(input)
+    };
+    index.ok_or_else(|| ParseError::UnknownAttribute(input.to_string()))
+}
+
+/// Parse comma-separated attributes to group by, by name or by position from 0.
+pub fn parse_group_by(input: &str, schema: &Schema) -> Result<Vec<usize>, ParseError> {
+    input.split(',').map(|attr| parse_attr(attr, schema)).collect()
+}
+
+fn is_numeric(attr_type: AttrType) -> bool {
+    attr_type == AttrType::Int || attr_type == AttrType::Float
+}
+
+/// Running value of an aggregate for one group.
+#[derive(Debug, Clone)]
+enum State {
+    Count(u64),
+    Min(Option<String>),
+    Max(Option<String>),
+    /// Integers are summed exactly, and floats as floats.
+    SumInt(Option<i128>),
+    SumFloat(Option<f64>),
+    Avg(f64, u64),
+}
+
+impl State {
+    fn new(aggregate: &Aggregate, schema: &Schema) -> State {
+        let attr_type = aggregate.attr.map(|i| schema.attrs[i].attr_type);
+        match (aggregate.function, attr_type) {
+            (Function::Count, _) => State::Count(0),
+            (Function::Min, _) => State::Min(None),
+            (Function::Max, _) => State::Max(None),
+            (Function::Sum, Some(AttrType::Int)) => State::SumInt(None),
+            (Function::Sum, _) => State::SumFloat(None),
+            (Function::Avg, _) => State::Avg(0.0, 0),
+        }
+    }
+
+    /// Take a tuple's value of the aggregate's attribute into account, or the tuple itself
+    /// if the aggregate has no attribute.
+    fn add(&mut self, value: Option<&str>, attr: Option<usize>, schema: &Schema) {
+        let (i, value) = match (attr, value) {
+            (Some(i), Some(value)) => (i, value),
+            // `COUNT(*)` counts every tuple, and NULL values count for nothing.
+            (None, _) => {
+                if let State::Count(ref mut count) = *self {
+                    *count += 1;
+                }
+                return;
+            }
+            (Some(_), None) => return
+        };
+        // Whether the value should replace the current minimum or maximum.
+        let replaces = |current: &Option<String>, wanted: Ordering| {
+            current.as_ref()
+                .is_none_or(|current| schema.compare_values(i, value, current) == wanted)
+        };
+        match *self {
+            State::Count(ref mut count) => *count += 1,
+            State::Min(ref mut min) => if replaces(min, Ordering::Less) {
+                *min = Some(value.to_string());
+            },
+            State::Max(ref mut max) => if replaces(max, Ordering::Greater) {
+                *max = Some(value.to_string());
+            },
+            State::SumInt(ref mut sum) => if let Ok(x) = value.parse::<i64>() {
+                *sum = Some(sum.unwrap_or(0) + x as i128);
+            },
+            State::SumFloat(ref mut sum) => if let Ok(x) = value.parse::<f64>() {
+                *sum = Some(sum.unwrap_or(0.0) + x);
+            },
+            State::Avg(ref mut sum, ref mut count) => if let Ok(x) = value.parse::<f64>() {
+                *sum += x;
+                *count += 1;
+            },
+        }
+    }
+
+    /// The aggregate's value, which is NULL for anything but `COUNT` if there were no values.
+    fn result(&self, attr: Option<usize>, schema: &Schema) -> Option<String> {
+        let canonical = |value: &String| {
+            attr.map_or(value.clone(), |i| schema.attrs[i].attr_type.canonical(value).into_owned())
+        };
+        match *self {
+            State::Count(count) => Some(count.to_string()),
+            State::Min(ref value) | State::Max(ref value) => value.as_ref().map(canonical),
+            State::SumInt(sum) => sum.map(|sum| sum.to_string()),
+            State::SumFloat(sum) => sum.map(|sum| sum.to_string()),
+            State::Avg(_, 0) => None,
+            State::Avg(sum, count) => Some((sum / count as f64).to_string()),
+        }
+    }
+}
+
+/// Aggregates over groups of tuples that have equal values of the `group_by` attributes,
+/// compared as values of their types.
+pub struct Aggregator<'a> {
+    schema: &'a Schema,
+    aggregates: Vec<Aggregate>,
+    group_by: Vec<usize>,
+    /// The aggregates' states by the canonical values of each group.
+    groups: HashMap<Vec<Option<String>>, Vec<State>>,
+}
+
+impl<'a> Aggregator<'a> {
+    pub fn new(schema: &'a Schema, aggregates: Vec<Aggregate>, group_by: Vec<usize>)
+    -> Aggregator<'a>
+    {
+        Aggregator {
+            schema: schema,
+            aggregates: aggregates,
+            group_by: group_by,
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Attributes whose values the aggregates need, including those to group by.
+    pub fn attrs(&self) -> Vec<usize> {
+        let mut attrs: Vec<usize> = self.group_by.iter().cloned()
+            .chain(self.aggregates.iter().filter_map(|aggregate| aggregate.attr))
+            .collect();
+        attrs.sort();
+        attrs.dedup();
+        attrs
+    }
+
+    /// Take a tuple into account.
+    pub fn add(&mut self, tuple: &Tuple) {
+        let schema = self.schema;
+        let key = self.group_by.iter().map(|&i| {
+            tuple.values[i].as_ref().map(|v| schema.attrs[i].attr_type.canonical(v).into_owned())
+        }).collect();
+        let aggregates = &self.aggregates;
+        let states = self.groups.entry(key).or_insert_with(|| {
+            aggregates.iter().map(|aggregate| State::new(aggregate, schema)).collect()
+        });
+        for (state, aggregate) in states.iter_mut().zip(aggregates) {
+            let value = aggregate.attr.and_then(|i| tuple.values[i].as_deref());
+            state.add(value, aggregate.attr, schema);
+        }
+    }
+
+    /// Finish aggregating, with a row per group of the group's values followed by the
+    /// aggregates', ordered by group with NULLs first. Without attributes to group by,
+    /// there's a single row even if there were no tuples.
+    pub fn results(mut self) -> Vec<Tuple> {
+        let (schema, group_by) = (self.schema, &self.group_by);
+        if group_by.is_empty() && self.groups.is_empty() {
+            let states = self.aggregates.iter()
+                .map(|aggregate| State::new(aggregate, schema))
+                .collect();
+            self.groups.insert(vec![], states);
+        }
+        let mut groups: Vec<(Vec<Option<String>>, Vec<State>)> = self.groups.into_iter().collect();
+        groups.sort_by(|(a, _), (b, _)| {
+            a.iter().zip(b).zip(group_by).map(|((a, b), &i)| match (a, b) {
+                (Some(a), Some(b)) => schema.compare_values(i, a, b),
+                (a, b) => a.is_some().cmp(&b.is_some())
+            }).find(|&ordering| ordering != Ordering::Equal).unwrap_or(Ordering::Equal)
+        });
+        let aggregates = &self.aggregates;
+        groups.into_iter().map(|(mut values, states)| {
+            values.extend(states.iter().zip(aggregates).map(|(state, aggregate)| {
+                state.result(aggregate.attr, schema)
+            }));
+            Tuple { values: values }
+        }).collect()
+    }
+}
+
+/// Compute aggregates over the tuples that match a query, grouped as described for
+/// `Aggregator`. Only the values that the query and aggregates need are read, and
+/// `COUNT(*)` alone uses `Relation::count`.
+pub fn aggregate(
+    relation: &Relation,
+    query: &Query,
+    aggregates: Vec<Aggregate>,
+    group_by: Vec<usize>
+) -> io::Result<Vec<Tuple>>
+{
+    // Counting tuples alone needs no values.
+    let count_all = Aggregate { function: Function::Count, attr: None };
+    if group_by.is_empty() && aggregates.iter().all(|&aggregate| aggregate == count_all) {
+        let count = Some(try!(relation.count(query)).to_string());
+        return Ok(vec![Tuple { values: vec![count; aggregates.len()] }]);
+    }
+    let mut aggregator = Aggregator::new(&relation.schema, aggregates, group_by);
+    let attrs = aggregator.attrs();
+    let query = query.clone().project(attrs.clone());
+    for projected in relation.select(&query) {
+        // Put the values back at their attributes' positions.
+        let mut values = vec![None; relation.schema.attrs.len()];
+        for (&i, value) in attrs.iter().zip(try!(projected).values) {
+            values[i] = value;
+        }
+        aggregator.add(&Tuple { values: values });
+    }
+    Ok(aggregator.results())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schema::Schema;
+    use tuple::Tuple;
+
+    #[test]
+    fn parse_aggregates() {
+        let schema = Schema::parse("name,age:int,score:float").unwrap();
+        let input = "count(*), MIN(name),max(1), Sum( score ),avg(age)";
+        let aggregates = Aggregate::parse_list(input, &schema);
+        assert_eq!(aggregates, Ok(vec![
+            Aggregate { function: Function::Count, attr: None },
+            Aggregate { function: Function::Min, attr: Some(0) },
+            Aggregate { function: Function::Max, attr: Some(1) },
+            Aggregate { function: Function::Sum, attr: Some(2) },
+            Aggregate { function: Function::Avg, attr: Some(1) },
+        ]));
+        assert_eq!(parse_group_by("age,0", &schema), Ok(vec![1, 0]));
+
+        let errors = [
+            ("count", ParseError::InvalidAggregate("count".to_string())),
+            ("count(*", ParseError::InvalidAggregate("count(*".to_string())),
+            ("median(age)", ParseError::UnknownFunction("median".to_string())),
+            ("min(*)", ParseError::UnknownAttribute("*".to_string())),
+            ("max(3)", ParseError::UnknownAttribute("3".to_string())),
+            ("sum(name)", ParseError::NotNumeric("name".to_string())),
+        ];
+        for &(input, ref error) in errors.iter() {
+            assert_eq!(Aggregate::parse(input, &schema).as_ref(), Err(error), "{}", input);
+        }
+    }
+
+    #[test]
+    fn aggregate_groups() {
+        let schema = Schema::parse("name,age:int,score:float").unwrap();
+        let input = "count(*),count(score),min(score),max(name),sum(age),avg(score)";
+        let aggregates = Aggregate::parse_list(input, &schema).unwrap();
+        let mut aggregator = Aggregator::new(&schema, aggregates.clone(), vec![1]);
+        for tuple in &["a,30,1.5", "b,030,\\N", "c,\\N,2", "d,4,0.5", "e,30,-1e1"] {
+            aggregator.add(&Tuple::parse(tuple, 3).unwrap());
+        }
+        let results: Vec<String> = aggregator.results().iter().map(Tuple::to_string).collect();
+        assert_eq!(results, vec![
+            "\\N,1,1,2,c,\\N,2",
+            "4,1,1,0.5,d,4,0.5",
+            "30,3,2,-10,e,90,-4.25",
+        ]);
+
+        let aggregator = Aggregator::new(&schema, aggregates, vec![]);
+        let results: Vec<String> = aggregator.results().iter().map(Tuple::to_string).collect();
+        assert_eq!(results, vec!["0,0,\\N,\\N,\\N,\\N"]);
+    }
+}
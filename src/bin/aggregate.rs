@@ -0,0 +1,55 @@
+extern crate malh;
+
+use std::env;
+
+use malh::util::*;
+use malh::relation::*;
+use malh::query::*;
+use malh::aggregate::{self, Aggregate};
+
+fn main() {
+    run_main(aggregate);
+}
+
+const USAGE: &str =
+    "Usage: aggregate <relation name> <aggregates> [query] [--group-by <attributes>]";
+
+fn aggregate() -> Result<(), BoxError> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        try!(Err(USAGE));
+    }
+
+    let relation_name = &args[1];
+    let aggregates_string = &args[2];
+    let mut query_string = None;
+    let mut group_by = None;
+    let mut options = args[3..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--group-by" => group_by = Some(try!(options.next().ok_or(USAGE))),
+            _ if query_string.is_none() => query_string = Some(option),
+            _ => try!(Err(USAGE))
+        }
+    }
+
+    let relation = try!(Relation::open(relation_name, Reading));
+    let aggregates = try!(Aggregate::parse_list(aggregates_string, &relation.schema)
+        .map_err(|e| format!("Error: unable to parse aggregates, reason: {:?}", e)));
+    let group_by = match group_by {
+        Some(group_by) => try!(aggregate::parse_group_by(group_by, &relation.schema)
+            .map_err(|e| format!("Error: unable to parse groups, reason: {:?}", e))),
+        None => vec![]
+    };
+    let query = match query_string {
+        Some(query_string) => try!(Query::parse_with_schema(query_string, &relation.schema)
+            .map_err(|e| format!("Error: unable to parse query, reason: {:?}", e))),
+        None => Query::wildcard(relation.num_attrs)
+    };
+
+    for row in try!(aggregate::aggregate(&relation, &query, aggregates, group_by)) {
+        println!("{}", row.to_string());
+    }
+
+    Ok(())
+}
@@ -4,6 +4,9 @@ use std::env;
 
 use malh::relation::Relation;
 use malh::choice_vec::ChoiceVec;
+use malh::split_policy::SplitPolicy;
+use malh::schema::Schema;
+use malh::page::DEFAULT_PAGE_SIZE;
 use malh::util::*;
 
 fn main() {
@@ -12,20 +15,35 @@ fn main() {
 
 fn create() -> Result<(), BoxError> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 5 {
-        try!(Err("Usage: create <name> <num attrs> <num pages> <choice vec>"));
+    if args.len() < 5 || args.len() > 7 {
+        try!(Err("Usage: create <name> <num attrs | schema> <num pages> <choice vec> \
+                  [split policy] [page size]"));
     }
 
     let relation_name = &args[1];
-    let num_attrs = try!(args[2].parse()
-        .map_err(|_| "Error: non-integer number of attributes"));
+    // Either a number of untyped attributes, or a schema like `name:text,age:int`.
+    let schema = match args[2].parse() {
+        Ok(num_attrs) => Schema::untyped(num_attrs),
+        Err(_) => try!(Schema::parse(&args[2])
+            .map_err(|e| format!("Error: invalid schema, reason: {:?}", e)))
+    };
+    let num_attrs = schema.num_attrs();
     let est_num_pages = try!(args[3].parse()
         .map_err(|_| "Error: non-integer number of pages"));
     let choice_vec = try!(ChoiceVec::parse(&args[4], num_attrs)
         .map_err(|e| format!("Error: invalid choice vector, reason: {:?}", e)));
+    let split_policy = match args.get(5) {
+        Some(policy) => try!(SplitPolicy::parse(policy)
+            .map_err(|e| format!("Error: invalid split policy, reason: {:?}", e))),
+        None => SplitPolicy::default()
+    };
+    let page_size = match args.get(6) {
+        Some(size) => try!(size.parse().map_err(|_| "Error: non-integer page size")),
+        None => DEFAULT_PAGE_SIZE
+    };
 
     try!(
-        Relation::new(relation_name, num_attrs, est_num_pages, choice_vec)
+        Relation::new(relation_name, schema, est_num_pages, choice_vec, split_policy, page_size)
         .map_err(|e| format!("Error: {}", e))
     );
 
@@ -0,0 +1,30 @@
+extern crate malh;
+
+use std::env;
+
+use malh::util::*;
+use malh::relation::*;
+use malh::query::*;
+
+fn main() {
+    run_main(delete);
+}
+
+fn delete() -> Result<(), BoxError> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        try!(Err("Usage: delete <relation name> <query>"));
+    }
+
+    let relation_name = &args[1];
+    let query_string = &args[2];
+
+    let mut relation = try!(Relation::open(relation_name, Writing)
+        .map_err(|e| format!("Error: unable to open relation: {}, reason: {}", relation_name, e)));
+    let query = try!(Query::parse_with_schema(query_string, &relation.schema)
+        .map_err(|e| format!("Error: unable to parse query, reason: {:?}", e)));
+
+    let num_deleted = try!(relation.delete_matching(&query));
+    println!("Deleted {} tuples.", num_deleted);
+    Ok(())
+}
@@ -8,6 +8,9 @@ use std::io::{self, BufRead, BufReader};
 use std::env;
 use std::fs::File;
 
+/// Number of tuples to insert per transaction.
+const BATCH_SIZE: usize = 1000;
+
 fn main() {
     run_main(insert);
 }
@@ -19,8 +22,8 @@ fn insert() -> Result<(), BoxError> {
     }
 
     let relation_name = &args[1];
-    let mut relation = try!(Relation::open(&relation_name, Writing)
-        .map_err(|_| format!("Error: unable to open relation: {}", relation_name)));
+    let mut relation = try!(Relation::open(relation_name, Writing)
+        .map_err(|e| format!("Error: unable to open relation: {}, reason: {}", relation_name, e)));
 
     // OS X's GUI profiler thinks it's too good for IO redirection, so we allow a filename
     // to be specified as an optional second argument.
@@ -33,15 +36,24 @@ fn insert() -> Result<(), BoxError> {
         Box::new(stdin.lock())
     };
 
-    for raw_line in input.lines() {
-        let line = raw_line.unwrap();
+    // Insert in batches, each in a single transaction, to avoid syncing the disk per tuple.
+    // On an error, the relation is dropped with the current batch uncommitted, rolling it back.
+    try!(relation.begin());
+    for (i, raw_line) in input.lines().enumerate() {
+        let line = try!(raw_line);
         let tuple = try!(Tuple::parse(&line, relation.num_attrs)
-            .ok_or_else(|| format!("Error: invalid tuple: {}", line)));
+            .ok_or_else(|| format!("Error: invalid tuple on line {}: {}", i + 1, line)));
 
         try!(relation.insert(tuple).map_err(|e| {
             format!("Error: unable to insert tuple\nReason: {}\nTuple: {}", e, line)
         }));
+
+        if (i + 1) % BATCH_SIZE == 0 {
+            try!(relation.commit());
+            try!(relation.begin());
+        }
     }
+    try!(relation.commit());
 
     println!("All insertions successful.");
     Ok(())
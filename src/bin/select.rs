@@ -5,28 +5,62 @@ use std::env;
 use malh::util::*;
 use malh::relation::*;
 use malh::query::*;
+use malh::sort::SortKey;
 
 fn main() {
     run_main(select);
 }
 
+const USAGE: &str = "Usage: select <relation name> <query> [--columns <attributes>] \
+                      [--order-by <attributes>] [--limit <n>] [--offset <n>]";
+
 fn select() -> Result<(), BoxError> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        try!(Err("Usage: select <relation name> <query>"));
+    if args.len() < 3 {
+        try!(Err(USAGE));
     }
 
     let relation_name = &args[1];
     let query_string = &args[2];
+    let mut columns = None;
+    let mut order_by = None;
+    let mut options = SelectOptions::default();
+    let mut args = args[3..].iter();
+    while let Some(option) = args.next() {
+        match option.as_str() {
+            "--columns" => columns = Some(try!(args.next().ok_or(USAGE))),
+            "--order-by" => order_by = Some(try!(args.next().ok_or(USAGE))),
+            "--limit" => options.limit = Some(try!(parse_count(args.next(), "limit"))),
+            "--offset" => options.offset = try!(parse_count(args.next(), "offset")),
+            _ => try!(Err(USAGE))
+        }
+    }
 
     let relation = try!(Relation::open(relation_name, Reading));
-    let query = try!(Query::parse(query_string, relation.num_attrs)
+    let mut query = try!(Query::parse_with_schema(query_string, &relation.schema)
         .map_err(|e| format!("Error: unable to parse query, reason: {:?}", e)));
+    if let Some(columns) = columns {
+        let columns = try!(Query::parse_columns(columns, &relation.schema)
+            .map_err(|e| format!("Error: unable to parse columns, reason: {:?}", e)));
+        query = query.project(columns);
+    }
+    if let Some(order_by) = order_by {
+        options.order_by = try!(SortKey::parse_list(order_by, &relation.schema)
+            .map_err(|e| format!("Error: unable to parse order, reason: {:?}", e)));
+    }
 
-    for item in relation.select(&query) {
+    for item in try!(relation.select_with(&query, &options)) {
         let tuple = try!(item);
         println!("{}", tuple.to_string());
     }
 
     Ok(())
 }
+
+/// Parse the number given for an option.
+fn parse_count(arg: Option<&String>, name: &str) -> Result<usize, BoxError> {
+    let arg = try!(arg.ok_or(USAGE));
+    let count = try!(arg.parse()
+        .map_err(|_| format!("Error: invalid {} {:?}, expected a number", name, arg)));
+    Ok(count)
+}
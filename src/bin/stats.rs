@@ -3,8 +3,10 @@ extern crate malh;
 use std::env;
 
 use malh::relation::Relation;
+use malh::page::{Page, NO_OVFLOW};
 use malh::relation::OpenMode::Reading;
 use malh::util::*;
+use malh::upgrade;
 
 fn main() {
     run_main(stats);
@@ -19,10 +21,40 @@ fn stats() -> Result<(), BoxError> {
     let r = try!(Relation::open(relation_name, Reading)
         .map_err(|e| format!("Error: {}", e)));
     println!("== Information for relation '{}' ==", relation_name);
+    println!("format version: {}", try!(upgrade::stored_version(relation_name)));
     println!("# of attributes: {}", r.num_attrs);
+    for (i, attr) in r.schema.attrs.iter().enumerate() {
+        println!("  {}: {} ({})", i, attr.name, attr.attr_type);
+    }
+    println!("page size: {} bytes", r.page_size);
     println!("# of pages: {}", r.num_pages);
     println!("# of tuples: {}", r.num_tuples);
+    println!("# of bytes of tuple data: {}", r.num_bytes);
+    println!("split policy: {}", r.split_policy);
+    println!("# of free overflow pages: {}", r.free_list.len);
+    println!("# of blob pages: {} ({} free)",
+             try!(r.blob_file.num_pages()), r.blob_free_list.len);
     println!("linear hashing params: d = {}, sp = {}", r.depth, r.split_pointer);
     println!("choice vector: {:?}", r.choice_vec.data);
+
+    // Walk every bucket, which also exercises the buffer pool.
+    let mut num_ovflow_pages = 0;
+    let mut longest_chain = 0;
+    for page_id in 0..r.num_pages as u32 {
+        let mut chain_length = 0;
+        let mut next_page_id = try!(Page::read(&r.data_file, page_id)).ovflow;
+        while next_page_id != NO_OVFLOW {
+            chain_length += 1;
+            next_page_id = try!(Page::read(&r.ovflow_file, next_page_id)).ovflow;
+        }
+        num_ovflow_pages += chain_length;
+        longest_chain = longest_chain.max(chain_length);
+    }
+    println!("# of overflow pages in use: {}", num_ovflow_pages);
+    println!("longest overflow chain: {}", longest_chain);
+
+    let pool = r.pool_stats();
+    println!("buffer pool: {} of {} pages cached, {} hits, {} misses",
+             pool.num_cached, pool.capacity, pool.hits, pool.misses);
     Ok(())
 }
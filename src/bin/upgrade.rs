@@ -0,0 +1,29 @@
+extern crate malh;
+
+use std::env;
+
+use malh::util::*;
+use malh::relation::Relation;
+use malh::upgrade::FORMAT_VERSION;
+
+fn main() {
+    run_main(upgrade);
+}
+
+fn upgrade() -> Result<(), BoxError> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 2 {
+        try!(Err("Usage: upgrade <relation name>"));
+    }
+
+    let relation_name = &args[1];
+    let old_version = try!(Relation::upgrade(relation_name)
+        .map_err(|e| format!("Error: unable to upgrade relation: {}, reason: {}", relation_name, e)));
+    if old_version == FORMAT_VERSION {
+        println!("Relation {} is already at format version {}.", relation_name, FORMAT_VERSION);
+    } else {
+        println!("Upgraded relation {} from format version {} to {}.",
+                 relation_name, old_version, FORMAT_VERSION);
+    }
+    Ok(())
+}
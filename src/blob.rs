@@ -0,0 +1,175 @@
+use std::io;
+use std::io::ErrorKind::InvalidData;
+
+use page::{Page, RecordKind, encode_record, decode_record, max_record_size, NO_OVFLOW};
+use page_file::PageFile;
+use free_list::FreeList;
+use tuple::Tuple;
+use util::*;
+
+/// Reference to a tuple stored out of line in the blob file, which is kept in the tuple's bucket
+/// in its place.
+///
+/// The tuple is split across a chain of blob pages, linked by their overflow pointers, with
+/// one chunk in each page's first slot. The reference records the tuple's hash, so that it
+/// can be moved between buckets without reading it back.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BlobRef {
+    /// ID of the first page in the chain.
+    pub first_page: u32,
+    /// Length of the serialised tuple.
+    pub len: u32,
+    pub hash: u32,
+}
+
+impl BlobRef {
+    /// Serialise the reference as a record to be stored in a page.
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(12);
+        for &field in &[self.first_page, self.len, self.hash] {
+            write_u32(&mut payload, field).unwrap();
+        }
+        encode_record(RecordKind::Blob, &payload)
+    }
+
+    /// Parse a record from a page as a blob reference, if it is one.
+    pub fn parse_bytes(record: &[u8]) -> Option<BlobRef> {
+        match decode_record(record) {
+            Some((RecordKind::Blob, payload)) if payload.len() == 12 => {
+                let field = |i: usize| read_u32(&payload[4 * i..]).unwrap();
+                Some(BlobRef { first_page: field(0), len: field(1), hash: field(2) })
+            }
+            _ => None
+        }
+    }
+
+    /// Write a serialised tuple to a new chain of blob pages.
+    pub fn store(blob_file: &PageFile, free_list: &mut FreeList, bytes: &[u8], hash: u32)
+    -> io::Result<BlobRef>
+    {
+        assert!(!bytes.is_empty(), "empty blob");
+        let chunks: Vec<&[u8]> = bytes.chunks(max_record_size(blob_file.page_size())).collect();
+        let mut page_ids = vec![];
+        for _ in 0..chunks.len() {
+            page_ids.push(try!(free_list.alloc(blob_file)));
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut page = Page::empty(blob_file, page_ids[i]);
+            assert!(page.add_tuple(chunk));
+            page.ovflow = page_ids.get(i + 1).cloned().unwrap_or(NO_OVFLOW);
+            try!(page.write());
+        }
+        trace!("Stored {} byte tuple in blob pages {:?}", bytes.len(), page_ids);
+        Ok(BlobRef { first_page: page_ids[0], len: bytes.len() as u32, hash: hash })
+    }
+
+    /// Read the tuple back from the blob file.
+    pub fn load(&self, blob_file: &PageFile) -> io::Result<Tuple> {
+        self.load_attrs(blob_file, None)
+    }
+
+    /// Read the tuple back from the blob file, with only the values marked in `needed`,
+    /// as for `Tuple::deserialise_attrs`.
+    pub fn load_attrs(&self, blob_file: &PageFile, needed: Option<&[bool]>) -> io::Result<Tuple> {
+        let mut bytes = Vec::with_capacity(self.len as usize);
+        try!(self.walk_chain(blob_file, |page| {
+            bytes.extend_from_slice(page.record(0).unwrap_or(&[]));
+            if bytes.len() > self.len as usize {
+                return Err(io::Error::new(InvalidData, "blob is longer than its reference"));
+            }
+            Ok(())
+        }));
+        if bytes.len() != self.len as usize {
+            return Err(io::Error::new(InvalidData, "blob length doesn't match its reference"));
+        }
+        Tuple::deserialise_attrs(&bytes, needed).ok_or_else(malformed_tuple)
+    }
+
+    /// Release the blob's pages for re-use.
+    pub fn free(&self, blob_file: &PageFile, free_list: &mut FreeList) -> io::Result<()> {
+        for page_id in try!(self.page_ids(blob_file)) {
+            try!(free_list.free(blob_file, page_id));
+        }
+        Ok(())
+    }
+
+    /// Collect the IDs of the pages holding the blob, in order.
+    pub fn page_ids(&self, blob_file: &PageFile) -> io::Result<Vec<u32>> {
+        let mut page_ids = vec![];
+        try!(self.walk_chain(blob_file, |page| {
+            page_ids.push(page.id);
+            Ok(())
+        }));
+        Ok(page_ids)
+    }
+
+    /// Read each page of the chain in order, failing rather than looping forever if the
+    /// chain has more pages than the blob's length needs, as a corrupt or cyclic one may.
+    fn walk_chain<F>(&self, blob_file: &PageFile, mut visit: F) -> io::Result<()>
+        where F: FnMut(&Page) -> io::Result<()>
+    {
+        let max_pages = self.len as usize / max_record_size(blob_file.page_size()) + 1;
+        let mut num_pages = 0;
+        let mut next_page_id = self.first_page;
+        while next_page_id != NO_OVFLOW {
+            num_pages += 1;
+            if num_pages > max_pages {
+                let message = "blob chain is longer than its length needs";
+                return Err(io::Error::new(InvalidData, message));
+            }
+            let page = try!(Page::read(blob_file, next_page_id));
+            try!(visit(&page));
+            next_page_id = page.ovflow;
+        }
+        Ok(())
+    }
+}
+
+/// Turn a record from a page into a tuple, reading it from the blob file if it is
+/// stored out of line.
+pub fn read_record(record: &[u8], blob_file: &PageFile) -> io::Result<Tuple> {
+    read_record_attrs(record, blob_file, None)
+}
+
+/// Turn a record into a tuple with only the values marked in `needed`, as for
+/// `Tuple::deserialise_attrs`.
+pub fn read_record_attrs(record: &[u8], blob_file: &PageFile, needed: Option<&[bool]>)
+-> io::Result<Tuple>
+{
+    if let Some(blob_ref) = BlobRef::parse_bytes(record) {
+        return blob_ref.load_attrs(blob_file, needed);
+    }
+    match decode_record(record) {
+        Some((RecordKind::Tuple, payload)) => {
+            Tuple::deserialise_attrs(payload, needed).ok_or_else(malformed_tuple)
+        }
+        _ => Err(malformed_tuple())
+    }
+}
+
+fn malformed_tuple() -> io::Error {
+    io::Error::new(InvalidData, "malformed tuple record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlobRef;
+    use page::{RecordKind, encode_record};
+    use tuple::Tuple;
+
+    #[test]
+    fn serialise_parse() {
+        let blob_ref = BlobRef { first_page: 7, len: 5000, hash: 0xdeadbeef };
+        let serialised = blob_ref.serialise();
+        assert_eq!(BlobRef::parse_bytes(&serialised), Some(blob_ref));
+        assert_eq!(BlobRef::parse_bytes(&serialised[..serialised.len() - 1]), None);
+    }
+
+    #[test]
+    fn parse_ordinary_tuples() {
+        let tuple = Tuple { values: vec![Some("hello".to_string()), None] };
+        assert_eq!(BlobRef::parse_bytes(&encode_record(RecordKind::Tuple, &tuple.serialise())), None);
+        assert_eq!(BlobRef::parse_bytes(b""), None);
+        assert_eq!(BlobRef::parse_bytes(&encode_record(RecordKind::Blob, b"short")), None);
+    }
+}
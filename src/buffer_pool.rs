@@ -0,0 +1,317 @@
+use std::fs::File;
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::collections::{HashMap, BTreeMap};
+
+use wal::{FileKind, NUM_FILE_KINDS};
+
+/// Number of pages a relation's buffer pool holds by default (256 KiB).
+pub const DEFAULT_POOL_PAGES: usize = 256;
+
+/// Bounded LRU cache of pages, shared by all of a relation's page files.
+///
+/// Writes only touch the cached copy of a page. Dirty pages reach the disk when they are
+/// evicted, or when the pool is flushed (which the relation does on commit and close).
+pub struct BufferPool {
+    /// Maximum number of pages held at once.
+    capacity: usize,
+    /// Size of the pages in all the files.
+    page_size: u32,
+    /// Handles for the files being cached, indexed by `FileKind`.
+    files: [Option<File>; NUM_FILE_KINDS],
+    /// Length in pages of each file, including pages that so far only exist in the pool.
+    lengths: [u32; NUM_FILE_KINDS],
+    frames: HashMap<(FileKind, u32), Frame>,
+    /// Cached pages ordered from least to most recently used, keyed by time of last use.
+    lru: BTreeMap<u64, (FileKind, u32)>,
+    /// Counter used to order page accesses.
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct Frame {
+    data: Box<[u8]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Snapshot of a buffer pool's counters.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct PoolStats {
+    pub capacity: usize,
+    pub num_cached: usize,
+    pub num_dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize, page_size: u32) -> BufferPool {
+        assert!(capacity > 0, "buffer pool must hold at least one page");
+        BufferPool {
+            capacity: capacity,
+            page_size: page_size,
+            files: [None, None, None],
+            lengths: [0; NUM_FILE_KINDS],
+            frames: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Start caching pages of a file.
+    pub fn add_file(&mut self, kind: FileKind, file: File) -> io::Result<()> {
+        self.lengths[kind as usize] = try!(self.disk_num_pages(&file));
+        self.files[kind as usize] = Some(file);
+        Ok(())
+    }
+
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// Change the size of pages, for a relation whose page size wasn't known when the files
+    /// were added. Any cached pages are discarded.
+    pub fn set_page_size(&mut self, page_size: u32) -> io::Result<()> {
+        if page_size != self.page_size {
+            assert_eq!(self.stats().num_dirty, 0, "changing page size with dirty pages cached");
+            self.page_size = page_size;
+            try!(self.invalidate());
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            capacity: self.capacity,
+            num_cached: self.frames.len(),
+            num_dirty: self.frames.values().filter(|frame| frame.dirty).count(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+
+    /// Number of pages in a file, which is also the ID of the next page to be added.
+    pub fn num_pages(&self, kind: FileKind) -> u32 {
+        self.lengths[kind as usize]
+    }
+
+    /// Read a whole page into `buf`, from the pool if possible.
+    pub fn read_page(&mut self, kind: FileKind, page_id: u32, buf: &mut [u8]) -> io::Result<()> {
+        if self.frames.contains_key(&(kind, page_id)) {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+            let mut data = vec![0; self.page_size as usize].into_boxed_slice();
+            {
+                let mut file = self.file(kind);
+                try!(file.seek(SeekFrom::Start(page_id as u64 * self.page_size as u64)));
+                try!(file.read_exact(&mut data));
+            }
+            try!(self.insert(kind, page_id, data, false));
+        }
+        let frame = self.touch(kind, page_id);
+        buf.copy_from_slice(&frame.data);
+        Ok(())
+    }
+
+    /// Write a whole page into the pool, leaving it to be written back later.
+    pub fn write_page(&mut self, kind: FileKind, page_id: u32, buf: &[u8]) -> io::Result<()> {
+        if self.frames.contains_key(&(kind, page_id)) {
+            let frame = self.touch(kind, page_id);
+            frame.data.copy_from_slice(buf);
+            frame.dirty = true;
+        } else {
+            try!(self.insert(kind, page_id, buf.to_vec().into_boxed_slice(), true));
+        }
+        let length = &mut self.lengths[kind as usize];
+        *length = (*length).max(page_id + 1);
+        Ok(())
+    }
+
+    /// Truncate a file to `num_pages` pages, discarding any cached pages past the end.
+    pub fn truncate(&mut self, kind: FileKind, num_pages: u32) -> io::Result<()> {
+        let removed: Vec<_> = self.frames.keys()
+            .filter(|&&(k, id)| k == kind && id >= num_pages)
+            .cloned()
+            .collect();
+        for key in removed {
+            let frame = self.frames.remove(&key).unwrap();
+            self.lru.remove(&frame.last_used);
+        }
+        try!(self.file(kind).set_len(num_pages as u64 * self.page_size as u64));
+        self.lengths[kind as usize] = num_pages;
+        Ok(())
+    }
+
+    /// Write all dirty pages back to their files.
+    pub fn flush(&mut self) -> io::Result<()> {
+        // Write pages in file order, to keep the IO sequential.
+        let mut dirty: Vec<_> = self.frames.iter()
+            .filter(|&(_, frame)| frame.dirty)
+            .map(|(&key, _)| key)
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        dirty.sort_by_key(|&(kind, id)| (kind as u8, id));
+        debug!("Flushing {} dirty pages", dirty.len());
+        for (kind, page_id) in dirty {
+            try!(self.write_back(kind, page_id));
+        }
+        for file in self.files.iter().filter_map(|f| f.as_ref()) {
+            try!((&*file).flush());
+        }
+        Ok(())
+    }
+
+    /// Throw away all cached pages, including dirty ones, and re-read the file lengths.
+    /// Used after the files have been changed behind the pool's back.
+    pub fn invalidate(&mut self) -> io::Result<()> {
+        self.frames.clear();
+        self.lru.clear();
+        for kind in &FileKind::ALL {
+            if let Some(ref file) = self.files[*kind as usize] {
+                self.lengths[*kind as usize] = try!(self.disk_num_pages(file));
+            }
+        }
+        Ok(())
+    }
+
+    fn disk_num_pages(&self, file: &File) -> io::Result<u32> {
+        let file_length = try!(file.metadata()).len();
+        Ok((file_length / self.page_size as u64) as u32)
+    }
+
+    fn file(&self, kind: FileKind) -> &File {
+        self.files[kind as usize].as_ref().expect("file not registered with buffer pool")
+    }
+
+    /// Mark a cached page as the most recently used, and return its frame.
+    fn touch(&mut self, kind: FileKind, page_id: u32) -> &mut Frame {
+        self.clock += 1;
+        let frame = self.frames.get_mut(&(kind, page_id)).unwrap();
+        self.lru.remove(&frame.last_used);
+        self.lru.insert(self.clock, (kind, page_id));
+        frame.last_used = self.clock;
+        frame
+    }
+
+    /// Add a page to the pool, evicting the least recently used page if it is full.
+    fn insert(&mut self, kind: FileKind, page_id: u32, data: Box<[u8]>, dirty: bool)
+    -> io::Result<()>
+    {
+        if self.frames.len() >= self.capacity {
+            let (&oldest, &(old_kind, old_id)) = self.lru.iter().next().unwrap();
+            trace!("Evicting page {} of {:?} file", old_id, old_kind);
+            try!(self.write_back(old_kind, old_id));
+            self.lru.remove(&oldest);
+            self.frames.remove(&(old_kind, old_id));
+        }
+        self.clock += 1;
+        self.lru.insert(self.clock, (kind, page_id));
+        self.frames.insert((kind, page_id), Frame { data: data, dirty: dirty, last_used: self.clock });
+        Ok(())
+    }
+
+    /// Write a cached page to disk if it is dirty.
+    fn write_back(&mut self, kind: FileKind, page_id: u32) -> io::Result<()> {
+        let frame = self.frames.get_mut(&(kind, page_id)).unwrap();
+        if !frame.dirty {
+            return Ok(());
+        }
+        let mut file = self.files[kind as usize].as_ref().unwrap();
+        try!(file.seek(SeekFrom::Start(page_id as u64 * self.page_size as u64)));
+        try!(file.write_all(&frame.data));
+        frame.dirty = false;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Read;
+
+    use super::BufferPool;
+    use page::DEFAULT_PAGE_SIZE;
+    use wal::FileKind::Data;
+
+    const PAGE_SIZE: usize = DEFAULT_PAGE_SIZE as usize;
+
+    fn page_of(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE]
+    }
+
+    fn with_pool<F: FnOnce(&mut BufferPool, &str)>(test_name: &str, capacity: usize, f: F) {
+        let path = env::temp_dir().join(format!("malh-pool-{}-{}", test_name, ::std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut pool = BufferPool::new(capacity, DEFAULT_PAGE_SIZE);
+        pool.add_file(Data, file).unwrap();
+        f(&mut pool, &path);
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn disk_contents(path: &str) -> Vec<u8> {
+        let mut contents = vec![];
+        File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn writes_are_deferred_until_flush() {
+        with_pool("deferred", 4, |pool, path| {
+            pool.write_page(Data, 0, &page_of(1)).unwrap();
+            pool.write_page(Data, 1, &page_of(2)).unwrap();
+            assert_eq!(pool.num_pages(Data), 2);
+            assert!(disk_contents(path).is_empty());
+
+            pool.flush().unwrap();
+            let contents = disk_contents(path);
+            assert_eq!(&contents[..PAGE_SIZE], &page_of(1)[..]);
+            assert_eq!(&contents[PAGE_SIZE..], &page_of(2)[..]);
+            assert_eq!(pool.stats().num_dirty, 0);
+        });
+    }
+
+    #[test]
+    fn least_recently_used_page_is_evicted() {
+        with_pool("lru", 2, |pool, path| {
+            let mut buf = page_of(0);
+            pool.write_page(Data, 0, &page_of(1)).unwrap();
+            pool.write_page(Data, 1, &page_of(2)).unwrap();
+            pool.read_page(Data, 0, &mut buf).unwrap();
+
+            // Page 1 is older than page 0, so it gets written back and evicted.
+            pool.write_page(Data, 2, &page_of(3)).unwrap();
+            assert_eq!(disk_contents(path).len(), 2 * PAGE_SIZE);
+
+            pool.read_page(Data, 0, &mut buf).unwrap();
+            assert_eq!(buf, page_of(1));
+            pool.read_page(Data, 1, &mut buf).unwrap();
+            assert_eq!(buf, page_of(2));
+
+            let stats = pool.stats();
+            assert_eq!((stats.hits, stats.misses), (2, 1));
+            assert_eq!(stats.num_cached, 2);
+        });
+    }
+
+    #[test]
+    fn truncate_discards_cached_pages() {
+        with_pool("truncate", 4, |pool, path| {
+            for i in 0..3 {
+                pool.write_page(Data, i, &page_of(i as u8)).unwrap();
+            }
+            pool.truncate(Data, 1).unwrap();
+            pool.flush().unwrap();
+            assert_eq!(pool.num_pages(Data), 1);
+            assert_eq!(disk_contents(path), page_of(0));
+        });
+    }
+}
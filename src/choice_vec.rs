@@ -46,7 +46,7 @@ impl ChoiceVec {
         for i in start..HASH_SIZE {
             loop {
                 let (attr, bit) = (rng.gen::<u32>() % num_attrs, rng.gen::<u8>() % HASH_SIZE as u8);
-                if !(&self.data[..i]).contains(&(attr, bit)) {
+                if !self.data[..i].contains(&(attr, bit)) {
                     self.data[i] = (attr, bit);
                     trace!("Generated entry: ({}, {})", attr, bit);
                     break;
@@ -64,8 +64,8 @@ impl ChoiceVec {
             if split.len() != 2 {
                 return Err(ParseError::InvalidEntry);
             }
-            let l: u32 = try!(split[0].parse().or_else(|_| Err(ParseError::NumberUnparsable)));
-            let r: u8 = try!(split[1].parse().or_else(|_| Err(ParseError::NumberUnparsable)));
+            let l: u32 = try!(split[0].parse().map_err(|_| ParseError::NumberUnparsable));
+            let r: u8 = try!(split[1].parse().map_err(|_| ParseError::NumberUnparsable));
             if l >= num_attrs { return Err(ParseError::InvalidAttr(l)) }
             if r as usize >= HASH_SIZE { return Err(ParseError::InvalidBit(r)) }
             given_bits.push((l, r));
@@ -84,15 +84,15 @@ impl ChoiceVec {
 
     pub fn read(mut f: &File) -> io::Result<ChoiceVec> {
         let mut data = [(0, 0); HASH_SIZE];
-        for i in 0..HASH_SIZE {
+        for entry in data.iter_mut() {
             let attr = try!(read_u32(f));
             let val = try!(f.read_u8());
-            data[i] = (attr, val);
+            *entry = (attr, val);
         }
         Ok(ChoiceVec { data: data })
     }
 
-    pub fn iter(&self) -> slice::Iter<ChoiceEntry> {
+    pub fn iter(&self) -> slice::Iter<'_, ChoiceEntry> {
         return self.data.iter();
     }
 }
@@ -106,7 +106,7 @@ mod tests {
     fn parse_1_n_2s() {
         match ChoiceVec::parse("1,2:1,2", 2) {
             Ok(result) => {
-                let expect = vec![(1, 2), (1, 2)];
+                let expect = [(1, 2), (1, 2)];
                 assert_eq!(&result.data[..2], &expect[..]);
             },
             Err(reason) => {
@@ -122,8 +122,7 @@ mod tests {
 
     #[test]
     fn parse_too_long() {
-        use std::iter::repeat;
-        let raw_vec: Vec<&str> = repeat("1,1").take(HASH_SIZE + 1).collect();
+        let raw_vec: Vec<&str> = std::iter::repeat_n("1,1", HASH_SIZE + 1).collect();
         let too_long: String = raw_vec.join(":");
         ChoiceVec::parse(&too_long, 10).unwrap_err();
     }
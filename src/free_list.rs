@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io;
+
+use page::{Page, NO_OVFLOW};
+use page_file::PageFile;
+use util::*;
+
+/// List of unused pages in an overflow file, so that freed pages get re-used.
+///
+/// Free pages are chained together through their overflow pointers, so only the head of
+/// the list and its length need to be stored in the relation's info file.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct FreeList {
+    /// ID of the first free page, or NO_OVFLOW if the list is empty.
+    pub head: u32,
+    /// Number of pages in the list.
+    pub len: u32,
+}
+
+impl FreeList {
+    pub fn new() -> FreeList {
+        FreeList { head: NO_OVFLOW, len: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == NO_OVFLOW
+    }
+
+    /// Claim an overflow page, re-using a free page if possible, or appending one otherwise.
+    /// The claimed page is empty on disk, so it can't be claimed twice.
+    pub fn alloc(&mut self, ovflow_file: &PageFile) -> io::Result<u32> {
+        if self.is_empty() {
+            let mut page = try!(Page::new(ovflow_file));
+            try!(page.write());
+            trace!("Allocated new overflow page {}", page.id);
+            return Ok(page.id);
+        }
+
+        let page_id = self.head;
+        let free_page = try!(Page::read(ovflow_file, page_id));
+        self.head = free_page.ovflow;
+        self.len -= 1;
+
+        try!(Page::empty(ovflow_file, page_id).write());
+        trace!("Re-using free overflow page {}", page_id);
+        Ok(page_id)
+    }
+
+    /// Wipe an overflow page and add it to the list.
+    pub fn free(&mut self, ovflow_file: &PageFile, page_id: u32) -> io::Result<()> {
+        trace!("Freeing overflow page {}", page_id);
+        let mut page = Page::empty(ovflow_file, page_id);
+        page.ovflow = self.head;
+        try!(page.write());
+        self.head = page_id;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Collect the IDs of all the pages in the list, in order.
+    pub fn page_ids(&self, ovflow_file: &PageFile) -> io::Result<Vec<u32>> {
+        let mut page_ids = vec![];
+        let mut next_page_id = self.head;
+        while next_page_id != NO_OVFLOW {
+            page_ids.push(next_page_id);
+            next_page_id = try!(Page::read(ovflow_file, next_page_id)).ovflow;
+        }
+        Ok(page_ids)
+    }
+
+    pub fn write(&self, f: &File) -> io::Result<()> {
+        try!(write_u32(f, self.head));
+        write_u32(f, self.len)
+    }
+
+    pub fn read(f: &File) -> io::Result<FreeList> {
+        let head = try!(read_u32(f));
+        let len = try!(read_u32(f));
+        Ok(FreeList { head: head, len: len })
+    }
+}
+
+impl Default for FreeList {
+    fn default() -> FreeList {
+        FreeList::new()
+    }
+}
@@ -6,6 +6,8 @@
 extern crate env_logger;
 extern crate rand;
 extern crate byteorder;
+extern crate regex;
+extern crate fs2;
 
 pub mod query;
 pub mod relation;
@@ -14,3 +16,14 @@ pub mod choice_vec;
 pub mod util;
 pub mod partial_hash;
 pub mod tuple;
+pub mod split_policy;
+pub mod free_list;
+pub mod page_file;
+pub mod wal;
+pub mod blob;
+pub mod buffer_pool;
+pub mod upgrade;
+pub mod schema;
+pub mod pattern;
+pub mod aggregate;
+pub mod sort;
@@ -1,48 +1,145 @@
-use std::fs::File;
-use std::io::{self, Read, Write, Seek, SeekFrom, Cursor};
-use std::io::ErrorKind::InvalidInput;
+use std::io::{self, Read, Write, Cursor};
+use std::io::ErrorKind::{InvalidInput, InvalidData};
 use std::collections::LinkedList;
+use std::error::Error;
+use std::fmt;
 use util::*;
 use tuple::Tuple;
 use query::Query;
+use schema::Schema;
+use free_list::FreeList;
+use page_file::PageFile;
+use blob::{read_record, read_record_attrs};
+use wal::FileKind;
 
-pub const PAGE_SIZE: u64 = 1024;
-pub const PAGE_HEADER_SIZE: u64 = 4 * 3;
-pub const PAGE_DATA_SIZE: usize = (PAGE_SIZE - PAGE_HEADER_SIZE) as usize;
+/// Page size used unless a relation is created with another.
+pub const DEFAULT_PAGE_SIZE: u32 = 1024;
+pub const MIN_PAGE_SIZE: u32 = 512;
+pub const MAX_PAGE_SIZE: u32 = 64 * 1024;
+/// Header: checksum, overflow page ID, number of slots, offset of the lowest record.
+pub const PAGE_HEADER_SIZE: u32 = 4 * 4;
+/// Slot directory entry: offset and length of a record.
+pub const SLOT_SIZE: usize = 2 + 2;
 pub const NO_OVFLOW: u32 = 0xffffffff;
+/// Slot of a removed record, which isn't reused so that the record's ID never refers to
+/// another record.
+const TOMBSTONE: (u16, u16) = (0xffff, 0);
+/// Record header: kind, payload length.
+pub const RECORD_HEADER_SIZE: usize = 1 + 4;
 
+/// What the payload of a record in a page holds.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RecordKind {
+    /// A serialised tuple.
+    Tuple = 0,
+    /// A `BlobRef` to a tuple stored out of line.
+    Blob = 1,
+}
+
+/// Frame a payload as a record to be stored in a page.
+pub fn encode_record(kind: RecordKind, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.push(kind as u8);
+    write_u32(&mut record, payload.len() as u32).unwrap();
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Split a record into its kind and payload, returning None if it is malformed.
+pub fn decode_record(record: &[u8]) -> Option<(RecordKind, &[u8])> {
+    if record.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let kind = match record[0] {
+        0 => RecordKind::Tuple,
+        1 => RecordKind::Blob,
+        _ => return None
+    };
+    let len = read_u32(&record[1..RECORD_HEADER_SIZE]).unwrap() as usize;
+    if record.len() != RECORD_HEADER_SIZE + len {
+        return None;
+    }
+    Some((kind, &record[RECORD_HEADER_SIZE..]))
+}
+
+/// Space for tuple data in a page of the given size.
+pub const fn page_data_size(page_size: u32) -> usize {
+    (page_size - PAGE_HEADER_SIZE) as usize
+}
+
+/// Largest record that fits in a page of the given size, along with its slot.
+pub const fn max_record_size(page_size: u32) -> usize {
+    page_data_size(page_size) - SLOT_SIZE
+}
+
+/// Value of `RecordId::page_in_file` for records in the first page of a bucket.
+pub const PRIMARY_PAGE: u32 = NO_OVFLOW;
+
+/// Physical location of a record: its bucket, the page holding it, and its slot in that page.
+///
+/// A record keeps its ID until it is deleted, or its relation's epoch changes, which happens
+/// whenever records move: on splits, merges, updates that move tuples, and compaction, and
+/// when an emptied overflow page is freed. IDs from an earlier epoch are rejected rather
+/// than followed, and the ID of a deleted record refers to no record.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub struct RecordId {
+    /// The relation's epoch when the ID was handed out.
+    pub epoch: u32,
+    pub bucket: u32,
+    /// ID of the overflow page holding the record, or `PRIMARY_PAGE` if it is in the
+    /// bucket's own page in the data file.
+    pub page_in_file: u32,
+    pub slot: u16,
+}
+
+/// Check that a page size is a power of two within the supported range.
+pub fn is_valid_page_size(page_size: u32) -> bool {
+    page_size.is_power_of_two() && (MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size)
+}
+
+/// A page of records, laid out as a slotted page.
+///
+/// The slot directory grows from the start of the page's data, and the records it points to
+/// grow down from the end. Removing a record leaves a tombstone in its slot without moving
+/// any others, so a record keeps its slot number for as long as it stays in the page, and
+/// the number isn't reused until `reclaim_tombstones`.
 pub struct Page<'a> {
     /// Page ID for this page - offset within the data file.
     pub id: u32,
     /// Data file for this page.
-    file: &'a File,
-    /// Offset of free space within data.
-    pub free: u32,
+    file: &'a PageFile,
     /// Page ID for an associated overflow page, if one exists (or NO_OFFSET).
     pub ovflow: u32,
     /// Number of tuples stored in this page.
     pub num_tuples: u32,
+    /// Offset and length of each record within `data`, with a length of 0 for an empty slot
+    /// or a `TOMBSTONE`.
+    slots: Vec<(u16, u16)>,
+    /// Offset of the lowest record within `data`.
+    records_start: u32,
     /// Whether or not this page needs to be written to disk.
     dirty: bool,
-    // Actual page data.
-    pub data: Box<[u8; PAGE_DATA_SIZE]>
+    // Actual page data: the slot directory, free space, then the records.
+    data: Box<[u8]>
 }
 
 impl<'b> Page<'b> {
-    pub fn new<'a>(file: &'a File) -> io::Result<Page<'a>> {
+    pub fn new<'a>(file: &'a PageFile) -> io::Result<Page<'a>> {
         let id = try!(get_next_page_id(file));
         Ok(Page::empty(file, id))
     }
 
-    pub fn empty<'a>(file: &'a File, page_id: u32) -> Page<'a> {
+    pub fn empty<'a>(file: &'a PageFile, page_id: u32) -> Page<'a> {
+        let data = empty_data_block(file);
         Page {
             id: page_id,
             file: file,
-            free: 0,
             ovflow: NO_OVFLOW,
             num_tuples: 0,
+            slots: vec![],
+            records_start: data.len() as u32,
             dirty: true,
-            data: empty_data_block()
+            data: data
         }
     }
 
@@ -59,149 +156,397 @@ impl<'b> Page<'b> {
         }
     }
 
+    /// Space left for new records and their slots, including space that only becomes
+    /// usable once the page is compacted.
     pub fn free_space(&self) -> usize {
-        PAGE_DATA_SIZE - (self.free as usize)
+        let used: usize = self.slots.iter().map(|&(_, len)| len as usize).sum();
+        self.data.len() - self.slots.len() * SLOT_SIZE - used
     }
 
-    pub fn read<'a>(mut f: &'a File, page_id: u32) -> io::Result<Page<'a>> {
-        try!(seek_to_start(f, page_id));
-
+    pub fn read<'a>(f: &'a PageFile, page_id: u32) -> io::Result<Page<'a>> {
         // Load the whole page.
-        let mut buffer = Box::new([0; PAGE_SIZE as usize]);
-        try!(f.read_exact(&mut buffer[..]));
+        let mut buffer = vec![0; f.page_size() as usize];
+        try!(f.read_page(page_id, &mut buffer[..]));
 
-        // Parse the page's data.
-        let mut cursor = Cursor::new(&buffer.as_ref()[..]);
+        // Parse the page's data, checking the checksum covering everything after it.
+        let mut cursor = Cursor::new(&buffer[..]);
 
-        let free = try!(read_u32(&mut cursor));
+        let checksum = try!(read_u32(&mut cursor));
+        if checksum != crc32(&buffer[4..]) {
+            let error = CorruptPage { file: f.name().to_string(), page_id: page_id };
+            return Err(io::Error::new(InvalidData, error));
+        }
         let ovflow = try!(read_u32(&mut cursor));
-        let num_tuples = try!(read_u32(&mut cursor));
+        let num_slots = try!(read_u32(&mut cursor)) as usize;
+        let records_start = try!(read_u32(&mut cursor));
 
         let mut page = Page {
             id: page_id,
             file: f,
-            free: free,
             ovflow: ovflow,
-            num_tuples: num_tuples,
+            num_tuples: 0,
+            slots: Vec::with_capacity(num_slots),
+            records_start: records_start,
             dirty: false,
-            data: empty_data_block()
+            data: empty_data_block(f)
         };
 
         // XXX: we might need to call read more than once here.
         let bytes_read = try!(cursor.read(page.data.as_mut()));
-        assert_eq!(bytes_read, PAGE_DATA_SIZE);
+        assert_eq!(bytes_read, page.data.len());
+
+        // Check the directory describes records within the page, so they can be sliced safely.
+        let data_len = page.data.len();
+        if num_slots * SLOT_SIZE > records_start as usize || records_start as usize > data_len {
+            return Err(io::Error::new(InvalidData, format!("malformed page {} of {}", page_id, f.name())));
+        }
+        for i in 0..num_slots {
+            let entry = &page.data[i * SLOT_SIZE..(i + 1) * SLOT_SIZE];
+            let offset = u16::from_be_bytes([entry[0], entry[1]]);
+            let len = u16::from_be_bytes([entry[2], entry[3]]);
+            if len > 0 {
+                let end = offset as usize + len as usize;
+                if (offset as u32) < records_start || end > data_len {
+                    let msg = format!("malformed slot {} in page {} of {}", i, page_id, f.name());
+                    return Err(io::Error::new(InvalidData, msg));
+                }
+                page.num_tuples += 1;
+            }
+            page.slots.push((offset, len));
+        }
 
         Ok(page)
     }
 
     pub fn write(&mut self) -> io::Result<()> {
-        try!(seek_to_start(&self.file, self.id));
+        for (i, &(offset, len)) in self.slots.iter().enumerate() {
+            let entry = &mut self.data[i * SLOT_SIZE..(i + 1) * SLOT_SIZE];
+            entry[..2].copy_from_slice(&offset.to_be_bytes());
+            entry[2..].copy_from_slice(&len.to_be_bytes());
+        }
         // Write all the data into a buffer.
-        let mut buf = Vec::<u8>::with_capacity(PAGE_SIZE as usize);
-        try!(write_u32(&mut buf, self.free));
+        let mut buf = Vec::<u8>::with_capacity(self.file.page_size() as usize);
+        try!(write_u32(&mut buf, 0));
         try!(write_u32(&mut buf, self.ovflow));
-        try!(write_u32(&mut buf, self.num_tuples));
+        try!(write_u32(&mut buf, self.slots.len() as u32));
+        try!(write_u32(&mut buf, self.records_start));
         try!(buf.write_all(self.data.as_ref()));
-        try!(self.file.write_all(buf.as_ref()));
-        try!(self.file.flush());
+        let checksum = crc32(&buf[4..]);
+        try!(write_u32(&mut buf[..4], checksum));
+        try!(self.file.write_page(self.id, buf.as_ref()));
         self.dirty = false;
         Ok(())
     }
 
-    /// Retrieve all the tuples from this page.
-    pub fn get_tuple_list(&self) -> LinkedList<Tuple> {
-        self.data
-            .split(|&b| b == 0)
-            .filter(|slice| slice.len() > 0)
-            .map(|slice| Tuple::parse_bytes(slice))
+    /// Fetch the record in a slot, if the slot is in use.
+    pub fn record(&self, slot: u16) -> Option<&[u8]> {
+        match self.slots.get(slot as usize) {
+            Some(&(offset, len)) if len > 0 => {
+                Some(&self.data[offset as usize..offset as usize + len as usize])
+            }
+            _ => None
+        }
+    }
+
+    /// The records in this page, with their slot numbers.
+    fn record_slices(&self) -> Vec<(u16, &[u8])> {
+        (0..self.slots.len() as u16)
+            .filter_map(|slot| self.record(slot).map(|record| (slot, record)))
             .collect()
     }
 
-    /// Retrieve all the tuples from this page that match the given query.
-    pub fn get_tuples_matching<'a>(&self, query: &'a Query<'a>) -> LinkedList<Tuple> {
-        self.data
-            .split(|&b| b == 0)
-            .filter(|slice| slice.len() > 0)
-            .map(|slice| Tuple::parse_bytes(slice))
-            .filter(|tuple| query.matches_tuple(tuple))
+    /// ID of a record in this page, which belongs to `bucket`, with an epoch of 0 for the
+    /// relation to replace with its own.
+    pub fn record_id(&self, bucket: u32, slot: u16) -> RecordId {
+        let page_in_file = match self.file.kind() {
+            FileKind::Data => PRIMARY_PAGE,
+            _ => self.id
+        };
+        RecordId { epoch: 0, bucket: bucket, page_in_file: page_in_file, slot: slot }
+    }
+
+    /// Retrieve the raw records stored in this page.
+    ///
+    /// Tuples stored out of line are represented by their `BlobRef`s.
+    pub fn get_records(&self) -> LinkedList<Vec<u8>> {
+        self.record_slices().into_iter().map(|(_, record)| record.to_vec()).collect()
+    }
+
+    /// Retrieve the raw records stored in this page, with their slot numbers.
+    pub fn get_slotted_records(&self) -> Vec<(u16, Vec<u8>)> {
+        self.record_slices().into_iter().map(|(slot, record)| (slot, record.to_vec())).collect()
+    }
+
+    /// Retrieve all the tuples from this page, reading any stored out of line from `blob_file`.
+    pub fn get_tuple_list(&self, blob_file: &PageFile) -> io::Result<LinkedList<Tuple>> {
+        self.record_slices()
+            .into_iter()
+            .map(|(_, record)| read_record(record, blob_file))
             .collect()
     }
 
+    /// Retrieve all the tuples from this page that match the given query, with their IDs,
+    /// projected onto the query's columns.
+    pub fn get_tuples_matching<'a>(
+        &self,
+        query: &'a Query<'a>,
+        schema: &Schema,
+        bucket: u32,
+        blob_file: &PageFile
+    ) -> io::Result<LinkedList<(RecordId, Tuple)>>
+    {
+        let mut tuples = LinkedList::new();
+        let needed = query.needed_attrs(schema.attrs.len());
+        for (slot, record) in self.record_slices() {
+            let tuple = try!(read_record_attrs(record, blob_file, needed.as_ref().map(|n| &n[..])));
+            if query.matches_tuple(&tuple, schema) {
+                tuples.push_back((self.record_id(bucket, slot), query.project_tuple(tuple)));
+            }
+        }
+        Ok(tuples)
+    }
+
+    /// Count the tuples in this page that match a query. Values are read straight from the
+    /// records, unless a tuple is stored out of line.
+    pub fn count_matching(&self, query: &Query, schema: &Schema, blob_file: &PageFile)
+    -> io::Result<u64>
+    {
+        let mut count = 0;
+        let mut values = Vec::with_capacity(schema.attrs.len());
+        for (_, record) in self.record_slices() {
+            let matches = match decode_record(record) {
+                Some((RecordKind::Tuple, payload)) if Tuple::read_values(payload, &mut values) => {
+                    query.expr.matches_with(&|i| values[i], schema)
+                }
+                // Blobs, and malformed records for `read_record` to report.
+                _ => query.matches_tuple(&try!(read_record(record, blob_file)), schema)
+            };
+            if matches {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
     /// Retrieve all tuples matching a given query from this page AND its overflow.
-    pub fn select<'a>(&self, query: &'a Query<'a>, ovflow_file: &'a File) -> PageQueryIter<'a> {
-        PageQueryIter {
+    /// This must be the first page of its bucket.
+    pub fn select<'a>(
+        &self,
+        query: &'a Query<'a>,
+        schema: &'a Schema,
+        ovflow_file: &'a PageFile,
+        blob_file: &'a PageFile
+    ) -> io::Result<PageQueryIter<'a>>
+    {
+        Ok(PageQueryIter {
             query: query,
+            schema: schema,
+            bucket: self.id,
             next_page_id: self.ovflow,
             ovflow_file: ovflow_file,
-            tuple_cache: self.get_tuples_matching(query),
+            blob_file: blob_file,
+            tuple_cache: try!(self.get_tuples_matching(query, schema, self.id, blob_file)),
+        })
+    }
+
+    /// Remove all the tuples matching a query from this page, leaving the others in their slots.
+    /// Return the records removed, so that any blobs they refer to can be freed.
+    pub fn remove_matching(&mut self, query: &Query, schema: &Schema, blob_file: &PageFile)
+    -> io::Result<LinkedList<Vec<u8>>>
+    {
+        let mut removed = LinkedList::new();
+        for (slot, record) in self.get_slotted_records() {
+            let tuple = try!(read_record(&record, blob_file));
+            if query.matches_tuple(&tuple, schema) {
+                self.remove_record(slot);
+                removed.push_back(record);
+            }
         }
+        Ok(removed)
+    }
+
+    /// Remove the record in a slot, returning it if the slot was in use.
+    pub fn remove_record(&mut self, slot: u16) -> Option<Vec<u8>> {
+        let record = match self.record(slot) {
+            Some(record) => record.to_vec(),
+            None => return None
+        };
+        self.slots[slot as usize] = TOMBSTONE;
+        self.num_tuples -= 1;
+        if self.num_tuples == 0 {
+            self.records_start = self.data.len() as u32;
+        }
+        self.mark_dirty();
+        Some(record)
+    }
+
+    /// Free the slots of removed records for reuse, returning whether there were any. The
+    /// records' IDs must already be stale.
+    pub fn reclaim_tombstones(&mut self) -> bool {
+        if !self.slots.contains(&TOMBSTONE) {
+            return false;
+        }
+        for slot in self.slots.iter_mut().filter(|slot| **slot == TOMBSTONE) {
+            *slot = (0, 0);
+        }
+        // Trailing empty slots can go, as no record refers to them.
+        while self.slots.last() == Some(&(0, 0)) {
+            self.slots.pop();
+        }
+        self.mark_dirty();
+        true
+    }
+
+    /// Remove all tuples from this page, leaving its overflow pointer intact.
+    pub fn clear(&mut self) {
+        self.num_tuples = 0;
+        self.slots.clear();
+        self.data = empty_data_block(self.file);
+        self.records_start = self.data.len() as u32;
+        self.mark_dirty();
     }
 
     /// Add a tuple if one will fit.
     /// Return true if the tuple was added.
     pub fn add_tuple(&mut self, tuple: &[u8]) -> bool {
-        if self.free_space() < tuple.len() {
-            return false;
-        }
-        let new_free = self.free + tuple.len() as u32;
-        {
-            let mut dest = &mut self.data[self.free as usize .. new_free as usize];
-            dest.clone_from_slice(tuple);
+        self.add_record(tuple).is_some()
+    }
+
+    /// Add a record if it will fit, returning the slot it was stored in.
+    pub fn add_record(&mut self, record: &[u8]) -> Option<u16> {
+        let free_slot = self.slots.iter().position(|&slot| slot == (0, 0));
+        let slot_space = if free_slot.is_some() { 0 } else { SLOT_SIZE };
+        if record.is_empty() || self.free_space() < record.len() + slot_space {
+            return None;
         }
-        self.free = new_free;
+        let slot = match free_slot {
+            Some(slot) => slot,
+            None => {
+                self.slots.push((0, 0));
+                self.slots.len() - 1
+            }
+        };
+        self.place(slot as u16, record);
         self.num_tuples += 1;
+        Some(slot as u16)
+    }
+
+    /// Replace the record in a slot, returning false (and leaving the page unchanged)
+    /// if the new record won't fit.
+    pub fn replace_record(&mut self, slot: u16, record: &[u8]) -> bool {
+        let (offset, len) = self.slots[slot as usize];
+        assert!(len > 0, "replacing the record in an empty slot");
+        if record.len() <= len as usize {
+            // Shrink the record in place, leaving a gap for compaction to reclaim.
+            let offset = offset as usize;
+            self.data[offset..offset + record.len()].copy_from_slice(record);
+            self.slots[slot as usize] = (offset as u16, record.len() as u16);
+        } else if self.free_space() + (len as usize) < record.len() {
+            return false;
+        } else {
+            self.slots[slot as usize] = (0, 0);
+            self.place(slot, record);
+        }
         self.mark_dirty();
         true
     }
 
+    /// Write a record into the free space, and point an empty slot at it.
+    /// There must be enough free space, which is compacted if necessary.
+    fn place(&mut self, slot: u16, record: &[u8]) {
+        let directory_end = self.slots.len() * SLOT_SIZE;
+        if (self.records_start as usize) < directory_end + record.len() {
+            self.compact();
+        }
+        let start = self.records_start as usize - record.len();
+        assert!(start >= directory_end, "no room for record in page {}", self.id);
+        self.data[start..start + record.len()].copy_from_slice(record);
+        self.records_start = start as u32;
+        self.slots[slot as usize] = (start as u16, record.len() as u16);
+        self.mark_dirty();
+    }
+
+    /// Move the records to the end of the page, so that all the free space is in one piece.
+    fn compact(&mut self) {
+        let records: Vec<(u16, Vec<u8>)> = self.get_slotted_records();
+        let mut end = self.data.len();
+        for (slot, record) in records {
+            let start = end - record.len();
+            self.data[start..end].copy_from_slice(&record);
+            self.slots[slot as usize] = (start as u16, record.len() as u16);
+            end = start;
+        }
+        self.records_start = end as u32;
+    }
+
     /// Add a tuple to this page's overflow chain, creating any necessary overflow pages.
-    pub fn add_to_overflow(&mut self, ovflow_file: &File, tuple: &[u8]) -> io::Result<()> {
-        if tuple.len() > PAGE_DATA_SIZE {
+    /// Return true if a new overflow page was added to the chain.
+    pub fn add_to_overflow(&mut self, ovflow_file: &PageFile, free_list: &mut FreeList, tuple: &[u8])
+    -> io::Result<bool>
+    {
+        if tuple.len() > max_record_size(self.file.page_size()) {
             return Err(io::Error::new(InvalidInput, "tuple too large to fit in a page"));
         }
 
         // If the tuple fits in this page, insert it directly.
-        if self.free_space() >= tuple.len() {
-            assert!(self.add_tuple(tuple));
+        if self.add_tuple(tuple) {
             try!(self.write());
-            return Ok(());
+            return Ok(false);
         }
 
         // If the tuple doesn't fit, check for an overflow page for this page.
         // If there isn't one, create one and insert the tuple.
         if self.ovflow == NO_OVFLOW {
-            let mut ovflow_page = try!(Page::new(ovflow_file));
+            let ovflow_page_id = try!(free_list.alloc(ovflow_file));
+            let mut ovflow_page = Page::empty(ovflow_file, ovflow_page_id);
             assert!(ovflow_page.add_tuple(tuple));
             self.ovflow = ovflow_page.id;
             try!(self.write());
             try!(ovflow_page.write());
-            return Ok(());
+            return Ok(true);
         }
 
         // If there is an overflow page, try the insert there by recursing.
         let mut ovflow_page = try!(Page::read(ovflow_file, self.ovflow));
-        ovflow_page.add_to_overflow(ovflow_file, tuple)
+        ovflow_page.add_to_overflow(ovflow_file, free_list, tuple)
+    }
+
+    /// Copy this page's contents to a new location, which may be in a different file.
+    pub fn relocate<'a>(&self, file: &'a PageFile, page_id: u32) -> Page<'a> {
+        Page {
+            id: page_id,
+            file: file,
+            ovflow: self.ovflow,
+            num_tuples: self.num_tuples,
+            slots: self.slots.clone(),
+            records_start: self.records_start,
+            dirty: true,
+            data: self.data.clone()
+        }
     }
 }
 
-/// Iterator over all matching tuples in a bucket.
+/// Iterator over all matching tuples in a bucket, with their record IDs.
 pub struct PageQueryIter<'a> {
     query: &'a Query<'a>,
+    schema: &'a Schema,
+    bucket: u32,
     /// The ID of the next overflow page to read - initially the first overflow page.
     next_page_id: u32,
-    ovflow_file: &'a File,
+    ovflow_file: &'a PageFile,
+    blob_file: &'a PageFile,
     /// Tuples read from the bucket that have not yet been yielded.
     /// Initially contains all the matching tuples from the data page.
-    tuple_cache: LinkedList<Tuple>
+    tuple_cache: LinkedList<(RecordId, Tuple)>
 }
 
 impl<'a> Iterator for PageQueryIter<'a> {
-    type Item = io::Result<Tuple>;
+    type Item = io::Result<(RecordId, Tuple)>;
 
-    fn next(&mut self) -> Option<io::Result<Tuple>> {
+    fn next(&mut self) -> Option<io::Result<(RecordId, Tuple)>> {
         // If there's a tuple ready, yield it.
-        if let Some(tuple) = self.tuple_cache.pop_front() {
-            return Some(Ok(tuple));
+        if let Some(item) = self.tuple_cache.pop_front() {
+            return Some(Ok(item));
         }
         // If there are no more pages in this bucket, yield None forever.
         if self.next_page_id == NO_OVFLOW {
@@ -215,23 +560,38 @@ impl<'a> Iterator for PageQueryIter<'a> {
             }
         };
         self.next_page_id = page.ovflow;
-        self.tuple_cache = page.get_tuples_matching(self.query);
+        self.tuple_cache = match page.get_tuples_matching(self.query, self.schema, self.bucket, self.blob_file) {
+            Ok(tuples) => tuples,
+            Err(e) => {
+                return Some(Err(e));
+            }
+        };
         self.next()
     }
 }
 
-/// Create an empty data block of all zeroes.
-fn empty_data_block() -> Box<[u8; PAGE_DATA_SIZE]> {
-    Box::new([0; PAGE_DATA_SIZE])
+/// Error for a page whose contents don't match its checksum.
+#[derive(Debug)]
+pub struct CorruptPage {
+    /// Name of the file containing the page.
+    pub file: String,
+    pub page_id: u32,
 }
 
-// Fetch the Page ID of the next page to be added to a data file.
-pub fn get_next_page_id(file: &File) -> io::Result<u32> {
-    let file_length = try!(file.metadata().map(|m| m.len()));
-    Ok((file_length / PAGE_SIZE) as u32)
+impl fmt::Display for CorruptPage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "corrupt page: checksum mismatch for page {} of {}", self.page_id, self.file)
+    }
+}
+
+impl Error for CorruptPage {}
+
+/// Create an empty data block of all zeroes, sized for pages of the given file.
+fn empty_data_block(file: &PageFile) -> Box<[u8]> {
+    vec![0; page_data_size(file.page_size())].into_boxed_slice()
 }
 
-/// Seek to the start of a page.
-fn seek_to_start(mut f: &File, page_id: u32) -> io::Result<u64> {
-    f.seek(SeekFrom::Start((page_id as u64) * PAGE_SIZE))
+// Fetch the Page ID of the next page to be added to a data file.
+pub fn get_next_page_id(file: &PageFile) -> io::Result<u32> {
+    file.num_pages()
 }
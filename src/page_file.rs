@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use wal::{Wal, FileKind};
+use buffer_pool::BufferPool;
+
+/// A file of fixed-size pages belonging to a relation (its data, overflow or blob file).
+///
+/// All page IO goes through here, so that pages can be logged before they are changed,
+/// and cached in the relation's buffer pool.
+pub struct PageFile {
+    /// Name of the file on disk, for error messages.
+    name: String,
+    file: File,
+    kind: FileKind,
+    wal: Rc<RefCell<Wal>>,
+    pool: Rc<RefCell<BufferPool>>,
+}
+
+impl PageFile {
+    pub fn new(
+        name: String,
+        file: File,
+        kind: FileKind,
+        wal: Rc<RefCell<Wal>>,
+        pool: Rc<RefCell<BufferPool>>
+    ) -> io::Result<PageFile>
+    {
+        try!(pool.borrow_mut().add_file(kind, try!(file.try_clone())));
+        Ok(PageFile {
+            name: name,
+            file: file,
+            kind: kind,
+            wal: wal,
+            pool: pool,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> FileKind {
+        self.kind
+    }
+
+    /// The underlying file, for operations that don't touch individual pages.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Size of the file's pages, in bytes.
+    pub fn page_size(&self) -> u32 {
+        self.pool.borrow().page_size()
+    }
+
+    /// Number of pages in the file, which is also the ID of the next page to be added.
+    pub fn num_pages(&self) -> io::Result<u32> {
+        Ok(self.pool.borrow().num_pages(self.kind))
+    }
+
+    /// Read a whole page into `buf`.
+    pub fn read_page(&self, page_id: u32, buf: &mut [u8]) -> io::Result<()> {
+        self.pool.borrow_mut().read_page(self.kind, page_id, buf)
+    }
+
+    /// Write a whole page, logging its previous contents first.
+    ///
+    /// The page is only written to the pool: since the pool is flushed before a transaction
+    /// begins, the file still holds the contents the page had at the start of the transaction.
+    pub fn write_page(&self, page_id: u32, buf: &[u8]) -> io::Result<()> {
+        try!(self.wal.borrow_mut().log_page(self.kind, &self.file, page_id));
+        self.pool.borrow_mut().write_page(self.kind, page_id, buf)
+    }
+
+    /// Truncate the file to `num_pages` pages, logging the pages that are removed.
+    pub fn truncate(&self, num_pages: u32) -> io::Result<()> {
+        let current_num_pages = try!(self.num_pages());
+        for page_id in num_pages..current_num_pages {
+            try!(self.wal.borrow_mut().log_page(self.kind, &self.file, page_id));
+        }
+        self.pool.borrow_mut().truncate(self.kind, num_pages)
+    }
+}
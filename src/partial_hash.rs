@@ -1,10 +1,17 @@
-use util::{hash as util_hash, bit as ith_bit, highest_set_bit };
-use query::Query;
+use std::collections::{BTreeSet, HashSet};
+
+use util::{bit as ith_bit, highest_set_bit };
+use query::{Query, Expr, AttrMatch};
 use choice_vec::ChoiceVec;
+use schema::Schema;
 
 pub const FULL_MASK: u32 = 0b11111111_11111111_11111111_11111111;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+/// Most partial hashes to search for a single query. Attributes whose values would take the
+/// number of combinations past this are left unknown instead, and too many alternatives merged.
+pub const MAX_PARTIAL_HASHES: usize = 64;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub struct PartialHash {
     /// The multiattribute hash value, if a bit
     /// is unknown it'll be zero, but should be
@@ -52,29 +59,189 @@ impl PartialHash {
         PageIdIter::new(self, max_page_id)
     }
 
-    pub fn from_query(query: &Query, choice: &ChoiceVec) -> PartialHash {
-        let mut query_hash: u32 = 0;
-        let mut query_mask: u32 = FULL_MASK;
+    /// Compute the single partial hash that covers every tuple matching a query, knowing only
+    /// the bits of attributes that all matching tuples hash alike.
+    pub fn from_query(query: &Query, choice: &ChoiceVec, schema: &Schema) -> PartialHash {
+        let constraints = hash_constraints(&query.expr, schema, false);
+        let known: HashConstraint = hull(&constraints, schema.attrs.len()).into_iter()
+            .map(|hashes| hashes.filter(|hashes| hashes.len() == 1))
+            .collect();
+        PartialHash::for_constraint(&known, choice)[0]
+    }
 
-        // hash the queries known attributes
-        let attr_hashs: Vec<Option<u32>> = {
-            let func = |&attr_match: &Option<&str>| attr_match.map(util_hash);
-            query.matches.iter().map(func).collect()
-        };
+    /// Compute partial hashes that together cover every tuple matching a query, with one for
+    /// each combination of the values that its attributes can take. If there would be more
+    /// than `MAX_PARTIAL_HASHES`, fewer cover more tuples instead.
+    pub fn for_query(query: &Query, choice: &ChoiceVec, schema: &Schema) -> Vec<PartialHash> {
+        let constraints = hash_constraints(&query.expr, schema, false);
+        let partial_hashes: BTreeSet<PartialHash> = constraints.iter()
+            .flat_map(|constraint| PartialHash::for_constraint(constraint, choice))
+            .collect();
+        if partial_hashes.len() > MAX_PARTIAL_HASHES {
+            return PartialHash::for_constraint(&hull(&constraints, schema.attrs.len()), choice);
+        }
+        // Leave out those whose pages another one already covers.
+        partial_hashes.iter()
+            .filter(|&p| !partial_hashes.iter().any(|q| q != p && q.covers(p)))
+            .cloned()
+            .collect()
+    }
+
+    /// Check whether every hash that `other` matches, this one does too.
+    pub fn covers(&self, other: &PartialHash) -> bool {
+        self.mask & other.mask == self.mask && other.hash & self.mask == self.hash
+    }
 
-        // generate multiattribute hash, from attr_hashs & choice vector,
-        // along with the mask signifying which bits are known & unknown
-        for (q_bit, &(a_index, a_bit)) in choice.data.iter().enumerate() {
-            if let Some(a_hash) = attr_hashs[a_index as usize] {
-                // added a'th bit of attribute to hash
-                query_hash |= ith_bit(a_bit, a_hash) << q_bit;
+    /// Compute a partial hash for each combination of the hashes that a constraint allows.
+    fn for_constraint(constraint: &HashConstraint, choice: &ChoiceVec) -> Vec<PartialHash> {
+        let mut partial_hashes = BTreeSet::new();
+        partial_hashes.insert(PartialHash { hash: 0, mask: FULL_MASK });
+
+        for (i, hashes) in constraint.iter().enumerate() {
+            // Bits of the multiattribute hash taken from this attribute.
+            let positions: Vec<(usize, u8)> = choice.data.iter().enumerate()
+                .filter(|&(_, &(a_index, _))| a_index as usize == i)
+                .map(|(q_bit, &(_, a_bit))| (q_bit, a_bit))
+                .collect();
+            let bits_of = |a_hash: u32| positions.iter().fold(0, |hash, &(q_bit, a_bit)| {
+                hash | ith_bit(a_bit, a_hash) << q_bit
+            });
+            let attr_bits: BTreeSet<u32> = hashes.iter().flatten()
+                .map(|&a_hash| bits_of(a_hash))
+                .collect();
+
+            if attr_bits.is_empty() || attr_bits.len() * partial_hashes.len() > MAX_PARTIAL_HASHES {
+                let unknown = positions.iter().fold(0, |mask, &(q_bit, _)| mask | 1 << q_bit);
+                partial_hashes = partial_hashes.into_iter()
+                    .map(|p| PartialHash { hash: p.hash, mask: p.mask & !unknown })
+                    .collect();
             } else {
-                // remove the q'th bit from the query mask
-                query_mask &= !(1 << q_bit);
+                partial_hashes = partial_hashes.iter()
+                    .flat_map(|p| attr_bits.iter().map(move |&bits| {
+                        PartialHash { hash: p.hash | bits, mask: p.mask }
+                    }))
+                    .collect();
             }
         }
+        partial_hashes.into_iter().collect()
+    }
+}
+
+/// Hashes that each attribute's value can have, or None if it can have any.
+pub type HashConstraint = Vec<Option<BTreeSet<u32>>>;
+
+/// Derive constraints such that every tuple matching an expression, or not matching it if
+/// `negated`, meets at least one of them. There are none if no tuple can match. Negations
+/// are pushed down to single attributes, where most conditions leave the attribute's hash
+/// unconstrained.
+pub fn hash_constraints(expr: &Expr, schema: &Schema, negated: bool) -> Vec<HashConstraint> {
+    let num_attrs = schema.attrs.len();
+    match *expr {
+        Expr::Attr(i, ref attr_match) => {
+            // A negated match on values is unknown for NULL just like the match, so neither
+            // holds for it, but the other values it holds for can't be listed.
+            let hashes = match (attr_match, negated) {
+                (_, false) => attr_match.hashes(schema, i),
+                (&AttrMatch::IsNotNull, true) => Some(vec![schema.hash_value(i, None)]),
+                (&AttrMatch::Any, true) => Some(vec![]),
+                (_, true) => None
+            };
+            if hashes.as_ref().is_some_and(|hashes| hashes.is_empty()) {
+                return vec![];
+            }
+            let mut constraint = vec![None; num_attrs];
+            constraint[i] = hashes.map(|hashes| hashes.into_iter().collect());
+            vec![constraint]
+        }
+        Expr::Not(ref expr) => hash_constraints(expr, schema, !negated),
+        // A negated OR is an AND of negations, and a negated AND an OR of them.
+        Expr::And(ref exprs) | Expr::Or(ref exprs) if matches!(*expr, Expr::And(_)) != negated => {
+            exprs.iter().fold(vec![vec![None; num_attrs]], |constraints, expr| {
+                let others = hash_constraints(expr, schema, negated);
+                let combined = constraints.iter()
+                    .flat_map(|a| others.iter().filter_map(move |b| intersect(a, b)))
+                    .collect();
+                limit(combined, num_attrs)
+            })
+        }
+        Expr::And(ref exprs) | Expr::Or(ref exprs) => {
+            let constraints = exprs.iter()
+                .flat_map(|expr| hash_constraints(expr, schema, negated))
+                .collect();
+            limit(constraints, num_attrs)
+        }
+    }
+}
+
+/// Constraint met by tuples that meet both, if any can.
+fn intersect(a: &HashConstraint, b: &HashConstraint) -> Option<HashConstraint> {
+    a.iter().zip(b).map(|(a, b)| match (a, b) {
+        (Some(a), Some(b)) => {
+            let both: BTreeSet<u32> = a.intersection(b).cloned().collect();
+            if both.is_empty() { None } else { Some(Some(both)) }
+        }
+        (Some(hashes), None) | (None, Some(hashes)) => Some(Some(hashes.clone())),
+        (None, None) => Some(None)
+    }).collect()
+}
+
+/// Single constraint met by every tuple that meets any of the constraints, which leaves
+/// every attribute unconstrained if there are none.
+pub fn hull(constraints: &[HashConstraint], num_attrs: usize) -> HashConstraint {
+    (0..num_attrs).map(|i| {
+        if constraints.is_empty() {
+            return None;
+        }
+        constraints.iter().try_fold(BTreeSet::new(), |mut all, constraint| {
+            constraint[i].as_ref().map(|hashes| {
+                all.extend(hashes);
+                all
+            })
+        })
+    }).collect()
+}
+
+/// Replace too many constraints with their hull.
+fn limit(constraints: Vec<HashConstraint>, num_attrs: usize) -> Vec<HashConstraint> {
+    if constraints.len() > MAX_PARTIAL_HASHES {
+        vec![hull(&constraints, num_attrs)]
+    } else {
+        constraints
+    }
+}
+
+/// Iterator over the pages matching any of several partial hashes, visiting each page once.
+pub struct PageIdUnion {
+    iters: Vec<PageIdIter>,
+    /// Pages yielded so far, if there's more than one iterator to overlap.
+    seen: Option<HashSet<u32>>,
+}
+
+impl PageIdUnion {
+    pub fn new(partial_hashes: &[PartialHash], max_page_id: u32) -> PageIdUnion {
+        PageIdUnion {
+            // Iterate in reverse, as pages are taken from the back.
+            iters: partial_hashes.iter().rev().map(|p| p.matching_page_ids(max_page_id)).collect(),
+            seen: if partial_hashes.len() > 1 { Some(HashSet::new()) } else { None },
+        }
+    }
+}
+
+impl Iterator for PageIdUnion {
+    type Item = u32;
 
-        PartialHash { hash: query_hash, mask: query_mask }
+    fn next(&mut self) -> Option<u32> {
+        while let Some(page_id) = self.iters.last_mut().map(|iter| iter.next()) {
+            match page_id {
+                Some(page_id) => {
+                    if self.seen.as_mut().is_none_or(|seen| seen.insert(page_id)) {
+                        return Some(page_id);
+                    }
+                }
+                None => { self.iters.pop(); }
+            }
+        }
+        None
     }
 }
 
@@ -120,7 +287,7 @@ impl PageIdIter {
     }
 
     fn last_bit(&self) -> u32 {
-        (1 << (self.highest_usable_bit))
+        1 << self.highest_usable_bit
     }
 
     // calculates hash without the highest usable
@@ -159,7 +326,16 @@ impl Iterator for PageIdIter {
     type Item = u32;
 
     fn next(&mut self) -> Option<u32> {
-        if self.iteration == self.max_iteration { return None; }
+        // The page without the highest usable bit is still owed after the last iteration.
+        if self.iteration == self.max_iteration {
+            return match self.state {
+                HubState::HubUnknownB(hash) => {
+                    self.state = HubState::HubOff;
+                    Some(hash)
+                }
+                _ => None
+            };
+        }
 
         // Hub stands for highest usable bit
         match self.state {
@@ -213,10 +389,12 @@ impl Iterator for PageIdIter {
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
-    use super::{ PartialHash, FULL_MASK, PageIdIter };
+    use super::{ PartialHash, FULL_MASK, MAX_PARTIAL_HASHES, PageIdIter, PageIdUnion };
+    use util::highest_set_bit;
     use query::{ Query };
     use tuple::Tuple;
     use choice_vec::ChoiceVec;
+    use schema::Schema;
     use rand::random;
 
     // hashing
@@ -225,7 +403,7 @@ mod tests {
     fn hash_mask_correctness() {
         let query = Query::parse("a,b,c", 3).unwrap();
         let c_vec = ChoiceVec::parse("0,0:1,1:2,2", 3).unwrap();
-        let PartialHash { hash, mask } = PartialHash::from_query(&query, &c_vec);
+        let PartialHash { hash, mask } = PartialHash::from_query(&query, &c_vec, &Schema::untyped(3));
         assert_eq!(hash & mask, hash);
         assert_eq!(hash | mask, mask);
     }
@@ -234,7 +412,7 @@ mod tests {
     fn hash_when_known_isnt_0() {
         let query = Query::parse("a,b,c", 3).unwrap();
         let c_vec = ChoiceVec::parse("0,0:1,1:2,2", 3).unwrap();
-        let ma_hash = PartialHash::from_query(&query, &c_vec);
+        let ma_hash = PartialHash::from_query(&query, &c_vec, &Schema::untyped(3));
         assert!(ma_hash.mask != 0);
     }
 
@@ -242,7 +420,7 @@ mod tests {
     fn hash_with_unknown() {
         let query = Query::parse("?,?,?", 3).unwrap();
         let c_vec = ChoiceVec::parse("0,0:1,1:2,2", 3).unwrap();
-        let PartialHash { hash, mask } = PartialHash::from_query(&query, &c_vec);
+        let PartialHash { hash, mask } = PartialHash::from_query(&query, &c_vec, &Schema::untyped(3));
         assert_eq!(hash, 0);
         assert_eq!(mask, 0);
     }
@@ -252,8 +430,114 @@ mod tests {
         let c_vec = ChoiceVec::parse("0,0:0,1:1,0:1,1:2,0:2,1", 3).unwrap();
         let tuple = Tuple::parse("a,b,c", 3).unwrap();
         let query = Query::parse("a,b,c", 3).unwrap();
-        let ma_hash = PartialHash::from_query(&query, &c_vec);
-        assert_eq!(tuple.hash(&c_vec), ma_hash.hash);
+        let ma_hash = PartialHash::from_query(&query, &c_vec, &Schema::untyped(3));
+        assert_eq!(tuple.hash(&c_vec, &Schema::untyped(3)), ma_hash.hash);
+    }
+
+    #[test]
+    fn typed_query_hash_same_as_tuple_hash() {
+        let c_vec = ChoiceVec::parse("0,0:0,1:0,2:1,0:1,1:1,2", 2).unwrap();
+        let schema = Schema::parse("n:int,x:float").unwrap();
+        let tuple = Tuple::parse("007,2.50", 2).unwrap();
+        let query = Query::parse("7,2.5", 2).unwrap();
+        let ma_hash = PartialHash::from_query(&query, &c_vec, &schema);
+        assert_eq!(tuple.hash(&c_vec, &schema), ma_hash.hash);
+    }
+
+    #[test]
+    fn range_and_pattern_conditions_leave_bits_unknown() {
+        let c_vec = ChoiceVec::parse("0,0:1,0:0,1:1,1", 2).unwrap();
+        let schema = Schema::parse("n:int,s").unwrap();
+        let query = Query::parse_named("n>3,s=x", &schema).unwrap();
+        let PartialHash { mask, .. } = PartialHash::from_query(&query, &c_vec, &schema);
+        assert_eq!(mask & 0b1111, 0b1010);
+        let query = Query::parse_named("n BETWEEN 1 AND 2,s!=x", &schema).unwrap();
+        assert_eq!(PartialHash::from_query(&query, &c_vec, &schema).mask & 0b1111, 0);
+        let query = Query::parse_named("n=1,s LIKE x%", &schema).unwrap();
+        assert_eq!(PartialHash::from_query(&query, &c_vec, &schema).mask & 0b1111, 0b0101);
+    }
+
+    #[test]
+    fn one_hash_per_combination_of_values() {
+        // Give every bit, so that none are generated at random.
+        let bits: Vec<String> = (0..32).map(|i| format!("{},{}", i % 3, i / 3)).collect();
+        let c_vec = ChoiceVec::parse(&bits.join(":"), 3).unwrap();
+        let schema = Schema::untyped(3);
+        let query = Query::parse_named("attr0 IN (a, b, c),attr1 IN (x, y),attr2=z", &schema).unwrap();
+        let partial_hashes = PartialHash::for_query(&query, &c_vec, &schema);
+        assert!(partial_hashes.len() <= 6);
+        for a in &["a", "b", "c"] {
+            for x in &["x", "y"] {
+                let tuple = Tuple::parse(&format!("{},{},z", a, x), 3).unwrap();
+                let hash = tuple.hash(&c_vec, &schema);
+                assert!(partial_hashes.iter().any(|p| p.match_hash(hash)), "{} {}", a, x);
+            }
+        }
+        // A single value gives the same hash as `from_query`.
+        let query = Query::parse("a,?,z", 3).unwrap();
+        assert_eq!(PartialHash::for_query(&query, &c_vec, &schema),
+                   vec![PartialHash::from_query(&query, &c_vec, &schema)]);
+
+        // Too many combinations leave an attribute unknown.
+        let values: Vec<String> = (0..MAX_PARTIAL_HASHES + 1).map(|i| i.to_string()).collect();
+        let query = Query::parse_named(&format!("attr0 IN ({})", values.join(",")), &schema).unwrap();
+        assert_eq!(PartialHash::for_query(&query, &c_vec, &schema),
+                   vec![PartialHash::from_query(&Query::wildcard(3), &c_vec, &schema)]);
+    }
+
+    #[test]
+    fn constraints_from_boolean_expressions() {
+        let bits: Vec<String> = (0..32).map(|i| format!("{},{}", i % 2, i / 2)).collect();
+        let c_vec = ChoiceVec::parse(&bits.join(":"), 2).unwrap();
+        let schema = Schema::parse("a,b").unwrap();
+        let for_query = |input: &str| {
+            PartialHash::for_query(&Query::parse_named(input, &schema).unwrap(), &c_vec, &schema)
+        };
+        let covers = |partial_hashes: &[PartialHash], tuple: &str| {
+            let hash = Tuple::parse(tuple, 2).unwrap().hash(&c_vec, &schema);
+            partial_hashes.iter().any(|p| p.match_hash(hash))
+        };
+
+        let partial_hashes = for_query("(a=x OR a=y) AND b!=z");
+        assert_eq!(partial_hashes.len(), 2);
+        assert!(covers(&partial_hashes, "x,w") && covers(&partial_hashes, "y,\\N"));
+        let partial_hashes = for_query("(a=x AND b=1) OR (a=y AND b=2)");
+        assert_eq!(partial_hashes.len(), 2);
+        assert!(partial_hashes.iter().all(PartialHash::is_complete));
+        assert!(covers(&partial_hashes, "x,1") && covers(&partial_hashes, "y,2"));
+        // NOT is pushed down to the attributes.
+        assert_eq!(for_query("NOT (a!=\\N OR b=1)"), for_query("a=\\N"));
+        // Contradictions match no pages, and conditions that can't be hashed every page.
+        assert!(for_query("a=x AND a=y").is_empty());
+        assert_eq!(for_query("a=x OR b>1"), vec![PartialHash { hash: 0, mask: 0 }]);
+
+        // Too many alternatives fall back to the values each attribute can take.
+        let alternatives: Vec<String> = (0..MAX_PARTIAL_HASHES + 1)
+            .map(|i| format!("(a={} AND b={})", i, i))
+            .collect();
+        let partial_hashes = for_query(&alternatives.join(" OR "));
+        assert_eq!(partial_hashes, vec![PartialHash { hash: 0, mask: 0 }]);
+        let query = Query::parse_named("a=1 OR (a=1 AND b=2)", &schema).unwrap();
+        let PartialHash { mask, .. } = PartialHash::from_query(&query, &c_vec, &schema);
+        assert_eq!(mask, 0x55555555);
+    }
+
+    #[test]
+    fn union_yields_every_page_once() {
+        let partial_hashes = [
+            PartialHash { hash: 0b000, mask: 0b011 },
+            PartialHash { hash: 0b001, mask: 0b001 },
+            PartialHash { hash: 0b110, mask: 0b110 },
+        ];
+        for num_pages in 1u32..20 {
+            let expected: HashSet<u32> = partial_hashes.iter()
+                .flat_map(|p| p.matching_page_ids(num_pages))
+                .collect();
+            let results: Vec<u32> = PageIdUnion::new(&partial_hashes, num_pages).collect();
+            assert_eq!(results.len(), expected.len());
+            assert_eq!(results.into_iter().collect::<HashSet<u32>>(), expected);
+        }
+        assert_eq!(PageIdUnion::new(&[], 8).next(), None);
     }
 
     // matching hashed query
@@ -334,6 +618,31 @@ mod tests {
         assert_eq!(expected, results);
     }
 
+    #[test]
+    fn iter_yields_every_bucket_exactly_once() {
+        // Compare against the buckets of every hash matching the partial hash, over the low
+        // six bits, for relations of up to 40 pages.
+        for num_pages in 1u32..40 {
+            let depth = highest_set_bit(num_pages) - 1;
+            let split_pointer = num_pages - (1 << depth);
+            for mask in 0u32..64 {
+                for hash in (0u32..64).filter(|hash| hash & !mask == 0) {
+                    let expected: HashSet<u32> = (0u32..64)
+                        .filter(|h| h & mask == hash)
+                        .map(|h| {
+                            let bucket = h & ((1 << depth) - 1);
+                            if bucket < split_pointer { h & ((1 << (depth + 1)) - 1) } else { bucket }
+                        })
+                        .collect();
+                    let partial_hash = PartialHash { hash: hash, mask: mask | !0b111111 };
+                    let results: Vec<u32> = partial_hash.matching_page_ids(num_pages).collect();
+                    assert_eq!(results.len(), expected.len());
+                    assert_eq!(results.into_iter().collect::<HashSet<u32>>(), expected);
+                }
+            }
+        }
+    }
+
     #[test]
     fn iter_3_pages() {
         let iter = PageIdIter::new(&PartialHash { hash : 0b10, mask: 0b10}, 3);
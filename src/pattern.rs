@@ -0,0 +1,215 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+
+use regex::{self, Regex};
+
+use tuple::{Field, SyntaxError, parse_field, unescape_char};
+
+/// Kind of pattern, by how it was written.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum PatternKind {
+    /// `abc*`: values starting with `abc`.
+    Prefix,
+    /// `LIKE a%b_c`: values matching as in SQL, with `%` for any text and `_` for any character.
+    Like,
+    /// `/a.*b/`: values containing a match for the regular expression.
+    Regex,
+}
+
+/// A pattern that values of an attribute can match, compiled to a regular expression.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub kind: PatternKind,
+    regex: Regex,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum PatternError {
+    Syntax(SyntaxError),
+    /// A regular expression that doesn't compile, with the reason why.
+    InvalidRegex(String),
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatternError::Syntax(ref e) => write!(f, "syntax error: {:?}", e),
+            PatternError::InvalidRegex(ref e) => write!(f, "invalid regular expression: {}", e),
+        }
+    }
+}
+
+impl Error for PatternError {
+    fn description(&self) -> &str {
+        "invalid pattern"
+    }
+}
+
+impl Pattern {
+    /// Parse a query field as written, if it's a pattern:
+    ///
+    /// * `abc*` is a prefix, ending with a `*` that isn't quoted or escaped. The prefix is
+    ///   quoted and escaped like any other value.
+    /// * `LIKE a%b_c` is an SQL pattern. `%` and `_` are literal if quoted or escaped.
+    /// * `/a.*b/` is a regular expression, taken as written between the slashes.
+    ///
+    /// Returns None for fields that aren't patterns.
+    pub fn parse(raw: &str) -> Result<Option<Pattern>, PatternError> {
+        if let Some(like) = strip_like(raw) {
+            return parse_like(like).map(Some);
+        }
+        if raw.len() >= 2 && raw.starts_with('/') && raw.ends_with('/') {
+            return Pattern::regex(&raw[1..raw.len() - 1]).map(Some);
+        }
+        match strip_star(raw) {
+            Some(prefix) => match try!(parse_field(prefix).map_err(PatternError::Syntax)) {
+                Field::Value(prefix) => Ok(Some(Pattern::prefix(&prefix))),
+                Field::Wildcard => Err(PatternError::Syntax(SyntaxError::StrayQuestionMark)),
+                Field::Null => Err(PatternError::Syntax(SyntaxError::StrayNull)),
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Pattern for values starting with `prefix`.
+    pub fn prefix(prefix: &str) -> Pattern {
+        let regex = format!("^{}", regex::escape(prefix));
+        Pattern { kind: PatternKind::Prefix, regex: Regex::new(&regex).unwrap() }
+    }
+
+    /// Pattern for values containing a match for a regular expression.
+    pub fn regex(regex: &str) -> Result<Pattern, PatternError> {
+        let regex = try!(Regex::new(regex).map_err(|e| PatternError::InvalidRegex(e.to_string())));
+        Ok(Pattern { kind: PatternKind::Regex, regex: regex })
+    }
+
+    /// Check whether a value matches.
+    pub fn matches(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+}
+
+/// Patterns are the same if they are of the same kind and compile to the same expression.
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pattern {}
+
+impl PartialOrd for Pattern {
+    fn partial_cmp(&self, other: &Pattern) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pattern {
+    fn cmp(&self, other: &Pattern) -> Ordering {
+        (self.kind, self.regex.as_str()).cmp(&(other.kind, other.regex.as_str()))
+    }
+}
+
+/// Strip the `LIKE` keyword, in any case and followed by whitespace, from the start of a field.
+pub fn strip_like(raw: &str) -> Option<&str> {
+    let raw = raw.trim_start();
+    match (raw.get(..4), raw.get(4..)) {
+        (Some(keyword), Some(rest)) if keyword.eq_ignore_ascii_case("like") &&
+                                       rest.starts_with(char::is_whitespace) => {
+            Some(rest.trim_start())
+        }
+        _ => None
+    }
+}
+
+/// Strip a `*` that isn't quoted or escaped from the end of a field.
+fn strip_star(raw: &str) -> Option<&str> {
+    let mut quoted = false;
+    let mut star = None;
+    let mut chars = raw.char_indices();
+    while let Some((i, c)) = chars.next() {
+        star = None;
+        match c {
+            '\\' => { chars.next(); }
+            '"' => quoted = !quoted,
+            '*' if !quoted => star = Some(i),
+            _ => {}
+        }
+    }
+    star.map(|i| &raw[..i])
+}
+
+/// Translate an SQL `LIKE` pattern into an anchored regular expression.
+fn parse_like(like: &str) -> Result<Pattern, PatternError> {
+    let mut regex = String::from("(?s)^");
+    let mut quoted = false;
+    let mut chars = like.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = try!(chars.next()
+                    .ok_or(PatternError::Syntax(SyntaxError::TrailingBackslash)));
+                regex.push_str(&regex::escape(&unescape_char(escaped).to_string()));
+            }
+            '"' => quoted = !quoted,
+            '%' if !quoted => regex.push_str(".*"),
+            '_' if !quoted => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string()))
+        }
+    }
+    if quoted {
+        return Err(PatternError::Syntax(SyntaxError::UnterminatedQuote));
+    }
+    regex.push('$');
+    Ok(Pattern { kind: PatternKind::Like, regex: Regex::new(&regex).unwrap() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Pattern {
+        Pattern::parse(raw).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_patterns() {
+        assert_eq!(parse("abc*"), Pattern::prefix("abc"));
+        assert_eq!(parse("\"a*b,\"*"), Pattern::prefix("a*b,"));
+        assert_eq!(parse("like a%"), parse("LIKE  a%"));
+        assert_eq!(parse("/a.*b/").kind, PatternKind::Regex);
+        assert_eq!(parse("/a.*b/"), Pattern::regex("a.*b").unwrap());
+        for raw in &["abc", "a\\*", "\"a*\"", "a*b", "liked%", "\"LIKE a\"", "/a", "/"] {
+            assert_eq!(Pattern::parse(raw), Ok(None), "{}", raw);
+        }
+        assert_eq!(Pattern::parse("?*"), Err(PatternError::Syntax(SyntaxError::StrayQuestionMark)));
+        assert_eq!(Pattern::parse("LIKE \"a"), Err(PatternError::Syntax(SyntaxError::UnterminatedQuote)));
+        assert!(matches!(Pattern::parse("/a(/"), Err(PatternError::InvalidRegex(_))));
+    }
+
+    #[test]
+    fn match_patterns() {
+        let data = [
+            ("abc*", "abcdef", true),
+            ("abc*", "ab", false),
+            ("abc*", "xabc", false),
+            ("a.*", "a.b", true),
+            ("a.*", "ab", false),
+            ("LIKE %err_r%", "an error here", true),
+            ("LIKE %err_r%", "an errr", false),
+            ("LIKE a%", "a\nb", true),
+            ("LIKE a.c", "abc", false),
+            ("LIKE 100\\%", "100%", true),
+            ("LIKE 100\\%", "1000", false),
+            ("LIKE \"a_\"%", "a_b", true),
+            ("LIKE \"a_\"%", "ab", false),
+            ("/^[0-9]+$/", "123", true),
+            ("/^[0-9]+$/", "12a", false),
+            ("/rr/", "error", true),
+        ];
+        for &(raw, value, exp) in data.iter() {
+            assert_eq!(parse(raw).matches(value), exp, "{} {}", raw, value);
+        }
+    }
+}
@@ -1,55 +1,697 @@
-use tuple::Tuple;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+use tuple::{Tuple, Field, SyntaxError, split_raw, split_words, find_unquoted_where, parse_field};
+use schema::{Schema, SchemaError};
+use pattern::{Pattern, PatternError};
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub struct Query<'a> {
-    pub matches: Vec<Option<&'a str>>
+    pub expr: Expr<'a>,
+    /// Indices of the attributes that matching tuples are projected onto, in order,
+    /// or None to keep whole tuples.
+    pub columns: Option<Vec<usize>>,
+}
+
+/// Condition that tuples match, as a tree of conditions on single attributes.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+pub enum Expr<'a> {
+    /// The attribute at an index matches.
+    Attr(usize, AttrMatch<'a>),
+    /// The condition is false. Like the condition, it is unknown for NULLs that conditions
+    /// on values can't decide, and so never holds for them.
+    Not(Box<Expr<'a>>),
+    /// All of the conditions hold, which is always the case if there are none.
+    And(Vec<Expr<'a>>),
+    /// Any of the conditions holds, which is never the case if there are none.
+    Or(Vec<Expr<'a>>),
+}
+
+/// Condition on the value of a single attribute.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+pub enum AttrMatch<'a> {
+    /// Any value, including NULL.
+    Any,
+    /// A value equal to this one, as a value of the attribute's type. Never NULL.
+    Value(Cow<'a, str>),
+    IsNull,
+    IsNotNull,
+    /// A value that compares to this one as given. Never NULL.
+    Compare(Comparison, Cow<'a, str>),
+    /// A value from the first to the second, inclusive. Never NULL.
+    Between(Cow<'a, str>, Cow<'a, str>),
+    /// A value whose canonical form matches the pattern. Never NULL.
+    Pattern(Pattern),
+    /// A value equal to any of these. Never NULL.
+    In(Vec<Cow<'a, str>>),
+}
+
+/// Comparison of an attribute's value against another value, as values of its type.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    NotEqual,
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(PartialEq, Eq, Debug)]
 pub enum ParseError {
     AttributeMismatch(usize, usize),
+    Syntax(SyntaxError),
+    /// A field of a named query that isn't a condition on an attribute.
+    InvalidCondition(String),
+    UnknownAttribute(String),
+    /// A parenthesis without a partner, at the named query's text from it onwards.
+    UnmatchedParenthesis(String),
+    InvalidValue(SchemaError),
+    InvalidPattern(PatternError),
+}
+
+impl Comparison {
+    /// Check whether a value that orders as given against the other value satisfies
+    /// the comparison.
+    pub fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessOrEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterOrEqual => ordering != Ordering::Less,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+        }
+    }
+}
+
+impl<'a> AttrMatch<'a> {
+    /// Check whether a value of the attribute at `index` matches.
+    pub fn matches(&self, schema: &Schema, index: usize, value: Option<&str>) -> bool {
+        let compare = |other: &str| value.map(|value| schema.compare_values(index, value, other));
+        match *self {
+            AttrMatch::Any => true,
+            AttrMatch::Value(ref expected) => {
+                value.is_some_and(|value| schema.values_equal(index, expected, value))
+            }
+            AttrMatch::IsNull => value.is_none(),
+            AttrMatch::IsNotNull => value.is_some(),
+            AttrMatch::Compare(comparison, ref other) => {
+                compare(other).is_some_and(|ordering| comparison.holds(ordering))
+            }
+            AttrMatch::Between(ref low, ref high) => {
+                compare(low).is_some_and(|ordering| ordering != Ordering::Less) &&
+                compare(high).is_some_and(|ordering| ordering != Ordering::Greater)
+            }
+            AttrMatch::Pattern(ref pattern) => value.is_some_and(|value| {
+                pattern.matches(&schema.attrs[index].attr_type.canonical(value))
+            }),
+            AttrMatch::In(ref values) => value.is_some_and(|value| {
+                values.iter().any(|expected| schema.values_equal(index, expected, value))
+            }),
+        }
+    }
+
+    /// Evaluate the match on a value of the attribute at `index`, which is unknown (None) for
+    /// NULLs when matching values, and otherwise whether it matches.
+    pub fn eval(&self, schema: &Schema, index: usize, value: Option<&str>) -> Option<bool> {
+        match (self, value) {
+            (&AttrMatch::Any, _) | (&AttrMatch::IsNull, _) | (&AttrMatch::IsNotNull, _) |
+            (_, Some(_)) => Some(self.matches(schema, index, value)),
+            (_, None) => None
+        }
+    }
+
+    /// Hashes of the values of the attribute at `index` that match, if there are few enough
+    /// values to list.
+    pub fn hashes(&self, schema: &Schema, index: usize) -> Option<Vec<u32>> {
+        match *self {
+            AttrMatch::Value(ref value) => Some(vec![schema.hash_value(index, Some(value))]),
+            AttrMatch::IsNull => Some(vec![schema.hash_value(index, None)]),
+            AttrMatch::In(ref values) => {
+                Some(values.iter().map(|value| schema.hash_value(index, Some(value))).collect())
+            }
+            AttrMatch::Any | AttrMatch::IsNotNull | AttrMatch::Compare(..) | AttrMatch::Between(..) |
+            AttrMatch::Pattern(_) => None
+        }
+    }
+
+    /// The values that matching values are compared against.
+    fn operands(&self) -> Vec<&str> {
+        match *self {
+            AttrMatch::Value(ref value) | AttrMatch::Compare(_, ref value) => vec![value],
+            AttrMatch::Between(ref low, ref high) => vec![low, high],
+            AttrMatch::In(ref values) => values.iter().map(|value| &**value).collect(),
+            AttrMatch::Any | AttrMatch::IsNull | AttrMatch::IsNotNull | AttrMatch::Pattern(_) => vec![]
+        }
+    }
+}
+
+/// Parse a query field as written: a value, `?`, `\N`, or a pattern as described
+/// for `Pattern::parse`.
+fn parse_value_match<'a>(raw: &str) -> Result<AttrMatch<'a>, ParseError> {
+    match try!(Pattern::parse(raw).map_err(ParseError::InvalidPattern)) {
+        Some(pattern) => Ok(AttrMatch::Pattern(pattern)),
+        None => parse_field(raw).map(AttrMatch::from).map_err(ParseError::Syntax)
+    }
+}
+
+impl<'a> From<Field> for AttrMatch<'a> {
+    fn from(field: Field) -> AttrMatch<'a> {
+        match field {
+            Field::Wildcard => AttrMatch::Any,
+            Field::Null => AttrMatch::IsNull,
+            Field::Value(value) => AttrMatch::Value(Cow::Owned(value))
+        }
+    }
+}
+
+/// Operators that order values, longest first so that `<=` isn't read as `<`.
+const OPERATORS: [(&str, Comparison); 4] = [
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+/// Parse a condition on an attribute, like `age=30`, `age>=30`, `age IS NULL`,
+/// `age BETWEEN 20 AND 30` or `name LIKE a%`, into the attribute name and its match.
+/// Returns None if the field isn't written as a condition at all.
+fn parse_condition<'a>(field: &str) -> Option<Result<(&str, AttrMatch<'a>), ParseError>> {
+    match find_unquoted_where(field, |c| c == '=' || c == '<' || c == '>') {
+        Some(i) => Some(parse_comparison(field, i)),
+        None => parse_keyword_condition(field)
+    }
+}
+
+/// Parse a condition whose operator (`=`, `!=`, `<`, `<=`, `>` or `>=`) is at or just
+/// before byte offset `i`. `!=` accepts `\N` to mean `IS NOT NULL`, like `=` does `IS NULL`,
+/// and only `=` accepts patterns.
+fn parse_comparison<'a>(field: &str, i: usize) -> Result<(&str, AttrMatch<'a>), ParseError> {
+    let (name, rest) = field.split_at(i);
+    let (name, comparison, value) = match name.strip_suffix('!') {
+        Some(name) => (name, Some(Comparison::NotEqual), &rest[1..]),
+        None => {
+            let operator = OPERATORS.iter()
+                .find_map(|&(op, comparison)| rest.strip_prefix(op).map(|value| (comparison, value)));
+            match operator {
+                Some((comparison, value)) => (name, Some(comparison), value),
+                None => (name, None, &rest[1..])
+            }
+        }
+    };
+    let comparison = match comparison {
+        Some(comparison) => comparison,
+        None => return parse_value_match(value).map(|attr_match| (name.trim(), attr_match))
+    };
+    let value = try!(parse_field(value).map_err(ParseError::Syntax));
+    let attr_match = match (comparison, value) {
+        (Comparison::NotEqual, Field::Null) => AttrMatch::IsNotNull,
+        (comparison, Field::Value(value)) => AttrMatch::Compare(comparison, Cow::Owned(value)),
+        (_, Field::Wildcard) | (_, Field::Null) => {
+            return Err(ParseError::InvalidCondition(field.to_string()));
+        }
+    };
+    Ok((name.trim(), attr_match))
+}
+
+/// Parse a condition of the form `<name> IS NULL`, `<name> IS NOT NULL`,
+/// `<name> BETWEEN <low> AND <high>`, `<name> LIKE <pattern>` or `<name> IN (<values>)`,
+/// with the keywords in any case.
+fn parse_keyword_condition<'a>(field: &str) -> Option<Result<(&str, AttrMatch<'a>), ParseError>> {
+    let words = split_words(field);
+    let is_keyword = |i: usize, keyword: &str| words[i].eq_ignore_ascii_case(keyword);
+    // Everything after the attribute's name.
+    let rest = field.trim_start()[words.first().map_or(0, |name| name.len())..].trim_start();
+    let attr_match = match words.len() {
+        3 if is_keyword(1, "is") && is_keyword(2, "null") => Ok(AttrMatch::IsNull),
+        4 if is_keyword(1, "is") && is_keyword(2, "not") && is_keyword(3, "null") => {
+            Ok(AttrMatch::IsNotNull)
+        }
+        5 if is_keyword(1, "between") && is_keyword(3, "and") => {
+            parse_operand(words[2], field).and_then(|low| {
+                parse_operand(words[4], field).map(|high| AttrMatch::Between(low, high))
+            })
+        }
+        n if n >= 3 && is_keyword(1, "like") => {
+            // The pattern, with its keyword, is written as it would be for a positional query.
+            parse_value_match(rest)
+        }
+        n if n >= 3 && is_keyword(1, "in") => {
+            let list = rest[words[1].len()..].trim();
+            if !list.starts_with('(') || !list.ends_with(')') {
+                return None;
+            }
+            parse_in_list(&list[1..list.len() - 1], field)
+        }
+        _ => return None
+    };
+    Some(attr_match.map(|attr_match| (words[0], attr_match)))
+}
+
+/// Parse the comma-separated values of an `IN` list, ignoring whitespace around them.
+fn parse_in_list<'a>(list: &str, field: &str) -> Result<AttrMatch<'a>, ParseError> {
+    if list.trim().is_empty() {
+        return Err(ParseError::InvalidCondition(field.to_string()));
+    }
+    let values = try!(split_raw(list).into_iter()
+        .map(|value| parse_operand(value.trim(), field))
+        .collect());
+    Ok(AttrMatch::In(values))
+}
+
+/// Token of a named query.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Token<'s> {
+    Open,
+    Close,
+    Comma,
+    /// Text up to whitespace, a parenthesis or a comma that isn't quoted or escaped.
+    Word(&'s str),
+}
+
+/// Split a named query into tokens, each with the byte offsets where it starts and ends.
+/// A regular expression at the start of a word or value is part of the word, parentheses
+/// and all.
+fn tokenize<'s>(input: &'s str) -> Vec<(usize, usize, Token<'s>)> {
+    let mut tokens = vec![];
+    let mut word_start = None;
+    let mut quoted = false;
+    let mut prev = None;
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        let starts_value = word_start.is_none() || prev == Some('=');
+        prev = Some(c);
+        let token = match c {
+            '(' if !quoted => Some(Token::Open),
+            ')' if !quoted => Some(Token::Close),
+            ',' if !quoted => Some(Token::Comma),
+            c if c.is_whitespace() && !quoted => None,
+            c => {
+                word_start = word_start.or(Some(i));
+                match c {
+                    '\\' => { chars.next(); }
+                    '"' => quoted = !quoted,
+                    '/' if !quoted && starts_value => {
+                        if let Some(end) = regex_end(input, i) {
+                            while chars.next().is_some_and(|(j, _)| j < end) {}
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+        };
+        if let Some(start) = word_start.take() {
+            tokens.push((start, i, Token::Word(&input[start..i])));
+        }
+        if let Some(token) = token {
+            tokens.push((i, i + 1, token));
+        }
+    }
+    if let Some(start) = word_start {
+        tokens.push((start, input.len(), Token::Word(&input[start..])));
+    }
+    tokens
+}
+
+/// Find the slash that ends a regular expression starting with the one at byte offset `start`:
+/// the next one that isn't escaped and comes at the end of a word.
+fn regex_end(input: &str, start: usize) -> Option<usize> {
+    let mut chars = input[start + 1..].char_indices().map(|(i, c)| (start + 1 + i, c)).peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); }
+            '/' => match chars.peek() {
+                None => return Some(i),
+                Some(&(_, next)) if next.is_whitespace() || next == ',' || next == ')' => return Some(i),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Recursive descent parser for named queries:
+///
+/// ```text
+/// or        := and (OR and)*
+/// and       := unary ((AND | ,) unary)*
+/// unary     := NOT unary | ( or ) | condition
+/// ```
+///
+/// with the keywords in any case.
+struct ExprParser<'s> {
+    input: &'s str,
+    tokens: Vec<(usize, usize, Token<'s>)>,
+    pos: usize,
+}
+
+impl<'s> ExprParser<'s> {
+    fn new(input: &'s str) -> ExprParser<'s> {
+        ExprParser { input: input, tokens: tokenize(input), pos: 0 }
+    }
+
+    fn peek(&self) -> Option<Token<'s>> {
+        self.tokens.get(self.pos).map(|&(_, _, token)| token)
+    }
+
+    /// Check whether the token at an offset from the current one is a keyword.
+    fn is_keyword(&self, offset: usize, keyword: &str) -> bool {
+        match self.tokens.get(self.pos + offset) {
+            Some(&(_, _, Token::Word(word))) => word.eq_ignore_ascii_case(keyword),
+            _ => false
+        }
+    }
+
+    /// The input from the current token onwards.
+    fn rest(&self) -> &'s str {
+        self.tokens.get(self.pos).map_or("", |&(start, _, _)| &self.input[start..])
+    }
+
+    fn parse<'a>(&mut self, schema: &Schema) -> Result<Expr<'a>, ParseError> {
+        let expr = try!(self.parse_or(schema));
+        match self.peek() {
+            None => Ok(expr),
+            Some(Token::Close) => Err(ParseError::UnmatchedParenthesis(self.rest().to_string())),
+            Some(_) => Err(ParseError::InvalidCondition(self.rest().to_string()))
+        }
+    }
+
+    fn parse_or<'a>(&mut self, schema: &Schema) -> Result<Expr<'a>, ParseError> {
+        let mut exprs = vec![try!(self.parse_and(schema))];
+        while self.is_keyword(0, "or") {
+            self.pos += 1;
+            exprs.push(try!(self.parse_and(schema)));
+        }
+        Ok(Expr::or(exprs))
+    }
+
+    fn parse_and<'a>(&mut self, schema: &Schema) -> Result<Expr<'a>, ParseError> {
+        let mut exprs = vec![try!(self.parse_unary(schema))];
+        while self.peek() == Some(Token::Comma) || self.is_keyword(0, "and") {
+            self.pos += 1;
+            exprs.push(try!(self.parse_unary(schema)));
+        }
+        Ok(Expr::and(exprs))
+    }
+
+    fn parse_unary<'a>(&mut self, schema: &Schema) -> Result<Expr<'a>, ParseError> {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("not") => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(try!(self.parse_unary(schema)))))
+            }
+            Some(Token::Open) => {
+                let open = self.rest();
+                self.pos += 1;
+                let expr = try!(self.parse_or(schema));
+                if self.peek() != Some(Token::Close) {
+                    return Err(ParseError::UnmatchedParenthesis(open.to_string()));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            _ => {
+                let rest = self.rest();
+                let field = self.next_condition();
+                if field.is_empty() {
+                    return Err(ParseError::InvalidCondition(rest.to_string()));
+                }
+                let (name, attr_match) = try!(parse_condition(field)
+                    .unwrap_or_else(|| Err(ParseError::InvalidCondition(field.to_string()))));
+                let i = try!(schema.index_of(name)
+                    .ok_or_else(|| ParseError::UnknownAttribute(name.to_string())));
+                Ok(Expr::Attr(i, attr_match))
+            }
+        }
+    }
+
+    /// Take the text of the condition on a single attribute that starts at the current token:
+    /// its words up to a comma, a parenthesis, `AND` or `OR`. `BETWEEN` conditions take their
+    /// `AND`, and `IN` conditions their parenthesised list.
+    fn next_condition(&mut self) -> &'s str {
+        let start = self.pos;
+        let words = |parser: &ExprParser, n: usize| (0..n).all(|offset| {
+            matches!(parser.tokens.get(parser.pos + offset), Some(&(_, _, Token::Word(_))))
+        });
+        if words(self, 5) && self.is_keyword(1, "between") && self.is_keyword(3, "and") {
+            self.pos += 5;
+        }
+        else if words(self, 2) && self.is_keyword(1, "in") &&
+                self.tokens.get(self.pos + 2).map(|&(_, _, token)| token) == Some(Token::Open) {
+            self.pos += 2;
+            while self.peek().is_some_and(|token| token != Token::Close) {
+                self.pos += 1;
+            }
+            self.pos = (self.pos + 1).min(self.tokens.len());
+        }
+        else {
+            while words(self, 1) && !self.is_keyword(0, "and") && !self.is_keyword(0, "or") {
+                self.pos += 1;
+            }
+        }
+        if self.pos == start {
+            return "";
+        }
+        let (from, _, _) = self.tokens[start];
+        let (_, to, _) = self.tokens[self.pos - 1];
+        &self.input[from..to]
+    }
+}
+
+/// Parse a value that a condition compares against, which can't be `?` or `\N`.
+fn parse_operand<'a>(s: &str, field: &str) -> Result<Cow<'a, str>, ParseError> {
+    match try!(parse_field(s).map_err(ParseError::Syntax)) {
+        Field::Value(value) => Ok(Cow::Owned(value)),
+        Field::Wildcard | Field::Null => Err(ParseError::InvalidCondition(field.to_string()))
+    }
+}
+
+impl<'a> Expr<'a> {
+    /// Conjunction of conditions, which is the condition itself if there's only one.
+    pub fn and(mut exprs: Vec<Expr<'a>>) -> Expr<'a> {
+        if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::And(exprs) }
+    }
+
+    /// Disjunction of conditions, which is the condition itself if there's only one.
+    pub fn or(mut exprs: Vec<Expr<'a>>) -> Expr<'a> {
+        if exprs.len() == 1 { exprs.pop().unwrap() } else { Expr::Or(exprs) }
+    }
+
+    /// Check whether a tuple's values satisfy the condition.
+    pub fn matches(&self, tuple: &Tuple, schema: &Schema) -> bool {
+        self.matches_with(&|i| tuple.values[i].as_deref(), schema)
+    }
+
+    /// Check whether the values that `value` gives for each attribute's index satisfy
+    /// the condition.
+    pub fn matches_with<'v, F>(&self, value: &F, schema: &Schema) -> bool
+        where F: Fn(usize) -> Option<&'v str>
+    {
+        self.eval_with(value, schema) == Some(true)
+    }
+
+    /// Evaluate the condition on the values that `value` gives for each attribute's index,
+    /// as in SQL: None if it is unknown because of a NULL, which `NOT` leaves unknown.
+    pub fn eval_with<'v, F>(&self, value: &F, schema: &Schema) -> Option<bool>
+        where F: Fn(usize) -> Option<&'v str>
+    {
+        match *self {
+            Expr::Attr(i, ref attr_match) => attr_match.eval(schema, i, value(i)),
+            Expr::Not(ref expr) => expr.eval_with(value, schema).map(|holds| !holds),
+            Expr::And(ref exprs) => eval_all(exprs, false, value, schema),
+            Expr::Or(ref exprs) => eval_all(exprs, true, value, schema),
+        }
+    }
+
+    /// Mark the attributes that the condition looks at.
+    fn mark_attrs(&self, used: &mut [bool]) {
+        match *self {
+            Expr::Attr(_, AttrMatch::Any) => {}
+            Expr::Attr(i, _) => used[i] = true,
+            Expr::Not(ref expr) => expr.mark_attrs(used),
+            Expr::And(ref exprs) | Expr::Or(ref exprs) => {
+                for expr in exprs {
+                    expr.mark_attrs(used);
+                }
+            }
+        }
+    }
+
+    /// Check that the values to match are valid for their attributes' types.
+    fn check_values(&self, schema: &Schema) -> Result<(), ParseError> {
+        match *self {
+            Expr::Attr(i, ref attr_match) => {
+                for value in attr_match.operands() {
+                    try!(schema.check_value(i, value).map_err(ParseError::InvalidValue));
+                }
+                Ok(())
+            }
+            Expr::Not(ref expr) => expr.check_values(schema),
+            Expr::And(ref exprs) | Expr::Or(ref exprs) => {
+                exprs.iter().try_for_each(|expr| expr.check_values(schema))
+            }
+        }
+    }
+}
+
+/// Evaluate a conjunction, or a disjunction if `decisive` is true: any condition that
+/// evaluates to `decisive` decides it, and otherwise it is unknown if any condition is.
+fn eval_all<'v, F>(exprs: &[Expr], decisive: bool, value: &F, schema: &Schema) -> Option<bool>
+    where F: Fn(usize) -> Option<&'v str>
+{
+    let mut result = Some(!decisive);
+    for expr in exprs {
+        match expr.eval_with(value, schema) {
+            Some(holds) if holds == decisive => return Some(decisive),
+            Some(_) => {}
+            None => result = None
+        }
+    }
+    result
 }
 
 impl<'a> Query<'a> {
     /// Construct a Query that matches anything for a given number of attributes.
     pub fn wildcard(num_attrs: u32) -> Query<'a> {
-        Query { matches: vec![None; num_attrs as usize] }
+        Query::from_matches(vec![AttrMatch::Any; num_attrs as usize])
     }
 
-    pub fn parse(input: &'a str, num_attrs: u32) -> Result<Query<'a>, ParseError> {
-        let matches: Vec<Option<&'a str>> = input
-            .split(',')
-            .map(|x| if x == "?" { None } else { Some(x) })
+    /// Construct a Query that matches tuples whose values each match the condition at
+    /// their attribute's position.
+    pub fn from_matches(matches: Vec<AttrMatch<'a>>) -> Query<'a> {
+        let exprs = matches.into_iter().enumerate()
+            .filter(|(_, attr_match)| *attr_match != AttrMatch::Any)
+            .map(|(i, attr_match)| Expr::Attr(i, attr_match))
             .collect();
+        Query { expr: Expr::and(exprs), columns: None }
+    }
+
+    /// Parse comma-separated values, with `?` for attributes that can take any value,
+    /// `\N` for attributes that must be NULL, and patterns as described for `Pattern::parse`.
+    /// Values may be quoted or escaped as described for `split_fields`.
+    pub fn parse(input: &str, num_attrs: u32) -> Result<Query<'a>, ParseError> {
+        let matches: Vec<AttrMatch<'a>> = try!(split_raw(input).into_iter()
+            .map(parse_value_match)
+            .collect());
 
         let match_len = matches.len();
         if match_len == num_attrs as usize {
-            return Ok(Query { matches: matches });
+            return Ok(Query::from_matches(matches));
         }
         else {
             return Err(ParseError::AttributeMismatch(num_attrs as usize, match_len));
         }
     }
 
-    pub fn matches_tuple(&self, tuple: &Tuple) -> bool {
-        debug_assert!(self.matches.len() == tuple.values.len());
+    /// Parse conditions on attributes by name, like `name=alice`, `age>=30`,
+    /// `age BETWEEN 20 AND 30`, `age IS NULL`, `name=al*`, `name LIKE %li%` or
+    /// `age IN (20, 30)`, combined with `NOT`, `AND`, `OR` and parentheses, with `NOT`
+    /// binding tightest and `OR` loosest. A comma is the same as `AND`. Attributes that
+    /// aren't mentioned can take any value. Parentheses, commas and the keywords `AND` and
+    /// `OR` must be quoted or escaped in values.
+    pub fn parse_named(input: &str, schema: &Schema) -> Result<Query<'a>, ParseError> {
+        let expr = try!(ExprParser::new(input).parse(schema));
+        try!(expr.check_values(schema));
+        Ok(Query { expr: expr, columns: None })
+    }
+
+    /// Parse a query for a relation with the given schema, either by name or by position.
+    /// The query is by name if it starts with `(` or `NOT` followed by more of the query, or if
+    /// its first condition is written as one: it has an `=`, `<` or `>` that isn't quoted or
+    /// escaped, or is an `IS`, `BETWEEN`, `LIKE` or `IN` condition.
+    pub fn parse_with_schema(input: &str, schema: &Schema) -> Result<Query<'a>, ParseError> {
+        let mut parser = ExprParser::new(input);
+        let named = match parser.peek() {
+            Some(Token::Open) => true,
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("not") => {
+                matches!(parser.tokens.get(1), Some(&(_, _, Token::Word(_))) | Some(&(_, _, Token::Open)))
+            }
+            _ => parse_condition(parser.next_condition()).is_some()
+        };
+        if named {
+            return Query::parse_named(input, schema);
+        }
+        let query = try!(Query::parse(input, schema.num_attrs()));
+        try!(query.expr.check_values(schema));
+        Ok(query)
+    }
+
+    /// Check whether a tuple matches, comparing values as values of their attributes' types.
+    pub fn matches_tuple(&self, tuple: &Tuple, schema: &Schema) -> bool {
         trace!("Query::matches_tuple with: {:?}, {:?}", self, tuple);
-        for i in 0..tuple.values.len() {
-            if let Some(query_attr) = self.matches[i] {
-                if query_attr != &tuple.values[i] {
-                    return false;
-                }
+        self.expr.matches(tuple, schema)
+    }
+
+    /// Check whether the query matches every tuple.
+    pub fn is_wildcard(&self) -> bool {
+        self.expr == Expr::And(vec![])
+    }
+
+    /// Project matching tuples onto the attributes at `columns`, in that order.
+    pub fn project(mut self, columns: Vec<usize>) -> Query<'a> {
+        self.columns = Some(columns);
+        self
+    }
+
+    /// Parse comma-separated attributes to project onto, by name or by position from 0.
+    pub fn parse_columns(input: &str, schema: &Schema) -> Result<Vec<usize>, ParseError> {
+        input.split(',').map(|column| {
+            let column = column.trim();
+            let index = match column.parse::<usize>() {
+                Ok(i) if i < schema.attrs.len() => Some(i),
+                Ok(_) => None,
+                Err(_) => schema.index_of(column)
+            };
+            index.ok_or_else(|| ParseError::UnknownAttribute(column.to_string()))
+        }).collect()
+    }
+
+    /// Mark the attributes that must be read from a tuple to match and project it, or return
+    /// None if the query keeps whole tuples.
+    pub fn needed_attrs(&self, num_attrs: usize) -> Option<Vec<bool>> {
+        self.columns.as_ref().map(|columns| {
+            let mut needed = vec![false; num_attrs];
+            self.expr.mark_attrs(&mut needed);
+            for &i in columns {
+                needed[i] = true;
+            }
+            needed
+        })
+    }
+
+    /// Project a matching tuple onto the query's columns, if it has any.
+    pub fn project_tuple(&self, tuple: Tuple) -> Tuple {
+        match self.columns {
+            Some(ref columns) => {
+                Tuple { values: columns.iter().map(|&i| tuple.values[i].clone()).collect() }
             }
+            None => tuple
         }
-        true
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use super::{ Query, ParseError };
-    use tuple::Tuple;
+    use std::borrow::Cow;
+
+    use super::{ Query, Expr, AttrMatch, Comparison, ParseError };
+    use pattern::{Pattern, PatternError};
+    use tuple::{Tuple, SyntaxError};
+    use schema::{Schema, SchemaError, AttrType};
+
+    /// The condition on the attribute at `index` of a conjunction of single conditions.
+    fn attr_match<'a>(query: &Query<'a>, index: usize) -> AttrMatch<'a> {
+        let exprs = match query.expr {
+            Expr::And(ref exprs) => exprs.clone(),
+            ref expr => vec![expr.clone()]
+        };
+        exprs.into_iter().filter_map(|expr| match expr {
+            Expr::Attr(i, attr_match) if i == index => Some(attr_match),
+            _ => None
+        }).next().unwrap_or(AttrMatch::Any)
+    }
 
     // query parsing matching
 
@@ -72,14 +714,224 @@ mod tests {
     fn parse_correctly_identify_unknowns() {
         let query = Query::parse("a,?,c", 3);
         if let Ok(query) = query {
-            assert!(query.matches[0] != None);
-            assert!(query.matches[1] == None);
-            assert!(query.matches[2] != None);
+            assert!(attr_match(&query, 0) != AttrMatch::Any);
+            assert!(attr_match(&query, 1) == AttrMatch::Any);
+            assert!(attr_match(&query, 2) != AttrMatch::Any);
         } else {
             panic!();
         }
     }
 
+    #[test]
+    fn parse_escaped_values() {
+        let query = Query::parse("\\?,\"a,b\",?", 3).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::Value(Cow::Borrowed("?")));
+        assert_eq!(attr_match(&query, 1), AttrMatch::Value(Cow::Borrowed("a,b")));
+        assert_eq!(attr_match(&query, 2), AttrMatch::Any);
+        assert_eq!(Query::parse("a?", 1), Err(ParseError::Syntax(SyntaxError::StrayQuestionMark)));
+    }
+
+    #[test]
+    fn parse_by_name() {
+        let schema = Schema::parse("name,age:int,city").unwrap();
+        let query = Query::parse_named("age=30, name=\"a=b\"", &schema).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::Value(Cow::Borrowed("a=b")));
+        assert_eq!(attr_match(&query, 1), AttrMatch::Value(Cow::Borrowed("30")));
+        assert_eq!(attr_match(&query, 2), AttrMatch::Any);
+        assert_eq!(attr_match(&Query::parse_named("city=?", &schema).unwrap(), 2), AttrMatch::Any);
+
+        let errors = [
+            ("age", ParseError::InvalidCondition("age".to_string())),
+            ("name=a,bob", ParseError::InvalidCondition("bob".to_string())),
+            ("age is not", ParseError::InvalidCondition("age is not".to_string())),
+            ("height=2", ParseError::UnknownAttribute("height".to_string())),
+            ("(age=1", ParseError::UnmatchedParenthesis("(age=1".to_string())),
+            ("age=1)", ParseError::UnmatchedParenthesis(")".to_string())),
+            ("age=old", ParseError::InvalidValue(
+                SchemaError::InvalidValue("age".to_string(), AttrType::Int, "old".to_string())
+            )),
+        ];
+        for &(input, ref error) in errors.iter() {
+            assert_eq!(Query::parse_named(input, &schema).as_ref(), Err(error));
+        }
+    }
+
+    #[test]
+    fn parse_by_name_or_position() {
+        let schema = Schema::parse("name,age:int").unwrap();
+        let by_name = Query::parse_with_schema("age=30", &schema).unwrap();
+        assert_eq!(by_name, Query::parse("?,30", 2).unwrap());
+        let by_position = Query::parse_with_schema("a\\=b,?", &schema).unwrap();
+        assert_eq!(attr_match(&by_position, 0), AttrMatch::Value(Cow::Borrowed("a=b")));
+        assert!(Query::parse_with_schema("?,old", &schema).is_err());
+        let null_by_name = Query::parse_with_schema("age IS NULL", &schema).unwrap();
+        assert_eq!(null_by_name, Query::parse("?,\\N", 2).unwrap());
+    }
+
+    #[test]
+    fn parse_nulls() {
+        let schema = Schema::parse("name,age:int,city").unwrap();
+        let query = Query::parse_named("name=\\N, age is not null, city  IS  NULL", &schema).unwrap();
+        assert_eq!(query, Query::from_matches(vec![AttrMatch::IsNull, AttrMatch::IsNotNull, AttrMatch::IsNull]));
+        let query = Query::parse("\\N,\\\\N,?", 3).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::IsNull);
+        assert_eq!(attr_match(&query, 1), AttrMatch::Value(Cow::Borrowed("\\N")));
+    }
+
+    #[test]
+    fn parse_comparisons() {
+        let schema = Schema::parse("name,age:int,city").unwrap();
+        let value = |v: &'static str| Cow::Borrowed(v);
+        let data = [
+            ("age<30", AttrMatch::Compare(Comparison::Less, value("30"))),
+            (" age<=30", AttrMatch::Compare(Comparison::LessOrEqual, value("30"))),
+            ("age>-1", AttrMatch::Compare(Comparison::Greater, value("-1"))),
+            ("age>=+1", AttrMatch::Compare(Comparison::GreaterOrEqual, value("+1"))),
+            ("age!=30", AttrMatch::Compare(Comparison::NotEqual, value("30"))),
+            ("age!=\\N", AttrMatch::IsNotNull),
+            ("age between 20 AND 30", AttrMatch::Between(value("20"), value("30"))),
+        ];
+        for &(input, ref expected) in data.iter() {
+            let query = Query::parse_named(input, &schema);
+            assert_eq!(query.as_ref().map(|q| attr_match(q, 1)).as_ref(), Ok(expected), "{}", input);
+        }
+        let query = Query::parse_named("name BETWEEN \"a b\" and c\\ d", &schema).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::Between(value("a b"), value("c d")));
+        let query = Query::parse_named("name=a<b,city<\"x,y\"", &schema).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::Value(value("a<b")));
+        assert_eq!(attr_match(&query, 2), AttrMatch::Compare(Comparison::Less, value("x,y")));
+
+        let errors = [
+            ("age<?", ParseError::InvalidCondition("age<?".to_string())),
+            ("age>=\\N", ParseError::InvalidCondition("age>=\\N".to_string())),
+            ("age between 1 and ?", ParseError::InvalidCondition("age between 1 and ?".to_string())),
+            ("age between 1", ParseError::InvalidCondition("age between 1".to_string())),
+            ("age<old", ParseError::InvalidValue(
+                SchemaError::InvalidValue("age".to_string(), AttrType::Int, "old".to_string())
+            )),
+            ("age between 1 and x", ParseError::InvalidValue(
+                SchemaError::InvalidValue("age".to_string(), AttrType::Int, "x".to_string())
+            )),
+        ];
+        for &(input, ref error) in errors.iter() {
+            assert_eq!(Query::parse_named(input, &schema).as_ref(), Err(error));
+        }
+
+        let by_name = Query::parse_with_schema("age>30", &schema).unwrap();
+        assert_eq!(attr_match(&by_name, 1), AttrMatch::Compare(Comparison::Greater, value("30")));
+        let by_name = Query::parse_with_schema("age BETWEEN 1 AND 2", &schema).unwrap();
+        assert_eq!(attr_match(&by_name, 1), AttrMatch::Between(value("1"), value("2")));
+        let by_position = Query::parse_with_schema("a\\<b,?,?", &schema).unwrap();
+        assert_eq!(attr_match(&by_position, 0), AttrMatch::Value(value("a<b")));
+    }
+
+    #[test]
+    fn parse_patterns() {
+        let schema = Schema::parse("name,age:int").unwrap();
+        let query = Query::parse("al*,?", 2).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::Pattern(Pattern::prefix("al")));
+        let query = Query::parse("LIKE a%,/^[0-9]\\,/", 2).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::Pattern(Pattern::parse("like a%").unwrap().unwrap()));
+        assert_eq!(attr_match(&query, 1), AttrMatch::Pattern(Pattern::regex("^[0-9]\\,").unwrap()));
+        let query = Query::parse("a\\*,\"LIKE x\"", 2).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::Value(Cow::Borrowed("a*")));
+        assert_eq!(attr_match(&query, 1), AttrMatch::Value(Cow::Borrowed("LIKE x")));
+
+        let by_name = Query::parse_with_schema("name like %a b%", &schema).unwrap();
+        assert_eq!(by_name, Query::parse("LIKE %a b%,?", 2).unwrap());
+        let by_name = Query::parse_with_schema("name=/x/,age=4*", &schema).unwrap();
+        assert_eq!(by_name, Query::parse("/x/,4*", 2).unwrap());
+        let by_position = Query::parse_with_schema("LIKE %,?", &schema).unwrap();
+        assert_eq!(by_position, Query::parse_with_schema("name LIKE %", &schema).unwrap());
+
+        assert!(matches!(Query::parse("/(/", 1),
+                         Err(ParseError::InvalidPattern(PatternError::InvalidRegex(_)))));
+        let comparison = Query::parse_named("name>a*", &schema).unwrap();
+        assert_eq!(attr_match(&comparison, 0), AttrMatch::Compare(Comparison::Greater, Cow::Borrowed("a*")));
+    }
+
+    #[test]
+    fn parse_in_lists() {
+        let schema = Schema::parse("name,age:int").unwrap();
+        let value = |v: &'static str| Cow::Borrowed(v);
+        let query = Query::parse_named("name IN (a, \"b,c\" , d\\)), age in (1)", &schema).unwrap();
+        assert_eq!(attr_match(&query, 0), AttrMatch::In(vec![value("a"), value("b,c"), value("d)")]));
+        assert_eq!(attr_match(&query, 1), AttrMatch::In(vec![value("1")]));
+        let by_name = Query::parse_with_schema("age IN (1,2),name=x", &schema).unwrap();
+        assert_eq!(attr_match(&by_name, 0), AttrMatch::Value(value("x")));
+        assert_eq!(attr_match(&by_name, 1), AttrMatch::In(vec![value("1"), value("2")]));
+
+        let errors = [
+            ("age IN ()", ParseError::InvalidCondition("age IN ()".to_string())),
+            ("age IN (1, ?)", ParseError::InvalidCondition("age IN (1, ?)".to_string())),
+            ("age IN 1, 2", ParseError::InvalidCondition("age IN 1".to_string())),
+            ("age IN (1, x)", ParseError::InvalidValue(
+                SchemaError::InvalidValue("age".to_string(), AttrType::Int, "x".to_string())
+            )),
+        ];
+        for &(input, ref error) in errors.iter() {
+            assert_eq!(Query::parse_named(input, &schema).as_ref(), Err(error));
+        }
+    }
+
+    #[test]
+    fn parse_boolean_expressions() {
+        let schema = Schema::parse("a,b,c:int").unwrap();
+        let attr = |i: usize, v: &'static str| Expr::Attr(i, AttrMatch::Value(Cow::Borrowed(v)));
+        let query = Query::parse_named("(a=x OR a=y) AND b!=z", &schema).unwrap();
+        assert_eq!(query.expr, Expr::And(vec![
+            Expr::Or(vec![attr(0, "x"), attr(0, "y")]),
+            Expr::Attr(1, AttrMatch::Compare(Comparison::NotEqual, Cow::Borrowed("z"))),
+        ]));
+        // NOT binds tightest and OR loosest, and a comma is the same as AND.
+        let query = Query::parse_named("not a=x or b=y, c between 1 and 2", &schema).unwrap();
+        assert_eq!(query.expr, Expr::Or(vec![
+            Expr::Not(Box::new(attr(0, "x"))),
+            Expr::And(vec![attr(1, "y"), Expr::Attr(2, AttrMatch::Between(Cow::Borrowed("1"), Cow::Borrowed("2")))]),
+        ]));
+        assert_eq!(Query::parse_named("a=1,a=2", &schema).unwrap().expr, Expr::And(vec![attr(0, "1"), attr(0, "2")]));
+        let query = Query::parse_named("NOT (a IS NOT NULL AND b IN (x, \"y)\")) OR a=/^(x|y)$/", &schema).unwrap();
+        assert_eq!(query, Query::parse_with_schema("(NOT(a is not null and b in (x,\"y)\")))or(a=/^(x|y)$/)", &schema).unwrap());
+        assert_eq!(Query::parse_named("a=\"x OR y\"", &schema).unwrap().expr, attr(0, "x OR y"));
+
+        let errors = [
+            ("a=x OR", ParseError::InvalidCondition("".to_string())),
+            ("a=x AND (b=y,)", ParseError::InvalidCondition(")".to_string())),
+            ("(a=x OR b=y", ParseError::UnmatchedParenthesis("(a=x OR b=y".to_string())),
+            ("a=x) AND b=y", ParseError::UnmatchedParenthesis(") AND b=y".to_string())),
+            ("a=f(x)", ParseError::InvalidCondition("(x)".to_string())),
+            ("NOT c=x", ParseError::InvalidValue(
+                SchemaError::InvalidValue("c".to_string(), AttrType::Int, "x".to_string())
+            )),
+        ];
+        for &(input, ref error) in errors.iter() {
+            assert_eq!(Query::parse_named(input, &schema).as_ref(), Err(error), "{}", input);
+        }
+
+        // Positional queries stay positional, even with a leading `not`.
+        let by_position = Query::parse_with_schema("not,?,1", &schema).unwrap();
+        assert_eq!(by_position.expr, Expr::And(vec![attr(0, "not"), attr(2, "1")]));
+        assert!(Query::parse_with_schema("NOT a=x", &schema).is_ok());
+    }
+
+    #[test]
+    fn projection() {
+        let schema = Schema::parse("name,age:int,city").unwrap();
+        assert_eq!(Query::parse_columns("city, 0,age", &schema), Ok(vec![2, 0, 1]));
+        assert_eq!(Query::parse_columns("3", &schema), Err(ParseError::UnknownAttribute("3".to_string())));
+        assert_eq!(Query::parse_columns("name,", &schema), Err(ParseError::UnknownAttribute("".to_string())));
+
+        let query = Query::parse_named("age>30 OR city=x", &schema).unwrap();
+        assert_eq!(query.needed_attrs(3), None);
+        let query = query.project(vec![0, 0]);
+        assert_eq!(query.needed_attrs(3), Some(vec![true, true, true]));
+        let tuple = Tuple::parse("bob,40,\\N", 3).unwrap();
+        assert_eq!(query.project_tuple(tuple).values, vec![Some("bob".to_string()); 2]);
+        let query = Query::parse_named("age>30", &schema).unwrap().project(vec![2]);
+        assert_eq!(query.needed_attrs(3), Some(vec![false, true, true]));
+        assert_eq!(Query::wildcard(3).project(vec![1]).needed_attrs(3), Some(vec![false, true, false]));
+    }
+
     #[test]
     fn matching() {
         let data = [
@@ -88,7 +940,144 @@ mod tests {
             (Query::parse("?", 1), Tuple::parse("wowzas", 1), true)
         ];
         for &(ref query, ref tuple, exp) in data.iter() {
-            assert_eq!(query.as_ref().unwrap().matches_tuple(tuple.as_ref().unwrap()), exp);
+            let schema = Schema::untyped(tuple.as_ref().unwrap().values.len() as u32);
+            assert_eq!(query.as_ref().unwrap().matches_tuple(tuple.as_ref().unwrap(), &schema), exp);
+        }
+    }
+
+    #[test]
+    fn matching_typed_values() {
+        let schema = Schema::parse("id:int,score:float,name").unwrap();
+        let tuple = Tuple::parse("01,1.50,007", 3).unwrap();
+        assert!(Query::parse("1,1.5,007", 3).unwrap().matches_tuple(&tuple, &schema));
+        assert!(!Query::parse("?,?,7", 3).unwrap().matches_tuple(&tuple, &schema));
+    }
+
+    #[test]
+    fn matching_comparisons() {
+        let schema = Schema::parse("name,age:int,score:float").unwrap();
+        let tuple = Tuple::parse("bob,9,2.50", 3).unwrap();
+        let null_score = Tuple::parse("bob,9,\\N", 3).unwrap();
+        let data = [
+            ("age<10", true),
+            ("age<9", false),
+            ("age<=9", true),
+            ("age>+8", true),
+            ("age>=10", false),
+            ("age!=09", false),
+            ("age!=10", true),
+            ("age between 9 and 9", true),
+            ("age BETWEEN 10 AND 20", false),
+            ("score between 2.5 AND 1e1", true),
+            ("score>2.499", true),
+            ("name<carol", true),
+            ("name>=bobby", false),
+            ("name BETWEEN b bob", true),
+        ];
+        for &(input, exp) in data.iter() {
+            let input = input.replace("b bob", "b AND bob");
+            let query = Query::parse_with_schema(&input, &schema).unwrap();
+            assert_eq!(query.matches_tuple(&tuple, &schema), exp, "{}", input);
+        }
+        for input in &["score>0", "score<0", "score!=0", "score between -1 and 1e9"] {
+            let query = Query::parse_with_schema(input, &schema).unwrap();
+            assert!(!query.matches_tuple(&null_score, &schema), "{}", input);
+        }
+    }
+
+    #[test]
+    fn matching_patterns() {
+        let schema = Schema::parse("msg,code:int").unwrap();
+        let tuple = Tuple::parse("disk error on sda,0042", 2).unwrap();
+        let null_msg = Tuple::parse("\\N,1", 2).unwrap();
+        let data = [
+            ("msg=disk*", true),
+            ("msg=error*", false),
+            ("msg LIKE %error%", true),
+            ("msg LIKE %ERROR%", false),
+            ("msg LIKE disk_error%", true),
+            ("msg=/err(or|no)/", true),
+            ("msg=/^error/", false),
+            ("code=4*", true),
+            ("code=0*", false),
+            ("code LIKE __", true),
+        ];
+        for &(input, exp) in data.iter() {
+            let query = Query::parse_with_schema(input, &schema).unwrap();
+            assert_eq!(query.matches_tuple(&tuple, &schema), exp, "{}", input);
+            assert!(!query.matches_tuple(&null_msg, &schema), "{}", input);
+        }
+    }
+
+    #[test]
+    fn matching_in_lists() {
+        let schema = Schema::parse("name,age:int").unwrap();
+        let query = Query::parse_named("name IN (alice, bob),age IN (30, 040)", &schema).unwrap();
+        let data = [
+            ("alice,30", true),
+            ("bob,40", true),
+            ("carol,30", false),
+            ("bob,35", false),
+            ("\\N,30", false),
+        ];
+        for &(tuple, exp) in data.iter() {
+            assert_eq!(query.matches_tuple(&Tuple::parse(tuple, 2).unwrap(), &schema), exp, "{}", tuple);
+        }
+    }
+
+    #[test]
+    fn matching_boolean_expressions() {
+        let schema = Schema::parse("a,b,n:int").unwrap();
+        let query = Query::parse_named("(a=x OR a=y) AND b!=z", &schema).unwrap();
+        let data = [
+            ("x,w,1", true),
+            ("y,w,1", true),
+            ("x,z,1", false),
+            ("w,w,1", false),
+            ("x,\\N,1", false),
+        ];
+        for &(tuple, exp) in data.iter() {
+            assert_eq!(query.matches_tuple(&Tuple::parse(tuple, 3).unwrap(), &schema), exp, "{}", tuple);
+        }
+
+        let tuple = Tuple::parse("x,\\N,07", 3).unwrap();
+        let data = [
+            ("NOT a=x", false),
+            ("NOT b=z", false),
+            ("NOT b!=z", false),
+            ("NOT (b=z AND a=w)", true),
+            ("NOT (b=z OR a=w)", false),
+            ("NOT (b=z OR a=x)", false),
+            ("NOT b IS NULL", false),
+            ("NOT NOT n=7", true),
+            ("a=y OR n>5", true),
+            ("a=y OR NOT n>5", false),
+            ("n=7, NOT (a=y OR b IS NOT NULL)", true),
+            ("a=1,a=2", false),
+        ];
+        for &(input, exp) in data.iter() {
+            let query = Query::parse_named(input, &schema).unwrap();
+            assert_eq!(query.matches_tuple(&tuple, &schema), exp, "{}", input);
+        }
+    }
+
+    #[test]
+    fn matching_nulls() {
+        let schema = Schema::parse("name,age:int").unwrap();
+        let null_age = Tuple::parse("bob,\\N", 2).unwrap();
+        let empty_name = Tuple::parse("\"\",30", 2).unwrap();
+        let data = [
+            ("?,?", &null_age, true),
+            ("?,\\N", &null_age, true),
+            ("age is not null", &null_age, false),
+            ("?,0", &null_age, false),
+            ("name=\\N", &empty_name, false),
+            ("name=\"\"", &empty_name, true),
+            ("name IS NOT NULL", &empty_name, true),
+        ];
+        for &(input, tuple, exp) in data.iter() {
+            let query = Query::parse_with_schema(input, &schema).unwrap();
+            assert_eq!(query.matches_tuple(tuple, &schema), exp, "{}", input);
         }
     }
 }
@@ -1,15 +1,32 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
 use std::path::Path;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
-use std::collections::LinkedList;
-use std::error::Error;
+use std::io::{self, Seek, SeekFrom};
+use std::collections::{LinkedList, HashMap, HashSet};
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use fs2::FileExt;
 
 use choice_vec::*;
-use page::{Page, PageQueryIter, get_next_page_id, PAGE_SIZE, NO_OVFLOW};
+use page::{Page, PageQueryIter, get_next_page_id, page_data_size, is_valid_page_size, NO_OVFLOW};
+use page::{DEFAULT_PAGE_SIZE, MIN_PAGE_SIZE, MAX_PAGE_SIZE};
+use page::{RecordKind, RecordId, encode_record, max_record_size, RECORD_HEADER_SIZE, PRIMARY_PAGE};
+use page_file::PageFile;
 use tuple::Tuple;
 use util::*;
 use query::Query;
-use partial_hash::{PartialHash, PageIdIter};
+use partial_hash::{PartialHash, PageIdUnion};
+use split_policy::SplitPolicy;
+use schema::Schema;
+use sort::{SortKey, Sorter, DEFAULT_MEMORY_BUDGET};
+use free_list::FreeList;
+use wal::{Wal, FileKind};
+use blob::{BlobRef, read_record};
+use buffer_pool::{BufferPool, PoolStats, DEFAULT_POOL_PAGES};
+use upgrade;
 
 pub use self::OpenMode::*;
 
@@ -22,25 +39,86 @@ pub enum OpenMode {
 pub struct Relation {
     pub name: String,
     pub num_attrs: u32,
+    /// Names and types of the attributes.
+    pub schema: Schema,
+    /// Size of the pages in the data and overflow files, in bytes.
+    pub page_size: u32,
     pub depth: u8,
     pub split_pointer: u32,
     /// Number of main data pages (overflow pages not counted).
     pub num_pages: u64,
     pub num_tuples: u64,
+    /// Total size of the records stored in the relation's buckets, in bytes.
+    /// Tuples stored out of line only count the size of their `BlobRef`s.
+    pub num_bytes: u64,
     pub choice_vec: ChoiceVec,
+    pub split_policy: SplitPolicy,
+    /// Unused pages in the overflow file.
+    pub free_list: FreeList,
+    /// Unused pages in the blob file.
+    pub blob_free_list: FreeList,
+    /// Number of times records have been moved between pages, by splits, merges and
+    /// compaction, which invalidates their `RecordId`s.
+    pub epoch: u32,
     pub mode: OpenMode,
     pub info_file: File,
-    pub data_file: File,
-    pub ovflow_file: File
+    pub data_file: PageFile,
+    pub ovflow_file: PageFile,
+    /// Storage for tuples too large to fit in a page.
+    pub blob_file: PageFile,
+    /// Format version of the info file, which is the current one except during upgrades.
+    version: u32,
+    /// Log of changes made by the current transaction, shared with the page files.
+    wal: Rc<RefCell<Wal>>,
+    /// Cache of pages from both page files.
+    pool: Rc<RefCell<BufferPool>>
 }
 
 fn file_name(name: &str, extension: &str) -> String {
     format!("{}.{}", name, extension)
 }
 
-fn info_file_name(name: &str) -> String { file_name(name, "info") }
-fn data_file_name(name: &str) -> String { file_name(name, "data") }
-fn ovflow_file_name(name: &str) -> String { file_name(name, "ovflow") }
+pub fn info_file_name(name: &str) -> String { file_name(name, "info") }
+pub fn data_file_name(name: &str) -> String { file_name(name, "data") }
+pub fn ovflow_file_name(name: &str) -> String { file_name(name, "ovflow") }
+pub fn blob_file_name(name: &str) -> String { file_name(name, "blob") }
+pub fn wal_file_name(name: &str) -> String { file_name(name, "wal") }
+/// Marker left while an upgrade swaps a rebuilt relation's files into place.
+pub fn swap_file_name(name: &str) -> String { file_name(name, "swap") }
+
+/// Check whether a relation's log holds an incomplete transaction.
+fn needs_recovery(name: &str) -> bool {
+    fs::metadata(wal_file_name(name)).map(|m| m.len() > 0).unwrap_or(false)
+}
+
+/// Take the lock held by a relation's single writer, which is released when the info file
+/// is closed.
+fn lock_for_writing(info_file: &File, name: &str) -> io::Result<()> {
+    info_file.try_lock_exclusive().map_err(|e| {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            io::Error::new(e.kind(), format!("relation {} is already open for writing", name))
+        } else {
+            e
+        }
+    })
+}
+
+/// Error for a record ID from an earlier epoch of its relation, whose record may have moved.
+#[derive(Debug)]
+pub struct StaleRecordId {
+    pub rid: RecordId,
+    /// The relation's current epoch.
+    pub epoch: u32,
+}
+
+impl fmt::Display for StaleRecordId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stale record ID: from epoch {} of the relation, which is now at epoch {}",
+               self.rid.epoch, self.epoch)
+    }
+}
+
+impl Error for StaleRecordId {}
 
 impl OpenMode {
     fn open_options(self) -> OpenOptions {
@@ -50,48 +128,96 @@ impl OpenMode {
     }
 }
 
-// Automatically write relation metadata to disk when a relation goes out of scope.
+// Automatically roll back any open transaction when a relation goes out of scope, as only
+// an explicit commit makes changes durable, and write back any cached changes.
 impl Drop for Relation {
     fn drop(&mut self) {
         trace!("Running relation destructor.");
-        if self.mode == Writing {
-            if let Err(e) = self.write_info_file() {
-                error!("Error: unable to write relation metadata, relation info may be corrupt.");
-                error!("Reason: {}, {}", e, e.description());
+        if self.in_transaction() {
+            warn!("Rolling back uncommitted transaction on relation {}", self.name);
+            if let Err(e) = self.rollback() {
+                error!("Error: unable to roll back transaction, it will be rolled back when the \
+                        relation is next opened for writing.");
+                error!("Reason: {}", e);
             }
+        } else if let Err(e) = self.flush() {
+            error!("Error: unable to write back cached pages: {}", e);
         }
     }
 }
 
 impl Relation {
     /// Create a new relation on disk.
-    pub fn new(name: &str, num_attrs: u32, est_num_pages: u64, choice_vec: ChoiceVec)
-    -> Result<(), BoxError>
+    pub fn new(
+        name: &str,
+        schema: Schema,
+        est_num_pages: u64,
+        choice_vec: ChoiceVec,
+        split_policy: SplitPolicy,
+        page_size: u32
+    ) -> Result<(), BoxError>
     {
         if Relation::exists(name) {
             try!(Err(format!("relation already exists: {}", name)))
         }
 
+        let num_attrs = schema.num_attrs();
         if num_attrs == 0 {
             try!(Err("number of attributes can't be zero"));
         }
 
+        if !is_valid_page_size(page_size) {
+            try!(Err(format!("page size must be a power of two from {} to {} bytes",
+                             MIN_PAGE_SIZE, MAX_PAGE_SIZE)));
+        }
+
         // Compute the depth and number of pages to use based on the user's estimate.
         let (depth, num_pages) = get_depth_and_num_pages(est_num_pages);
 
         // Create new relation struct and associated files.
-        let r = Relation {
+        let wal = Rc::new(RefCell::new(Wal::new(Some(try!(File::create(wal_file_name(name)))))));
+        let pool = Rc::new(RefCell::new(BufferPool::new(DEFAULT_POOL_PAGES, page_size)));
+        let mut r = Relation {
             name: name.to_string(),
             num_attrs: num_attrs,
+            schema: schema,
+            page_size: page_size,
             depth: depth,
             split_pointer: 0,
             num_pages: num_pages,
             num_tuples: 0,
+            num_bytes: 0,
             choice_vec: choice_vec,
+            split_policy: split_policy,
+            free_list: FreeList::new(),
+            blob_free_list: FreeList::new(),
+            epoch: 0,
             mode: Writing,
             info_file: try!(File::create(info_file_name(name))),
-            data_file: try!(File::create(data_file_name(name))),
-            ovflow_file: try!(File::create(ovflow_file_name(name)))
+            data_file: try!(PageFile::new(
+                data_file_name(name),
+                try!(File::create(data_file_name(name))),
+                FileKind::Data,
+                wal.clone(),
+                pool.clone()
+            )),
+            ovflow_file: try!(PageFile::new(
+                ovflow_file_name(name),
+                try!(File::create(ovflow_file_name(name))),
+                FileKind::Ovflow,
+                wal.clone(),
+                pool.clone()
+            )),
+            blob_file: try!(PageFile::new(
+                blob_file_name(name),
+                try!(File::create(blob_file_name(name))),
+                FileKind::Blob,
+                wal.clone(),
+                pool.clone()
+            )),
+            version: upgrade::FORMAT_VERSION,
+            wal: wal,
+            pool: pool
         };
 
         // Write initial empty pages.
@@ -101,104 +227,680 @@ impl Relation {
         }
 
         // Write metadata.
-        drop(r);
+        try!(r.flush());
+        try!(r.write_info_file());
         Ok(())
     }
 
     /// Open an existing relation for reading or writing.
+    ///
+    /// If a transaction was left incomplete by a crash, it is rolled back first.
     pub fn open(name: &str, mode: OpenMode) -> io::Result<Relation> {
+        Relation::open_version(name, mode, upgrade::FORMAT_VERSION)
+    }
+
+    /// Open a relation whose info file has an older format version, for upgrade steps that
+    /// only change how tuples are placed. The next commit writes the current version.
+    pub fn open_version(name: &str, mode: OpenMode, version: u32) -> io::Result<Relation> {
+        try!(upgrade::check_swap_finished(name));
         let open_opts = mode.open_options();
         let info_file = try!(open_opts.open(info_file_name(name)));
-        let num_attrs = try!(read_u32(&info_file));
-        let depth = try!(read_u8(&info_file));
-        let split_pointer = try!(read_u32(&info_file));
-        let num_pages = try!(read_u64(&info_file));
-        let num_tuples = try!(read_u64(&info_file));
-        let choice_vec = try!(ChoiceVec::read(&info_file));
-
-        Ok(Relation {
+        // Check the format version before creating a log, or looking for files older versions
+        // don't have. Reading the info file again checks it once any rollback is done.
+        try!(upgrade::check_version(&info_file, &info_file_name(name), version));
+        // Only a writer may roll back an incomplete transaction, as it may belong to a
+        // writer that is still running. Readers see whatever the files hold.
+        let wal_file = if mode == Writing {
+            try!(lock_for_writing(&info_file, name));
+            try!(Relation::recover_locked(name, &info_file));
+            let mut wal_opts = open_opts.clone();
+            Some(try!(wal_opts.create(true).open(wal_file_name(name))))
+        } else {
+            if needs_recovery(name) {
+                warn!("Relation {} has an incomplete transaction, which readers don't roll back", name);
+            }
+            None
+        };
+        let wal = Rc::new(RefCell::new(Wal::new(wal_file)));
+
+        // The real page size is only known once the info file has been read.
+        let pool = Rc::new(RefCell::new(BufferPool::new(DEFAULT_POOL_PAGES, DEFAULT_PAGE_SIZE)));
+
+        let mut r = Relation {
             name: name.to_string(),
-            num_attrs: num_attrs,
-            depth: depth,
-            split_pointer: split_pointer,
-            num_pages: num_pages,
-            num_tuples: num_tuples,
-            choice_vec: choice_vec,
+            num_attrs: 0,
+            schema: Schema::untyped(0),
+            page_size: DEFAULT_PAGE_SIZE,
+            depth: 0,
+            split_pointer: 0,
+            num_pages: 0,
+            num_tuples: 0,
+            num_bytes: 0,
+            choice_vec: ChoiceVec { data: [(0, 0); HASH_SIZE] },
+            split_policy: SplitPolicy::default(),
+            free_list: FreeList::new(),
+            blob_free_list: FreeList::new(),
+            epoch: 0,
             mode: mode,
             info_file: info_file,
-            data_file: try!(open_opts.open(data_file_name(name))),
-            ovflow_file: try!(open_opts.open(ovflow_file_name(name)))
-        })
+            data_file: try!(PageFile::new(
+                data_file_name(name),
+                try!(open_opts.open(data_file_name(name))),
+                FileKind::Data,
+                wal.clone(),
+                pool.clone()
+            )),
+            ovflow_file: try!(PageFile::new(
+                ovflow_file_name(name),
+                try!(open_opts.open(ovflow_file_name(name))),
+                FileKind::Ovflow,
+                wal.clone(),
+                pool.clone()
+            )),
+            blob_file: try!(PageFile::new(
+                blob_file_name(name),
+                try!(open_opts.open(blob_file_name(name))),
+                FileKind::Blob,
+                wal.clone(),
+                pool.clone()
+            )),
+            version: version,
+            wal: wal,
+            pool: pool
+        };
+        try!(r.read_info_file());
+        Ok(r)
+    }
+
+    /// Roll back any incomplete transaction recorded in a relation's log, failing if the
+    /// relation is open for writing. Return true if there was a transaction to roll back.
+    pub fn recover(name: &str) -> io::Result<bool> {
+        let info_file = try!(Writing.open_options().open(info_file_name(name)));
+        try!(lock_for_writing(&info_file, name));
+        Relation::recover_locked(name, &info_file)
+    }
+
+    /// Roll back any incomplete transaction, holding the lock on the relation's info file.
+    fn recover_locked(name: &str, info_file: &File) -> io::Result<bool> {
+        if !needs_recovery(name) {
+            return Ok(false);
+        }
+
+        warn!("Recovering relation {} from its write-ahead log", name);
+        let open_opts = Writing.open_options();
+        let mut wal = Wal::new(Some(try!(open_opts.open(wal_file_name(name)))));
+        let data_file = try!(open_opts.open(data_file_name(name)));
+        let ovflow_file = try!(open_opts.open(ovflow_file_name(name)));
+        // Relations from older format versions don't have a blob file.
+        let blob_file = open_opts.open(blob_file_name(name)).ok();
+        wal.rollback(info_file, [Some(&data_file), Some(&ovflow_file), blob_file.as_ref()])
+    }
+
+    /// Upgrade a relation's files to the current format version, returning the old version.
+    pub fn upgrade(name: &str) -> io::Result<u32> {
+        try!(Relation::recover(name));
+        upgrade::upgrade(name)
+    }
+
+    /// Begin a transaction.
+    ///
+    /// Changes made before the matching `commit` are applied atomically: if the process dies
+    /// part way through, they are all rolled back the next time the relation is opened for
+    /// writing, and if the relation is dropped first, they are rolled back then.
+    /// Operations performed outside of an explicit transaction get one of their own.
+    pub fn begin(&mut self) -> io::Result<()> {
+        // The log relies on the files holding the current contents of every page.
+        try!(self.flush());
+        let lengths = [
+            try!(self.data_file.num_pages()),
+            try!(self.ovflow_file.num_pages()),
+            try!(self.blob_file.num_pages())
+        ];
+        self.wal.borrow_mut().begin(&self.info_file, lengths, self.page_size)
+    }
+
+    /// Commit the current transaction, writing out the relation's metadata.
+    pub fn commit(&mut self) -> io::Result<()> {
+        try!(self.flush());
+        try!(self.write_info_file());
+        let files = [
+            &self.info_file, self.data_file.file(), self.ovflow_file.file(), self.blob_file.file()
+        ];
+        self.wal.borrow_mut().commit(&files)
+    }
+
+    /// Undo all the changes made by the current transaction.
+    pub fn rollback(&mut self) -> io::Result<()> {
+        try!(self.wal.borrow_mut().rollback(
+            &self.info_file,
+            [Some(self.data_file.file()), Some(self.ovflow_file.file()), Some(self.blob_file.file())]
+        ));
+        try!(self.pool.borrow_mut().invalidate());
+        self.read_info_file()
+    }
+
+    /// Write any cached changes to pages back to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.pool.borrow_mut().flush()
+    }
+
+    /// Counters for the relation's buffer pool.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.borrow().stats()
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.wal.borrow().is_active()
+    }
+
+    /// Run `f` as a single transaction, unless there is already a transaction in progress.
+    /// If `f` fails in its own transaction, all of its changes are rolled back.
+    fn atomically<T, F>(&mut self, f: F) -> io::Result<T>
+    where F: FnOnce(&mut Relation) -> io::Result<T>
+    {
+        if self.in_transaction() {
+            return f(self);
+        }
+        try!(self.begin());
+        match f(self) {
+            Ok(result) => {
+                try!(self.commit());
+                Ok(result)
+            }
+            Err(e) => {
+                if let Err(rollback_err) = self.rollback() {
+                    error!("Error: unable to roll back failed transaction: {}", rollback_err);
+                }
+                Err(e)
+            }
+        }
     }
 
     pub fn exists(name: &str) -> bool {
         Path::new(&info_file_name(name)).is_file()
     }
 
+    /// Number of tuples at which a relation using the `TupleCount` policy grows: an estimate of
+    /// how many fit in each page, which is at least one however wide the tuples are.
     fn resize_threshold(&self) -> u64 {
-        (PAGE_SIZE as u64 / (10 * self.num_attrs as u64)) * self.num_pages
+        let per_page = cmp::max(1, self.page_size as u64 / (10 * self.num_attrs as u64));
+        per_page * self.num_pages
+    }
+
+    /// Check whether the relation should grow before the next insert.
+    /// Relations using `OnOverflow` grow from within `insert` instead.
+    fn should_grow(&self) -> bool {
+        // Never expand once the depth has reached the size of hashes.
+        if self.depth as usize == HASH_SIZE {
+            return false;
+        }
+        match self.split_policy {
+            SplitPolicy::TupleCount => self.num_tuples >= self.resize_threshold(),
+            SplitPolicy::LoadFactor(bytes) => self.num_bytes >= bytes as u64 * self.num_pages,
+            SplitPolicy::OnOverflow | SplitPolicy::Never => false,
+        }
+    }
+
+    /// Check whether the load has dropped under the low-water mark, a quarter of the load
+    /// at which the relation grows. The gap stops a relation from thrashing between sizes.
+    fn should_shrink(&self) -> bool {
+        if self.num_pages == 1 {
+            return false;
+        }
+        match self.split_policy {
+            SplitPolicy::TupleCount => self.num_tuples < self.resize_threshold() / 4,
+            SplitPolicy::LoadFactor(bytes) => self.num_bytes < bytes as u64 * self.num_pages / 4,
+            SplitPolicy::OnOverflow => {
+                self.num_bytes < page_data_size(self.page_size) as u64 * self.num_pages / 4
+            }
+            SplitPolicy::Never => false,
+        }
+    }
+
+    /// IDs of the primary pages of the buckets that could hold tuples matching a query,
+    /// each given once.
+    pub fn matching_page_ids(&self, query: &Query) -> PageIdUnion {
+        let partial_hashes = PartialHash::for_query(query, &self.choice_vec, &self.schema);
+        // FIXME: Use num_pages as a u64 once partial_hash is revamped.
+        PageIdUnion::new(&partial_hashes, self.num_pages as u32)
     }
 
     /// Select tuples matching a query.
     pub fn select<'a>(&'a self, query: &'a Query<'a>) -> SelectIter<'a> {
         debug!("Relation::select, query is: {:?}", query);
-        let partial_hash = PartialHash::from_query(query, &self.choice_vec);
         SelectIter {
             query: query,
-            // FIXME: Use num_pages as a u64 once partial_hash is revamped.
-            page_id_iter: partial_hash.matching_page_ids(self.num_pages as u32),
+            schema: &self.schema,
+            epoch: self.epoch,
+            page_id_iter: self.matching_page_ids(query),
             bucket_iter: None,
             ovflow_file: &self.ovflow_file,
             data_file: &self.data_file,
+            blob_file: &self.blob_file,
+        }
+    }
+
+    /// Select tuples matching a query, ordered by `options.order_by` and with `options.offset`
+    /// tuples skipped and at most `options.limit` yielded. Without an order, the select stops
+    /// once the limit is reached. With one, every matching tuple is sorted first, spilling to
+    /// temporary files past `options.memory_budget`.
+    pub fn select_with<'a>(&'a self, query: &'a Query<'a>, options: &SelectOptions)
+                           -> io::Result<Box<Iterator<Item = io::Result<Tuple>> + 'a>> {
+        let (offset, limit) = (options.offset, options.limit.unwrap_or(usize::MAX));
+        if options.order_by.is_empty() {
+            return Ok(Box::new(self.select(query).skip(offset).take(limit)));
+        }
+
+        // Sort a projection by attributes it drops by projecting onto them too, after the
+        // query's columns, and truncating each tuple back to those columns once sorted.
+        let (sort_query, schema, keys, num_columns) = match query.columns {
+            Some(ref columns) => {
+                let mut all_columns = columns.clone();
+                let keys = options.order_by.iter().map(|key| {
+                    all_columns.push(key.attr);
+                    SortKey { attr: all_columns.len() - 1, descending: key.descending }
+                }).collect();
+                let schema = self.schema.project(&all_columns);
+                (query.clone().project(all_columns), schema, keys, Some(columns.len()))
+            }
+            None => (query.clone(), self.schema.clone(), options.order_by.clone(), None)
+        };
+        let mut sorter = Sorter::new(schema, keys, options.memory_budget);
+        for tuple in self.select(&sort_query) {
+            try!(sorter.add(try!(tuple)));
+        }
+        let sorted = try!(sorter.finish()).skip(offset).take(limit);
+        Ok(Box::new(sorted.map(move |item| item.map(|mut tuple| {
+            if let Some(num_columns) = num_columns {
+                tuple.values.truncate(num_columns);
+            }
+            tuple
+        }))))
+    }
+
+    /// Count the tuples matching a query, visiting only the buckets that could hold them,
+    /// and without building tuples from their records. Every tuple matches a wildcard,
+    /// which is counted without reading any pages.
+    pub fn count(&self, query: &Query) -> io::Result<u64> {
+        if query.is_wildcard() {
+            return Ok(self.num_tuples);
         }
+        let mut count = 0;
+        for page_id in self.matching_page_ids(query) {
+            let mut page = try!(Page::read(&self.data_file, page_id));
+            loop {
+                count += try!(page.count_matching(query, &self.schema, &self.blob_file));
+                if page.ovflow == NO_OVFLOW {
+                    break;
+                }
+                page = try!(Page::read(&self.ovflow_file, page.ovflow));
+            }
+        }
+        Ok(count)
     }
 
-    /// Insert a tuple into the relation.
+    /// Insert a tuple into the relation, failing if it doesn't fit the schema.
     pub fn insert(&mut self, t: Tuple) -> io::Result<()> {
-        // Expand whenever the resize threshold is hit, so long as the depth is still
-        // less than the size of hashes.
-        if self.num_tuples == self.resize_threshold() && self.depth as usize != HASH_SIZE {
+        try!(self.check_tuple(&t));
+        self.atomically(|r| r.insert_inner(t))
+    }
+
+    /// Check that a tuple fits the relation's schema.
+    fn check_tuple(&self, t: &Tuple) -> io::Result<()> {
+        self.schema.check(t).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn insert_inner(&mut self, t: Tuple) -> io::Result<()> {
+        if self.should_grow() {
             info!("Resizing the relation.");
-            try!(self.grow());
-            debug_assert!(self.is_sane() == ());
+            try!(self.grow_checked());
+        }
+
+        let hash = t.hash(&self.choice_vec, &self.schema);
+        let record = try!(self.make_record(&t, hash));
+        let added_ovflow_page = try!(self.store_record(&record, hash));
+        self.num_tuples += 1;
+        self.num_bytes += record.len() as u64;
+
+        // With uncontrolled splitting, every new overflow page triggers a split.
+        if added_ovflow_page && self.split_policy == SplitPolicy::OnOverflow &&
+           self.depth as usize != HASH_SIZE {
+            info!("Resizing the relation after adding an overflow page.");
+            try!(self.grow_checked());
         }
 
-        let tuple_hash = t.hash(&self.choice_vec);
+        Ok(())
+    }
+
+    /// Grow the relation, checking its integrity afterwards in debug builds.
+    fn grow_checked(&mut self) -> io::Result<()> {
+        try!(self.grow());
+        if cfg!(debug_assertions) {
+            self.is_sane();
+        }
+        Ok(())
+    }
 
-        let mut page_id = lower_bits(self.depth, tuple_hash);
+    /// Compute the ID of the bucket (main data page) for a tuple with the given hash.
+    fn bucket_for(&self, tuple_hash: u32) -> u32 {
+        let page_id = lower_bits(self.depth, tuple_hash);
 
         // If the d-bit hash is less than the split-pointer, then we have to use
         // d + 1 bits of hash.
         if page_id < self.split_pointer {
-            page_id = lower_bits(self.depth + 1, tuple_hash);
+            lower_bits(self.depth + 1, tuple_hash)
+        } else {
+            page_id
+        }
+    }
+
+    /// Compute the hash of the tuple a record holds, without reading it from the blob file.
+    fn record_hash(&self, record: &[u8]) -> io::Result<u32> {
+        match BlobRef::parse_bytes(record) {
+            Some(blob_ref) => Ok(blob_ref.hash),
+            None => Ok(try!(read_record(record, &self.blob_file)).hash(&self.choice_vec, &self.schema))
         }
+    }
 
-        let mut page = try!(Page::read(&self.data_file, page_id));
+    /// Serialise a tuple into a record to store in its bucket.
+    /// Tuples too large to fit in a page are written to the blob file, and referred to instead.
+    fn make_record(&mut self, t: &Tuple, hash: u32) -> io::Result<Vec<u8>> {
+        Relation::make_record_with(t, hash, &self.blob_file, &mut self.blob_free_list)
+    }
 
+    fn make_record_with(t: &Tuple, hash: u32, blob_file: &PageFile, blob_free_list: &mut FreeList)
+    -> io::Result<Vec<u8>>
+    {
         let serialised_tuple = t.serialise();
+        if RECORD_HEADER_SIZE + serialised_tuple.len() <= max_record_size(blob_file.page_size()) {
+            return Ok(encode_record(RecordKind::Tuple, &serialised_tuple));
+        }
+        let blob_ref = try!(BlobRef::store(blob_file, blob_free_list, &serialised_tuple, hash));
+        Ok(blob_ref.serialise())
+    }
+
+    /// Free the blob referred to by a record, if there is one.
+    fn free_record(blob_file: &PageFile, blob_free_list: &mut FreeList, record: &[u8])
+    -> io::Result<()>
+    {
+        match BlobRef::parse_bytes(record) {
+            Some(blob_ref) => blob_ref.free(blob_file, blob_free_list),
+            None => Ok(())
+        }
+    }
+
+    /// Free the blobs referred to by records that have been removed, returning the number
+    /// of records.
+    fn free_records(blob_file: &PageFile, blob_free_list: &mut FreeList, records: LinkedList<Vec<u8>>)
+    -> io::Result<u64>
+    {
+        let num_records = records.len() as u64;
+        for record in records {
+            try!(Relation::free_record(blob_file, blob_free_list, &record));
+        }
+        Ok(num_records)
+    }
+
+    /// Write a record into its bucket, without touching the tuple count or resizing.
+    /// Return true if a new overflow page had to be added to the bucket.
+    fn store_record(&mut self, record: &[u8], hash: u32) -> io::Result<bool> {
+        let mut page = try!(Page::read(&self.data_file, self.bucket_for(hash)));
 
-        // If the tuple fits in the main data page, add it and write out.
-        if page.add_tuple(&serialised_tuple) {
+        // If the record fits in the main data page, add it and write out.
+        if page.add_tuple(record) {
             try!(page.write());
+            Ok(false)
         }
         // Otherwise, add it to the overflow chain.
         else {
-            try!(page.add_to_overflow(&self.ovflow_file, &serialised_tuple));
+            page.add_to_overflow(&self.ovflow_file, &mut self.free_list, record)
         }
+    }
 
-        self.num_tuples += 1;
+    /// Apply `f` to every tuple matching a query, returning the number of tuples updated.
+    ///
+    /// Updated tuples that hash to a different bucket (or no longer fit in their page)
+    /// are moved once every candidate bucket has been rewritten.
+    pub fn update<F>(&mut self, query: &Query, f: F) -> io::Result<u64>
+    where F: FnMut(&mut Tuple)
+    {
+        self.atomically(|r| r.update_inner(query, f))
+    }
+
+    fn update_inner<F>(&mut self, query: &Query, mut f: F) -> io::Result<u64>
+    where F: FnMut(&mut Tuple)
+    {
+        debug!("Relation::update, query is: {:?}", query);
+        let mut displaced = LinkedList::new();
+        let mut num_bytes = self.num_bytes;
+        let mut blob_free_list = self.blob_free_list;
+        let result = self.update_in_place(
+            query, &mut f, &mut displaced, &mut num_bytes, &mut blob_free_list
+        );
+        self.num_bytes = num_bytes;
+        self.blob_free_list = blob_free_list;
+
+        // Store the displaced tuples even if the update failed part way, so none are lost.
+        // Their old record IDs now refer to tombstones, and their new ones to other slots.
+        debug!("Moving {} updated tuples", displaced.len());
+        if !displaced.is_empty() {
+            self.epoch += 1;
+        }
+        for (record, hash) in displaced {
+            try!(self.store_record(&record, hash));
+        }
+        result
+    }
+
+    /// Helper function for update.
+    /// Update matching tuples in their slots, pushing records (and their hashes) that need to
+    /// move onto `displaced`, and keeping `num_bytes` up to date as tuples change size.
+    fn update_in_place<F>(
+        &self,
+        query: &Query,
+        f: &mut F,
+        displaced: &mut LinkedList<(Vec<u8>, u32)>,
+        num_bytes: &mut u64,
+        blob_free_list: &mut FreeList
+    ) -> io::Result<u64>
+    where F: FnMut(&mut Tuple)
+    {
+        let mut num_updated = 0;
+
+        for bucket in self.matching_page_ids(query) {
+            let mut page = try!(Page::read(&self.data_file, bucket));
+            loop {
+                for (slot, record) in page.get_slotted_records() {
+                    let mut tuple = try!(read_record(&record, &self.blob_file));
+                    if !query.matches_tuple(&tuple, &self.schema) {
+                        continue;
+                    }
+                    f(&mut tuple);
+                    if let Err(e) = self.schema.check(&tuple) {
+                        let msg = format!("invalid updated tuple: {}: {}", tuple.to_string(), e);
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+                    }
+                    try!(Relation::free_record(&self.blob_file, blob_free_list, &record));
+                    let hash = tuple.hash(&self.choice_vec, &self.schema);
+                    let new_record = try!(Relation::make_record_with(
+                        &tuple, hash, &self.blob_file, blob_free_list
+                    ));
+                    *num_bytes = *num_bytes - record.len() as u64 + new_record.len() as u64;
+                    num_updated += 1;
+
+                    // Keep the tuple in its slot if it still belongs to this bucket and fits.
+                    if self.bucket_for(hash) != bucket || !page.replace_record(slot, &new_record) {
+                        page.remove_record(slot);
+                        displaced.push_back((new_record, hash));
+                    }
+                }
 
+                let next_page_id = page.ovflow;
+                try!(page.close());
+                if next_page_id == NO_OVFLOW {
+                    break;
+                }
+                page = try!(Page::read(&self.ovflow_file, next_page_id));
+            }
+        }
+        Ok(num_updated)
+    }
+
+    /// Delete all tuples matching a query, returning the number of tuples removed.
+    pub fn delete_matching(&mut self, query: &Query) -> io::Result<u64> {
+        self.atomically(|r| r.delete_matching_inner(query))
+    }
+
+    fn delete_matching_inner(&mut self, query: &Query) -> io::Result<u64> {
+        debug!("Relation::delete_matching, query is: {:?}", query);
+        let mut num_deleted = 0;
+
+        for page_id in self.matching_page_ids(query) {
+            // Walk the bucket, removing tuples from the data page and each overflow page.
+            let mut prev_page = try!(Page::read(&self.data_file, page_id));
+            let removed = try!(prev_page.remove_matching(query, &self.schema, &self.blob_file));
+            self.num_bytes -= removed.iter().map(|record| record.len() as u64).sum::<u64>();
+            num_deleted += try!(Relation::free_records(
+                &self.blob_file, &mut self.blob_free_list, removed
+            ));
+
+            while prev_page.ovflow != NO_OVFLOW {
+                let mut page = try!(Page::read(&self.ovflow_file, prev_page.ovflow));
+                let removed = try!(page.remove_matching(query, &self.schema, &self.blob_file));
+                self.num_bytes -= removed.iter().map(|record| record.len() as u64).sum::<u64>();
+                num_deleted += try!(Relation::free_records(
+                    &self.blob_file, &mut self.blob_free_list, removed
+                ));
+
+                // Unlink overflow pages that have been emptied, and free them. They may be
+                // reused without their tombstones, so IDs referring to them become stale.
+                if page.num_tuples == 0 {
+                    prev_page.ovflow = page.ovflow;
+                    prev_page.mark_dirty();
+                    try!(self.free_list.free(&self.ovflow_file, page.id));
+                    self.epoch += 1;
+                } else {
+                    try!(prev_page.close());
+                    prev_page = page;
+                }
+            }
+            try!(prev_page.close());
+        }
+
+        self.num_tuples -= num_deleted;
+        try!(self.shrink_while_underloaded());
+        Ok(num_deleted)
+    }
+
+    /// Contract while the load is under the low-water mark.
+    fn shrink_while_underloaded(&mut self) -> io::Result<()> {
+        while self.should_shrink() {
+            info!("Shrinking the relation.");
+            try!(self.shrink());
+            if cfg!(debug_assertions) {
+                self.is_sane();
+            }
+        }
         Ok(())
     }
 
+    /// Fetch the tuple with a given record ID, or None if the ID doesn't refer to a record.
+    /// An ID from an earlier epoch is an error, as its record may have moved.
+    pub fn fetch(&self, rid: RecordId) -> io::Result<Option<Tuple>> {
+        try!(self.check_epoch(rid));
+        let page = match try!(Relation::find_page(&self.data_file, &self.ovflow_file, self.num_pages, rid)) {
+            Some((_, page)) => page,
+            None => return Ok(None)
+        };
+        match page.record(rid.slot) {
+            Some(record) => read_record(record, &self.blob_file).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Delete the tuple with a given record ID, returning false if there was no such tuple.
+    /// An ID from an earlier epoch is an error, as its record may have moved.
+    pub fn delete_rid(&mut self, rid: RecordId) -> io::Result<bool> {
+        self.atomically(|r| r.delete_rid_inner(rid))
+    }
+
+    fn delete_rid_inner(&mut self, rid: RecordId) -> io::Result<bool> {
+        debug!("Relation::delete_rid, record ID is: {:?}", rid);
+        try!(self.check_epoch(rid));
+        let (prev_page, mut page) =
+            match try!(Relation::find_page(&self.data_file, &self.ovflow_file, self.num_pages, rid)) {
+                Some(pages) => pages,
+                None => return Ok(false)
+            };
+        let record = match page.remove_record(rid.slot) {
+            Some(record) => record,
+            None => return Ok(false)
+        };
+        self.num_tuples -= 1;
+        self.num_bytes -= record.len() as u64;
+        try!(Relation::free_record(&self.blob_file, &mut self.blob_free_list, &record));
+
+        // Unlink the page if it is an overflow page that has been emptied, and free it. It may
+        // be reused without its tombstones, so IDs referring to it become stale.
+        match prev_page {
+            Some(mut prev_page) if page.num_tuples == 0 => {
+                prev_page.ovflow = page.ovflow;
+                try!(prev_page.write());
+                try!(self.free_list.free(&self.ovflow_file, page.id));
+                self.epoch += 1;
+            }
+            _ => try!(page.close())
+        }
+
+        try!(self.shrink_while_underloaded());
+        Ok(true)
+    }
+
+    /// Fail if a record ID was handed out before records last moved.
+    fn check_epoch(&self, rid: RecordId) -> io::Result<()> {
+        if rid.epoch == self.epoch {
+            return Ok(());
+        }
+        let error = StaleRecordId { rid: rid, epoch: self.epoch };
+        Err(io::Error::new(io::ErrorKind::InvalidInput, error))
+    }
+
+    /// Find the page a record ID refers to, along with its predecessor in the bucket's chain.
+    /// Return None if the ID doesn't refer to a page in the bucket.
+    #[allow(clippy::type_complexity)]
+    fn find_page<'a>(data_file: &'a PageFile, ovflow_file: &'a PageFile, num_pages: u64, rid: RecordId)
+    -> io::Result<Option<(Option<Page<'a>>, Page<'a>)>>
+    {
+        if rid.bucket as u64 >= num_pages {
+            return Ok(None);
+        }
+        let mut prev_page = None;
+        let mut page = try!(Page::read(data_file, rid.bucket));
+        if rid.page_in_file == PRIMARY_PAGE {
+            return Ok(Some((prev_page, page)));
+        }
+        // Walk the chain, so that stale IDs for pages now in other buckets aren't followed.
+        while page.ovflow != NO_OVFLOW {
+            let next_page = try!(Page::read(ovflow_file, page.ovflow));
+            prev_page = Some(page);
+            page = next_page;
+            if page.id == rid.page_in_file {
+                return Ok(Some((prev_page, page)));
+            }
+        }
+        Ok(None)
+    }
+
     /// Helper function for growing a relation.
     /// Store `tuple` into `storage_page` if it will fit.
+    #[allow(clippy::too_many_arguments)]
     fn store_tuple_grow<'a>(
         tuple: &[u8],
         storage_page: &mut Page<'a>,
         next_page_id: &mut u32,
-        ovflow_file: &'a File,
-        tuple_cache: &mut LinkedList<Tuple>,
+        ovflow_file: &'a PageFile,
+        free_list: &mut FreeList,
+        tuple_cache: &mut LinkedList<Vec<u8>>,
         tuples_seen: &mut usize,
         spare_pages: &mut LinkedList<u32>
     ) -> io::Result<()>
@@ -210,7 +912,6 @@ impl Relation {
         // Otherwise, we look for an overflow page.
         // If there's a spare one already hanging around, use it.
         trace!("  looking for an overflow page");
-        let mut is_new_ovflow_page = false;
         let ovflow_page_id = if let Some(spare_page) = spare_pages.pop_front() {
             trace!("  using existing spare page: {}", spare_page);
             spare_page
@@ -221,13 +922,11 @@ impl Relation {
             try!(Relation::load_next_page(next_page_id, ovflow_file, tuple_cache, tuples_seen, spare_pages));
             spare_pages.pop_front().expect("Load next page didn't work")
         }
-        // In this case (probably rare), we've run out of overflow pages from before the split.
-        // We could scan the overflow file for an unused page here, but instead we tack another
-        // one on the end.
+        // In this case (probably rare), we've run out of overflow pages from before the split,
+        // so we claim one from the free list (or the end of the file).
         else {
-            let new_ovflow_page = try!(get_next_page_id(ovflow_file));
-            is_new_ovflow_page = true;
-            trace!("  using a new overflow page from the end of the file: {}", new_ovflow_page);
+            let new_ovflow_page = try!(free_list.alloc(ovflow_file));
+            trace!("  using a newly allocated overflow page: {}", new_ovflow_page);
             new_ovflow_page
         };
 
@@ -240,30 +939,25 @@ impl Relation {
         assert!(new_storage_page.add_tuple(tuple));
         *storage_page = new_storage_page;
 
-        // If the page is entirely new, write it to the file so it doesn't get double-claimed.
-        if is_new_ovflow_page {
-            try!(storage_page.write());
-        }
-
         Ok(())
     }
 
     /// Helper function for grow.
     /// Load the next page from *next_page_id by:
-    /// 1. Loading all the tuples into tuple_cache.
+    /// 1. Loading all the records into tuple_cache.
     /// 2. Adding next_page_id to the list of spare pages.
     /// 3. Updating next_page_id to point at the overflow page for the old next_page_id.
     fn load_next_page(
         next_page_id: &mut u32,
-        ovflow_file: &File,
-        tuple_cache: &mut LinkedList<Tuple>,
+        ovflow_file: &PageFile,
+        tuple_cache: &mut LinkedList<Vec<u8>>,
         tuples_seen: &mut usize,
         spare_pages: &mut LinkedList<u32>
     ) -> io::Result<()>
     {
         assert!(*next_page_id != NO_OVFLOW);
         let next_page = try!(Page::read(ovflow_file, *next_page_id));
-        let mut new_tuples = next_page.get_tuple_list();
+        let mut new_tuples = next_page.get_records();
         *tuples_seen += new_tuples.len();
         tuple_cache.append(&mut new_tuples);
 
@@ -276,6 +970,11 @@ impl Relation {
 
     /// Grow the number of main data pages in the relation.
     pub fn grow(&mut self) -> io::Result<()> {
+        self.atomically(|r| r.grow_inner())
+    }
+
+    fn grow_inner(&mut self) -> io::Result<()> {
+        self.epoch += 1;
         let d = self.depth;
         let sp = self.split_pointer;
 
@@ -293,7 +992,7 @@ impl Relation {
         let mut spare_pages = LinkedList::new();
 
         // Cache of tuples to be redistributed.
-        let mut tuple_cache = old_low_page.get_tuple_list();
+        let mut tuple_cache = old_low_page.get_records();
         let mut tuples_seen = tuple_cache.len();
 
         // Page ID of the next overflow page to redistribute.
@@ -308,25 +1007,23 @@ impl Relation {
             trace!("  spare_pages = {:?}", spare_pages);
             trace!("  tuples seen = {:?}", tuples_seen);
             // If there is a tuple in the cache, redistribute it.
-            if let Some(tuple) = tuple_cache.pop_front() {
-                warn!("TUPLE: {}", tuple.to_string());
-                let full_hash = tuple.hash(&self.choice_vec);
+            if let Some(record) = tuple_cache.pop_front() {
+                let full_hash = try!(self.record_hash(&record));
                 trace!("  full tuple hash = {:b}", full_hash);
                 let hash = lower_bits(d + 1, full_hash);
                 trace!("  lower_bits({}, hash) = {:b}", d + 1, hash);
-                let s_tuple = tuple.serialise();
 
                 // Store on the left if the hash bits still match the split pointer.
                 let storage_page = if hash == sp  {
-                    trace!("  Storing tuple {:?} with hash {:b} on the LEFT", tuple.values, hash);
+                    trace!("  Storing tuple with hash {:b} on the LEFT", hash);
                     &mut low_page
                 } else {
-                    trace!("  Storing tuple {:?} with hash {:b} on the RIGHT", tuple.values, hash);
+                    trace!("  Storing tuple with hash {:b} on the RIGHT", hash);
                     &mut high_page
                 };
                 try!(Relation::store_tuple_grow(
-                    &s_tuple, storage_page, &mut next_page_id,
-                    &self.ovflow_file, &mut tuple_cache, &mut tuples_seen, &mut spare_pages
+                    &record, storage_page, &mut next_page_id, &self.ovflow_file,
+                    &mut self.free_list, &mut tuple_cache, &mut tuples_seen, &mut spare_pages
                 ));
             }
             // Otherwise if the cache is exhausted and there are no further pages, we're done.
@@ -348,13 +1045,11 @@ impl Relation {
 
         self.num_pages += 1;
 
-        // If there are left-over overflow pages, zero them.
-        // NOTE: This is a source of fragmentation in the overflow file.
+        // If there are left-over overflow pages, free them for re-use.
         debug!("TOTAL tuples seen: {}", tuples_seen);
         debug!("Spare pages left are: {:?}", spare_pages);
         for spare_page in spare_pages {
-            let mut leftover_page = Page::empty(&self.ovflow_file, spare_page);
-            try!(leftover_page.write());
+            try!(self.free_list.free(&self.ovflow_file, spare_page));
         }
 
         // If the split pointer has hit the cross-over point, reset it to 0.
@@ -368,15 +1063,195 @@ impl Relation {
         Ok(())
     }
 
+    /// Shrink the number of main data pages in the relation, undoing the last split.
+    ///
+    /// The last bucket is merged back into its buddy (the bucket it was split from),
+    /// and the data file is truncated by one page.
+    pub fn shrink(&mut self) -> io::Result<()> {
+        self.atomically(|r| r.shrink_inner())
+    }
+
+    fn shrink_inner(&mut self) -> io::Result<()> {
+        assert!(self.num_pages > 1, "can't shrink a relation with a single page");
+        self.epoch += 1;
+
+        // Move the split pointer back, dropping a level of depth at the boundary.
+        if self.split_pointer == 0 {
+            self.depth -= 1;
+            self.split_pointer = 1 << self.depth;
+        }
+        self.split_pointer -= 1;
+
+        let buddy_id = self.split_pointer;
+        let last_id = (self.num_pages - 1) as u32;
+        debug!("Merging page {:b} into {:b}", last_id, buddy_id);
+
+        // Load every tuple from the last bucket, keeping its overflow pages for re-use.
+        let last_page = try!(Page::read(&self.data_file, last_id));
+        let mut tuple_cache = last_page.get_records();
+        let mut spare_pages = LinkedList::new();
+        let mut next_page_id = last_page.ovflow;
+        drop(last_page);
+        while next_page_id != NO_OVFLOW {
+            let mut tuples_seen = 0;
+            try!(Relation::load_next_page(
+                &mut next_page_id, &self.ovflow_file,
+                &mut tuple_cache, &mut tuples_seen, &mut spare_pages
+            ));
+        }
+
+        // Find the last page in the buddy's chain, and append the tuples from there.
+        let mut storage_page = try!(Page::read(&self.data_file, buddy_id));
+        while storage_page.ovflow != NO_OVFLOW {
+            let ovflow_id = storage_page.ovflow;
+            storage_page = try!(Page::read(&self.ovflow_file, ovflow_id));
+        }
+
+        for record in tuple_cache {
+            // There are no pages left to load, so the loader arguments are just placeholders.
+            let mut no_next_page = NO_OVFLOW;
+            try!(Relation::store_tuple_grow(
+                &record, &mut storage_page, &mut no_next_page, &self.ovflow_file,
+                &mut self.free_list, &mut LinkedList::new(), &mut 0, &mut spare_pages
+            ));
+        }
+        try!(storage_page.close());
+
+        // Free any overflow pages from the old bucket that weren't re-used.
+        debug!("Spare pages left are: {:?}", spare_pages);
+        for spare_page in spare_pages {
+            try!(self.free_list.free(&self.ovflow_file, spare_page));
+        }
+
+        // Finally, chop the last page off the data file.
+        self.num_pages -= 1;
+        try!(self.data_file.truncate(self.num_pages as u32));
+        Ok(())
+    }
+
+    /// Compact the overflow file so that every page in it is in use, and free the slots of
+    /// deleted records for reuse.
+    ///
+    /// Pages in use past the end of the compacted file are moved into free slots nearer the
+    /// start, and the tail of the file is trimmed. Return the number of pages reclaimed.
+    pub fn compact_overflow(&mut self) -> io::Result<u32> {
+        self.atomically(|r| r.compact_overflow_inner())
+    }
+
+    fn compact_overflow_inner(&mut self) -> io::Result<u32> {
+        if try!(self.reclaim_tombstones()) {
+            self.epoch += 1;
+        }
+
+        // Map each overflow page in a bucket to its predecessor in the chain,
+        // given as (is an overflow page, page ID).
+        let mut predecessors: HashMap<u32, (bool, u32)> = HashMap::new();
+        for bucket in 0..self.num_pages as u32 {
+            let mut prev = (false, bucket);
+            let mut next_page_id = try!(Page::read(&self.data_file, bucket)).ovflow;
+            while next_page_id != NO_OVFLOW {
+                predecessors.insert(next_page_id, prev);
+                prev = (true, next_page_id);
+                next_page_id = try!(Page::read(&self.ovflow_file, next_page_id)).ovflow;
+            }
+        }
+
+        let num_live = predecessors.len() as u32;
+        let file_len = try!(get_next_page_id(&self.ovflow_file));
+        let holes: Vec<u32> = (0..num_live).filter(|id| !predecessors.contains_key(id)).collect();
+        let mut to_move: Vec<u32> = predecessors.keys().cloned().filter(|&id| id >= num_live).collect();
+        to_move.sort();
+        assert_eq!(holes.len(), to_move.len());
+
+        for (&src, &dst) in to_move.iter().zip(holes.iter()) {
+            trace!("Moving overflow page {} to {}", src, dst);
+            let mut moved_page = try!(Page::read(&self.ovflow_file, src)).relocate(&self.ovflow_file, dst);
+            try!(moved_page.write());
+
+            // Point the predecessor at the page's new location.
+            let (pred_is_ovflow, pred_id) = predecessors[&src];
+            let pred_file = if pred_is_ovflow { &self.ovflow_file } else { &self.data_file };
+            let mut pred_page = try!(Page::read(pred_file, pred_id));
+            pred_page.ovflow = dst;
+            try!(pred_page.write());
+
+            // The successor (if any) now follows the moved page.
+            if moved_page.ovflow != NO_OVFLOW {
+                predecessors.insert(moved_page.ovflow, (true, dst));
+            }
+        }
+
+        try!(self.ovflow_file.truncate(num_live));
+        self.free_list = FreeList::new();
+        if !to_move.is_empty() {
+            self.epoch += 1;
+        }
+        debug!("Compacted overflow file from {} to {} pages", file_len, num_live);
+        Ok(file_len - num_live)
+    }
+
+    /// Free the slots of deleted records in every page, returning whether there were any.
+    fn reclaim_tombstones(&self) -> io::Result<bool> {
+        let mut reclaimed = false;
+        for bucket in 0..self.num_pages as u32 {
+            let mut page = try!(Page::read(&self.data_file, bucket));
+            loop {
+                reclaimed |= page.reclaim_tombstones();
+                let next_page_id = page.ovflow;
+                try!(page.close());
+                if next_page_id == NO_OVFLOW {
+                    break;
+                }
+                page = try!(Page::read(&self.ovflow_file, next_page_id));
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Read the relation's metadata from its info file.
+    fn read_info_file(&mut self) -> io::Result<()> {
+        let mut f = &self.info_file;
+        try!(f.seek(SeekFrom::Start(0)));
+        try!(upgrade::check_version(f, &info_file_name(&self.name), self.version));
+        self.num_attrs = try!(read_u32(f));
+        self.page_size = try!(read_u32(f));
+        if !is_valid_page_size(self.page_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid page size"));
+        }
+        try!(self.pool.borrow_mut().set_page_size(self.page_size));
+        self.depth = try!(read_u8(f));
+        self.split_pointer = try!(read_u32(f));
+        self.num_pages = try!(read_u64(f));
+        self.num_tuples = try!(read_u64(f));
+        self.num_bytes = try!(read_u64(f));
+        self.choice_vec = try!(ChoiceVec::read(f));
+        self.split_policy = try!(SplitPolicy::read(f));
+        self.free_list = try!(FreeList::read(f));
+        self.blob_free_list = try!(FreeList::read(f));
+        self.schema = try!(Schema::read(f, self.num_attrs));
+        // Older versions, only opened to upgrade them, are in their first epoch.
+        self.epoch = if self.version >= 9 { try!(read_u32(f)) } else { 0 };
+        Ok(())
+    }
+
     pub fn write_info_file(&mut self) -> io::Result<()> {
         let mut f = &self.info_file;
         try!(f.seek(SeekFrom::Start(0)));
+        try!(upgrade::write_header(f));
+        self.version = upgrade::FORMAT_VERSION;
         try!(write_u32(f, self.num_attrs));
+        try!(write_u32(f, self.page_size));
         try!(write_u8(f, self.depth));
         try!(write_u32(f, self.split_pointer));
         try!(write_u64(f, self.num_pages));
         try!(write_u64(f, self.num_tuples));
+        try!(write_u64(f, self.num_bytes));
         try!(self.choice_vec.write(f));
+        try!(self.split_policy.write(f));
+        try!(self.free_list.write(f));
+        try!(self.blob_free_list.write(f));
+        try!(self.schema.write(f));
+        try!(write_u32(f, self.epoch));
         Ok(())
     }
 
@@ -392,37 +1267,117 @@ impl Relation {
         // num_pages = 2^d + split_pointer.
         assert_eq!(self.num_pages, (1 << self.depth as u64) + (self.split_pointer as u64));
 
-        // Check the number of tuples.
-        assert_eq!(self.select(&Query::wildcard(self.num_attrs)).count(), self.num_tuples as usize);
+        // Check that every overflow page is either in a bucket or on the free list, exactly once.
+        let mut ovflow_pages = HashSet::new();
+        for bucket in 0..self.num_pages as u32 {
+            let mut next_page_id = Page::read(&self.data_file, bucket).unwrap().ovflow;
+            while next_page_id != NO_OVFLOW {
+                assert!(ovflow_pages.insert(next_page_id));
+                next_page_id = Page::read(&self.ovflow_file, next_page_id).unwrap().ovflow;
+            }
+        }
+        let free_pages = self.free_list.page_ids(&self.ovflow_file).unwrap();
+        assert_eq!(free_pages.len(), self.free_list.len as usize);
+        for page_id in free_pages {
+            assert!(ovflow_pages.insert(page_id));
+        }
+        assert_eq!(ovflow_pages.len() as u32, get_next_page_id(&self.ovflow_file).unwrap());
+
+        // Check the number of tuples, and the total size of their records.
+        let tuples: Vec<Tuple> = self.select(&Query::wildcard(self.num_attrs))
+            .map(|t| t.unwrap())
+            .collect();
+        assert_eq!(tuples.len(), self.num_tuples as usize);
+        let mut records = vec![];
+        for bucket in 0..self.num_pages as u32 {
+            let mut page = Page::read(&self.data_file, bucket).unwrap();
+            records.extend(page.get_records());
+            while page.ovflow != NO_OVFLOW {
+                page = Page::read(&self.ovflow_file, page.ovflow).unwrap();
+                records.extend(page.get_records());
+            }
+        }
+        let num_bytes: usize = records.iter().map(|r| r.len()).sum();
+        assert_eq!(num_bytes as u64, self.num_bytes);
+
+        // Check that every blob page belongs to exactly one blob, or is on the free list.
+        let mut blob_pages = HashSet::new();
+        for blob_ref in records.iter().filter_map(|r| BlobRef::parse_bytes(r)) {
+            for page_id in blob_ref.page_ids(&self.blob_file).unwrap() {
+                assert!(blob_pages.insert(page_id));
+            }
+        }
+        let free_pages = self.blob_free_list.page_ids(&self.blob_file).unwrap();
+        assert_eq!(free_pages.len(), self.blob_free_list.len as usize);
+        for page_id in free_pages {
+            assert!(blob_pages.insert(page_id));
+        }
+        assert_eq!(blob_pages.len() as u32, get_next_page_id(&self.blob_file).unwrap());
     }
 
     pub fn delete(&mut self) -> io::Result<()> {
+        // Discard cached pages, which would otherwise be written back to the deleted files.
+        try!(self.pool.borrow_mut().invalidate());
         try!(fs::remove_file(info_file_name(&self.name)));
         try!(fs::remove_file(data_file_name(&self.name)));
         try!(fs::remove_file(ovflow_file_name(&self.name)));
+        try!(fs::remove_file(blob_file_name(&self.name)));
+        // Relations created by older versions may not have a log.
+        let wal_name = wal_file_name(&self.name);
+        if Path::new(&wal_name).is_file() {
+            try!(fs::remove_file(wal_name));
+        }
         Ok(())
     }
 }
 
+/// Order and pagination of the tuples yielded by `Relation::select_with`.
+#[derive(Debug, Clone)]
+pub struct SelectOptions {
+    /// Keys to sort tuples by, or none to yield them in storage order.
+    pub order_by: Vec<SortKey>,
+    /// Number of tuples to skip.
+    pub offset: usize,
+    /// Most tuples to yield after skipping, or None for all of them.
+    pub limit: Option<usize>,
+    /// Most bytes of tuples to sort in memory.
+    pub memory_budget: usize,
+}
+
+impl Default for SelectOptions {
+    fn default() -> SelectOptions {
+        SelectOptions {
+            order_by: vec![],
+            offset: 0,
+            limit: None,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+        }
+    }
+}
+
 pub struct SelectIter<'a> {
     /// Query being executed by this iterator.
     query: &'a Query<'a>,
-    /// Iterator over page IDs that match the query's hash.
-    page_id_iter: PageIdIter,
+    schema: &'a Schema,
+    /// The relation's epoch, for the record IDs yielded.
+    epoch: u32,
+    /// Iterator over page IDs that match the query's hashes.
+    page_id_iter: PageIdUnion,
     /// Iterator for the current bucket.
     bucket_iter: Option<PageQueryIter<'a>>,
-    data_file: &'a File,
-    ovflow_file: &'a File
+    data_file: &'a PageFile,
+    ovflow_file: &'a PageFile,
+    blob_file: &'a PageFile
 }
 
-impl<'a> Iterator for SelectIter<'a> {
-    type Item = io::Result<Tuple>;
-
-    fn next(&mut self) -> Option<io::Result<Tuple>> {
+impl<'a> SelectIter<'a> {
+    /// Fetch the next matching tuple, along with its record ID.
+    pub fn next_with_id(&mut self) -> Option<io::Result<(RecordId, Tuple)>> {
         // Return values from the current bucket iterator, if there are any.
         let next_item = self.bucket_iter.as_mut().and_then(|iter| iter.next());
-        if next_item.is_some() {
-            return next_item;
+        if let Some(item) = next_item {
+            let epoch = self.epoch;
+            return Some(item.map(|(rid, tuple)| (RecordId { epoch: epoch, ..rid }, tuple)));
         }
         // Once it is exhausted, fetch the next page ID and start fetching tuples from it.
         let next_page_id = match self.page_id_iter.next() {
@@ -436,7 +1391,34 @@ impl<'a> Iterator for SelectIter<'a> {
                 return Some(Err(e));
             }
         };
-        self.bucket_iter = Some(next_page.select(self.query, self.ovflow_file));
-        self.next()
+        match next_page.select(self.query, self.schema, self.ovflow_file, self.blob_file) {
+            Ok(bucket_iter) => self.bucket_iter = Some(bucket_iter),
+            Err(e) => return Some(Err(e))
+        }
+        self.next_with_id()
+    }
+
+    /// Yield record IDs along with the tuples.
+    pub fn with_ids(self) -> SelectWithIds<'a> {
+        SelectWithIds(self)
+    }
+}
+
+impl<'a> Iterator for SelectIter<'a> {
+    type Item = io::Result<Tuple>;
+
+    fn next(&mut self) -> Option<io::Result<Tuple>> {
+        self.next_with_id().map(|item| item.map(|(_, tuple)| tuple))
+    }
+}
+
+/// Iterator over matching tuples and their record IDs, from `SelectIter::with_ids`.
+pub struct SelectWithIds<'a>(SelectIter<'a>);
+
+impl<'a> Iterator for SelectWithIds<'a> {
+    type Item = io::Result<(RecordId, Tuple)>;
+
+    fn next(&mut self) -> Option<io::Result<(RecordId, Tuple)>> {
+        self.0.next_with_id()
     }
 }
@@ -0,0 +1,437 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write, ErrorKind};
+
+use tuple::Tuple;
+use util::*;
+
+/// Hash of NULL, for every type. All NULLs of an attribute hash alike, so that
+/// `IS NULL` conditions can narrow down the buckets to search.
+pub const NULL_HASH: u32 = 0;
+
+/// Type of an attribute. Values are always stored as text, which must parse as the type.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AttrType {
+    Text,
+    /// 64-bit signed integer.
+    Int,
+    /// 64-bit floating point number, other than NaN.
+    Float,
+    /// `true` or `false`.
+    Bool,
+    /// Binary data, written as an even number of hex digits.
+    Bytes,
+}
+
+impl AttrType {
+    pub fn parse(input: &str) -> Option<AttrType> {
+        match input {
+            "text" => Some(AttrType::Text),
+            "int" => Some(AttrType::Int),
+            "float" => Some(AttrType::Float),
+            "bool" => Some(AttrType::Bool),
+            "bytes" => Some(AttrType::Bytes),
+            _ => None
+        }
+    }
+
+    /// Check whether a value is valid for the type.
+    pub fn is_valid(self, value: &str) -> bool {
+        match self {
+            AttrType::Text => true,
+            AttrType::Int => value.parse::<i64>().is_ok(),
+            AttrType::Float => value.parse::<f64>().map(|x| !x.is_nan()).unwrap_or(false),
+            AttrType::Bool => value == "true" || value == "false",
+            AttrType::Bytes => {
+                value.len() % 2 == 0 && value.chars().all(|c| c.is_ascii_hexdigit())
+            }
+        }
+    }
+
+    /// Canonical form of a value, which is the same for all values that are equal as the type:
+    /// integers and floats without redundant digits (`01` is `1`, `1.50` is `1.5`, `-0` is
+    /// `0`), and bytes in lower case. Invalid values are left as they are.
+    pub fn canonical<'a>(self, value: &'a str) -> Cow<'a, str> {
+        match self {
+            AttrType::Int => match value.parse::<i64>() {
+                Ok(x) => Cow::Owned(x.to_string()),
+                Err(_) => Cow::Borrowed(value)
+            },
+            AttrType::Float => match value.parse::<f64>() {
+                // Negative zero would otherwise print as `-0`.
+                Ok(x) if !x.is_nan() => Cow::Owned(if x == 0.0 { 0.0 } else { x }.to_string()),
+                _ => Cow::Borrowed(value)
+            },
+            AttrType::Bytes => Cow::Owned(value.to_ascii_lowercase()),
+            AttrType::Text | AttrType::Bool => Cow::Borrowed(value)
+        }
+    }
+
+    /// Compare two values as the type: numerically for integers and floats, and by their
+    /// canonical form otherwise, which puts `false` before `true` and orders bytes as bytes.
+    /// Values that aren't valid for the type compare as text.
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        let numeric = match self {
+            AttrType::Int => match (a.parse::<i64>(), b.parse::<i64>()) {
+                (Ok(x), Ok(y)) => Some(x.cmp(&y)),
+                _ => None
+            },
+            AttrType::Float => match (a.parse::<f64>(), b.parse::<f64>()) {
+                (Ok(x), Ok(y)) => x.partial_cmp(&y),
+                _ => None
+            },
+            AttrType::Text | AttrType::Bool | AttrType::Bytes => None
+        };
+        numeric.unwrap_or_else(|| self.canonical(a).cmp(&self.canonical(b)))
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            AttrType::Text => 0,
+            AttrType::Int => 1,
+            AttrType::Float => 2,
+            AttrType::Bool => 3,
+            AttrType::Bytes => 4,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<AttrType> {
+        match tag {
+            0 => Some(AttrType::Text),
+            1 => Some(AttrType::Int),
+            2 => Some(AttrType::Float),
+            3 => Some(AttrType::Bool),
+            4 => Some(AttrType::Bytes),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for AttrType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            AttrType::Text => "text",
+            AttrType::Int => "int",
+            AttrType::Float => "float",
+            AttrType::Bool => "bool",
+            AttrType::Bytes => "bytes",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Attribute {
+    pub name: String,
+    pub attr_type: AttrType,
+}
+
+/// Names and types of a relation's attributes, in order.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Schema {
+    pub attrs: Vec<Attribute>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum ParseError {
+    InvalidName(String),
+    DuplicateName(String),
+    UnknownType(String),
+}
+
+/// Ways in which a tuple can fail to fit a schema.
+#[derive(PartialEq, Eq, Debug)]
+pub enum SchemaError {
+    /// The tuple has the wrong number of values (expected, found).
+    WrongLength(usize, usize),
+    /// A value doesn't parse as the type of its attribute (attribute name, type, value).
+    InvalidValue(String, AttrType, String),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SchemaError::WrongLength(expected, found) => {
+                write!(f, "expected {} values, found {}", expected, found)
+            }
+            SchemaError::InvalidValue(ref name, attr_type, ref value) => {
+                write!(f, "invalid {} value for attribute {}: {:?}", attr_type, name, value)
+            }
+        }
+    }
+}
+
+impl Error for SchemaError {}
+
+/// Check that an attribute name is an identifier: letters, digits and underscores,
+/// not starting with a digit.
+fn is_valid_name(name: &str) -> bool {
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() => {
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false
+    }
+}
+
+impl Schema {
+    /// Schema for relations created without one: text attributes named `attr0`, `attr1`, ...
+    pub fn untyped(num_attrs: u32) -> Schema {
+        Schema {
+            attrs: (0..num_attrs).map(|i| Attribute {
+                name: format!("attr{}", i),
+                attr_type: AttrType::Text
+            }).collect()
+        }
+    }
+
+    /// Parse comma-separated attributes, each a name and optionally a type,
+    /// like `name:text,age:int`. Attributes without a type are text.
+    pub fn parse(input: &str) -> Result<Schema, ParseError> {
+        let mut attrs: Vec<Attribute> = vec![];
+        for entry in input.split(',') {
+            let (name, type_name) = match entry.find(':') {
+                Some(i) => (&entry[..i], &entry[i + 1..]),
+                None => (entry, "text")
+            };
+            if !is_valid_name(name) {
+                return Err(ParseError::InvalidName(name.to_string()));
+            }
+            if attrs.iter().any(|attr| attr.name == name) {
+                return Err(ParseError::DuplicateName(name.to_string()));
+            }
+            let attr_type = try!(AttrType::parse(type_name)
+                .ok_or_else(|| ParseError::UnknownType(type_name.to_string())));
+            attrs.push(Attribute { name: name.to_string(), attr_type: attr_type });
+        }
+        Ok(Schema { attrs: attrs })
+    }
+
+    pub fn num_attrs(&self) -> u32 {
+        self.attrs.len() as u32
+    }
+
+    /// Index of the attribute with a given name.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.attrs.iter().position(|attr| attr.name == name)
+    }
+
+    /// Hash a value of the attribute at `index`, so that equal values of its type collide.
+    /// Text values hash the same as they always have, and NULL hashes to `NULL_HASH`.
+    pub fn hash_value(&self, index: usize, value: Option<&str>) -> u32 {
+        match value {
+            Some(value) => hash(&*self.attrs[index].attr_type.canonical(value)),
+            None => NULL_HASH
+        }
+    }
+
+    /// Compare two values of the attribute at `index` as values of its type.
+    pub fn values_equal(&self, index: usize, a: &str, b: &str) -> bool {
+        let attr_type = self.attrs[index].attr_type;
+        a == b || attr_type.canonical(a) == attr_type.canonical(b)
+    }
+
+    /// Schema of tuples projected onto the attributes at `columns`, in that order.
+    pub fn project(&self, columns: &[usize]) -> Schema {
+        Schema { attrs: columns.iter().map(|&i| self.attrs[i].clone()).collect() }
+    }
+
+    /// Order two values of the attribute at `index` as values of its type.
+    pub fn compare_values(&self, index: usize, a: &str, b: &str) -> Ordering {
+        self.attrs[index].attr_type.compare(a, b)
+    }
+
+    /// Check that a value is valid for the attribute at `index`.
+    pub fn check_value(&self, index: usize, value: &str) -> Result<(), SchemaError> {
+        let attr = &self.attrs[index];
+        if attr.attr_type.is_valid(value) {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidValue(attr.name.clone(), attr.attr_type, value.to_string()))
+        }
+    }
+
+    /// Check that a tuple has a valid value for every attribute. Any attribute can be NULL.
+    pub fn check(&self, tuple: &Tuple) -> Result<(), SchemaError> {
+        if tuple.values.len() != self.attrs.len() {
+            return Err(SchemaError::WrongLength(self.attrs.len(), tuple.values.len()));
+        }
+        for (i, value) in tuple.values.iter().enumerate() {
+            if let Some(ref value) = *value {
+                try!(self.check_value(i, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Write each attribute's name (prefixed by its length) and type.
+    /// The number of attributes is stored separately.
+    pub fn write<W: Write>(&self, mut f: W) -> io::Result<()> {
+        for attr in &self.attrs {
+            try!(write_u32(&mut f, attr.name.len() as u32));
+            try!(f.write_all(attr.name.as_bytes()));
+            try!(f.write_all(&[attr.attr_type.tag()]));
+        }
+        Ok(())
+    }
+
+    pub fn read(f: &File, num_attrs: u32) -> io::Result<Schema> {
+        let invalid = |msg| io::Error::new(ErrorKind::InvalidData, msg);
+        let mut attrs = vec![];
+        for _ in 0..num_attrs {
+            let len = try!(read_u32(f));
+            let mut name = vec![];
+            try!(f.take(len as u64).read_to_end(&mut name));
+            let name = try!(String::from_utf8(name)
+                .ok()
+                .filter(|name| name.len() == len as usize)
+                .ok_or_else(|| invalid("invalid attribute name")));
+            let attr_type = try!(AttrType::from_tag(try!(read_u8(f)))
+                .ok_or_else(|| invalid("unknown attribute type")));
+            attrs.push(Attribute { name: name, attr_type: attr_type });
+        }
+        Ok(Schema { attrs: attrs })
+    }
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let attrs: Vec<String> = self.attrs.iter()
+            .map(|attr| format!("{}:{}", attr.name, attr.attr_type))
+            .collect();
+        write!(f, "{}", attrs.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn people() -> Schema {
+        Schema::parse("name:text,age:int,height:float,member:bool,photo:bytes").unwrap()
+    }
+
+    #[test]
+    fn parse_schemas() {
+        let schema = people();
+        assert_eq!(schema.num_attrs(), 5);
+        assert_eq!(schema.attrs[1], Attribute { name: "age".to_string(), attr_type: AttrType::Int });
+        assert_eq!(schema.index_of("member"), Some(3));
+        assert_eq!(schema.index_of("nobody"), None);
+        assert_eq!(Schema::parse("a,b:int").unwrap().attrs[0].attr_type, AttrType::Text);
+        assert_eq!(Schema::parse(&schema.to_string()), Ok(schema));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(Schema::parse(""), Err(ParseError::InvalidName("".to_string())));
+        assert_eq!(Schema::parse("1st"), Err(ParseError::InvalidName("1st".to_string())));
+        assert_eq!(Schema::parse("a b"), Err(ParseError::InvalidName("a b".to_string())));
+        assert_eq!(Schema::parse("a,a:int"), Err(ParseError::DuplicateName("a".to_string())));
+        assert_eq!(Schema::parse("a:string"), Err(ParseError::UnknownType("string".to_string())));
+    }
+
+    #[test]
+    fn untyped() {
+        assert_eq!(Schema::untyped(2).to_string(), "attr0:text,attr1:text");
+    }
+
+    #[test]
+    fn check_values() {
+        let valid = [
+            (AttrType::Int, vec!["0", "-12", "+7", "01"]),
+            (AttrType::Float, vec!["1", "-0.5", "1e10", "inf"]),
+            (AttrType::Bool, vec!["true", "false"]),
+            (AttrType::Bytes, vec!["", "00ff", "DeadBeef"]),
+        ];
+        let invalid = [
+            (AttrType::Int, vec!["", "1.5", "ten", "99999999999999999999"]),
+            (AttrType::Float, vec!["", "NaN", "1,5"]),
+            (AttrType::Bool, vec!["", "yes", "True", "1"]),
+            (AttrType::Bytes, vec!["f", "0g"]),
+        ];
+        for &(attr_type, ref values) in &valid {
+            assert!(values.iter().all(|v| attr_type.is_valid(v)), "{}", attr_type);
+        }
+        for &(attr_type, ref values) in &invalid {
+            assert!(values.iter().all(|v| !attr_type.is_valid(v)), "{}", attr_type);
+        }
+    }
+
+    #[test]
+    fn canonical_values() {
+        let equal = [
+            (AttrType::Int, "01", "1"),
+            (AttrType::Int, "+7", "7"),
+            (AttrType::Int, "-0", "0"),
+            (AttrType::Float, "1.0", "1"),
+            (AttrType::Float, "1.50", "1.5"),
+            (AttrType::Float, "-0.0", "0"),
+            (AttrType::Float, "1e3", "1000"),
+            (AttrType::Bytes, "DEADbeef", "deadbeef"),
+        ];
+        let unequal = [
+            (AttrType::Text, "01", "1"),
+            (AttrType::Int, "1", "2"),
+            (AttrType::Float, "0.1", "0.10000001"),
+            (AttrType::Bool, "true", "false"),
+        ];
+        let schema = Schema {
+            attrs: vec![AttrType::Text, AttrType::Int, AttrType::Float, AttrType::Bool, AttrType::Bytes]
+                .into_iter()
+                .map(|attr_type| Attribute { name: attr_type.to_string(), attr_type: attr_type })
+                .collect()
+        };
+        for &(attr_type, a, b) in &equal {
+            let i = schema.index_of(&attr_type.to_string()).unwrap();
+            assert_eq!(attr_type.canonical(a), attr_type.canonical(b));
+            assert!(schema.values_equal(i, a, b));
+            assert_eq!(schema.hash_value(i, Some(a)), schema.hash_value(i, Some(b)));
+        }
+        for &(attr_type, a, b) in &unequal {
+            let i = schema.index_of(&attr_type.to_string()).unwrap();
+            assert!(!schema.values_equal(i, a, b), "{} {} {}", attr_type, a, b);
+        }
+        assert_eq!(schema.hash_value(0, Some("text")), hash("text"));
+        assert_eq!(schema.hash_value(1, None), NULL_HASH);
+    }
+
+    #[test]
+    fn compare_values() {
+        let ordered = [
+            (AttrType::Text, "10", "9"),
+            (AttrType::Int, "9", "10"),
+            (AttrType::Int, "-10", "+2"),
+            (AttrType::Float, "-1.5", "-1"),
+            (AttrType::Float, "2.5", "1e1"),
+            (AttrType::Bool, "false", "true"),
+            (AttrType::Bytes, "0A", "0b"),
+            (AttrType::Bytes, "ff", "ff00"),
+        ];
+        for &(attr_type, a, b) in &ordered {
+            assert_eq!(attr_type.compare(a, b), Ordering::Less, "{} {} {}", attr_type, a, b);
+            assert_eq!(attr_type.compare(b, a), Ordering::Greater, "{} {} {}", attr_type, a, b);
+        }
+        assert_eq!(AttrType::Float.compare("1.50", "1.5"), Ordering::Equal);
+        assert_eq!(AttrType::Int.compare("007", "7"), Ordering::Equal);
+    }
+
+    #[test]
+    fn check_tuples() {
+        let schema = people();
+        let tuple = |values: &[&str]| Tuple { values: values.iter().map(|v| Some(v.to_string())).collect() };
+        assert_eq!(schema.check(&tuple(&["alice", "30", "1.7", "true", "00"])), Ok(()));
+        let mut nulls = tuple(&["alice", "30", "1.7", "true", "00"]);
+        nulls.values[1] = None;
+        nulls.values[4] = None;
+        assert_eq!(schema.check(&nulls), Ok(()));
+        assert_eq!(schema.check(&tuple(&["alice", "30"])), Err(SchemaError::WrongLength(5, 2)));
+        assert_eq!(
+            schema.check(&tuple(&["alice", "thirty", "1.7", "true", "00"])),
+            Err(SchemaError::InvalidValue("age".to_string(), AttrType::Int, "thirty".to_string()))
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write, ErrorKind};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::vec;
+
+use rand;
+
+use schema::Schema;
+use tuple::Tuple;
+use util::*;
+
+/// Most bytes of tuples to sort in memory before spilling them to a temporary file.
+pub const DEFAULT_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+/// Most sorted runs to merge at once, each of which needs an open file.
+pub const MERGE_FAN_IN: usize = 64;
+
+/// Estimated bytes of memory taken by each value of a tuple, besides its text.
+const VALUE_OVERHEAD: usize = 32;
+
+/// Attribute to order tuples by, in ascending order unless `descending`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SortKey {
+    pub attr: usize,
+    pub descending: bool,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum ParseError {
+    UnknownAttribute(String),
+    /// A key that isn't an attribute, optionally followed by `ASC` or `DESC`.
+    InvalidKey(String),
+}
+
+impl SortKey {
+    /// Parse comma-separated keys like `age DESC,name`, with attributes by name or by
+    /// position from 0, each optionally followed by `ASC` or `DESC` in any case.
+    pub fn parse_list(input: &str, schema: &Schema) -> Result<Vec<SortKey>, ParseError> {
+        input.split(',').map(|key| {
+            let words: Vec<&str> = key.split_whitespace().collect();
+            let descending = match words.get(1) {
+                None => false,
+                Some(word) if words.len() == 2 && word.eq_ignore_ascii_case("asc") => false,
+                Some(word) if words.len() == 2 && word.eq_ignore_ascii_case("desc") => true,
+                Some(_) => return Err(ParseError::InvalidKey(key.trim().to_string()))
+            };
+            let name = try!(words.first().ok_or_else(|| ParseError::InvalidKey(key.to_string())));
+            let attr = match name.parse::<usize>() {
+                Ok(i) if i < schema.attrs.len() => Some(i),
+                Ok(_) => None,
+                Err(_) => schema.index_of(name)
+            };
+            let attr = try!(attr.ok_or_else(|| ParseError::UnknownAttribute(name.to_string())));
+            Ok(SortKey { attr: attr, descending: descending })
+        }).collect()
+    }
+}
+
+/// Compare tuples by each key in turn, with values compared as values of their attributes'
+/// types, and NULLs before other values in ascending order.
+pub fn compare_tuples(a: &Tuple, b: &Tuple, keys: &[SortKey], schema: &Schema) -> Ordering {
+    for key in keys {
+        let ordering = match (&a.values[key.attr], &b.values[key.attr]) {
+            (Some(x), Some(y)) => schema.compare_values(key.attr, x, y),
+            (x, y) => x.is_some().cmp(&y.is_some())
+        };
+        let ordering = if key.descending { ordering.reverse() } else { ordering };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// External merge sort of tuples. Tuples are sorted in memory until they would take more
+/// than the memory budget, at which point they are written out to a temporary file as a
+/// sorted run, holding at least one tuple however small the budget. Runs are merged
+/// `fan_in` at a time, both while tuples are added and once they all have been, so that
+/// no more than `fan_in` run files are ever open at once. The sort is stable.
+pub struct Sorter {
+    order: Rc<Order>,
+    memory_budget: usize,
+    fan_in: usize,
+    /// Tuples not yet written out, and an estimate of the memory they take.
+    tuples: Vec<Tuple>,
+    size: usize,
+    /// Runs in the order their tuples were added, each with its level: the number of rounds
+    /// of merging its tuples have been through.
+    runs: Vec<(u32, Run)>,
+}
+
+/// Order to sort tuples in, shared by the heads of the runs being merged.
+struct Order {
+    schema: Schema,
+    keys: Vec<SortKey>,
+}
+
+impl Order {
+    fn compare(&self, a: &Tuple, b: &Tuple) -> Ordering {
+        compare_tuples(a, b, &self.keys, &self.schema)
+    }
+}
+
+impl Sorter {
+    /// Sorter of tuples fitting `schema`, ordered by `keys`.
+    pub fn new(schema: Schema, keys: Vec<SortKey>, memory_budget: usize) -> Sorter {
+        Sorter::with_fan_in(schema, keys, memory_budget, MERGE_FAN_IN)
+    }
+
+    /// Sorter that merges at most `fan_in` runs at once, which must be at least 2.
+    pub fn with_fan_in(schema: Schema, keys: Vec<SortKey>, memory_budget: usize, fan_in: usize)
+                       -> Sorter {
+        assert!(fan_in >= 2, "can't merge fewer than 2 runs at once");
+        Sorter {
+            order: Rc::new(Order { schema: schema, keys: keys }),
+            memory_budget: memory_budget,
+            fan_in: fan_in,
+            tuples: vec![],
+            size: 0,
+            runs: vec![],
+        }
+    }
+
+    pub fn add(&mut self, tuple: Tuple) -> io::Result<()> {
+        self.size += tuple.values.iter()
+            .map(|value| VALUE_OVERHEAD + value.as_ref().map_or(0, |v| v.len()))
+            .sum::<usize>();
+        self.tuples.push(tuple);
+        if self.size > self.memory_budget {
+            try!(self.spill());
+        }
+        Ok(())
+    }
+
+    /// Number of runs in temporary files.
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Sort the tuples in memory.
+    fn sort(&mut self) {
+        let order = &self.order;
+        self.tuples.sort_by(|a, b| order.compare(a, b));
+    }
+
+    /// Write the tuples in memory out as a sorted run.
+    fn spill(&mut self) -> io::Result<()> {
+        self.sort();
+        let run = try!(Run::write(self.tuples.drain(..).map(Ok)));
+        debug!("Sorter::spill: wrote {} bytes of tuples to {:?}", self.size, run.path);
+        self.size = 0;
+        self.runs.push((0, run));
+
+        // Levels never increase along the runs, so the last `fan_in` runs have the same
+        // level if the first and last of them do.
+        loop {
+            let num_runs = self.runs.len();
+            if num_runs < self.fan_in ||
+               self.runs[num_runs - self.fan_in].0 != self.runs[num_runs - 1].0 {
+                return Ok(());
+            }
+            try!(self.merge_last(self.fan_in));
+        }
+    }
+
+    /// Merge the last `num_runs` runs into one. Merging adjacent runs keeps the sort stable.
+    fn merge_last(&mut self, num_runs: usize) -> io::Result<()> {
+        let start = self.runs.len() - num_runs;
+        let level = self.runs[start..].iter().map(|&(level, _)| level).max().unwrap_or(0) + 1;
+        let runs = self.runs.drain(start..).map(|(_, run)| run).collect();
+        let merged = try!(Run::write(try!(Merge::new(runs, self.order.clone()))));
+        self.runs.push((level, merged));
+        Ok(())
+    }
+
+    /// Finish adding tuples, and iterate over them in order.
+    pub fn finish(mut self) -> io::Result<Sorted> {
+        if self.runs.is_empty() {
+            self.sort();
+            let tuples = mem::take(&mut self.tuples);
+            return Ok(Sorted { tuples: tuples.into_iter(), merge: None });
+        }
+        if !self.tuples.is_empty() {
+            try!(self.spill());
+        }
+        // Merge the smallest runs, at the end, until the rest can be merged in one go.
+        while self.runs.len() > self.fan_in {
+            let num_runs = cmp::min(self.fan_in, self.runs.len() - self.fan_in + 1);
+            try!(self.merge_last(num_runs));
+        }
+        let runs = self.runs.drain(..).map(|(_, run)| run).collect();
+        let merge = try!(Merge::new(runs, self.order.clone()));
+        Ok(Sorted { tuples: vec![].into_iter(), merge: Some(merge) })
+    }
+}
+
+/// Sorted tuples, either in memory or merged from runs.
+pub struct Sorted {
+    tuples: vec::IntoIter<Tuple>,
+    merge: Option<Merge>,
+}
+
+impl Iterator for Sorted {
+    type Item = io::Result<Tuple>;
+
+    fn next(&mut self) -> Option<io::Result<Tuple>> {
+        match self.merge {
+            Some(ref mut merge) => merge.next(),
+            None => self.tuples.next().map(Ok)
+        }
+    }
+}
+
+/// Next tuple from one of the runs being merged, ordered so that the heap of heads yields
+/// the least tuple first, and the tuple from the earliest run among equals.
+struct Head {
+    tuple: Tuple,
+    run: usize,
+    order: Rc<Order>,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        self.order.compare(&other.tuple, &self.tuple).then(other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+/// Merge of sorted runs, which are removed once the merge is dropped.
+struct Merge {
+    order: Rc<Order>,
+    readers: Vec<BufReader<File>>,
+    heads: BinaryHeap<Head>,
+    /// Runs being merged, kept only to remove their files after the readers are closed.
+    _runs: Vec<Run>,
+}
+
+impl Merge {
+    fn new(runs: Vec<Run>, order: Rc<Order>) -> io::Result<Merge> {
+        let mut readers = Vec::with_capacity(runs.len());
+        let mut heads = BinaryHeap::with_capacity(runs.len());
+        for (i, run) in runs.iter().enumerate() {
+            let mut reader = BufReader::new(try!(File::open(&run.path)));
+            if let Some(tuple) = try!(read_tuple(&mut reader)) {
+                heads.push(Head { tuple: tuple, run: i, order: order.clone() });
+            }
+            readers.push(reader);
+        }
+        Ok(Merge { order: order, readers: readers, heads: heads, _runs: runs })
+    }
+}
+
+impl Iterator for Merge {
+    type Item = io::Result<Tuple>;
+
+    fn next(&mut self) -> Option<io::Result<Tuple>> {
+        let head = match self.heads.pop() {
+            Some(head) => head,
+            None => return None
+        };
+        match read_tuple(&mut self.readers[head.run]) {
+            Ok(Some(tuple)) => {
+                self.heads.push(Head { tuple: tuple, run: head.run, order: self.order.clone() });
+            }
+            Ok(None) => (),
+            Err(e) => return Some(Err(e))
+        }
+        Some(Ok(head.tuple))
+    }
+}
+
+/// Sorted run of tuples in a temporary file, which is removed along with the run.
+struct Run {
+    path: PathBuf,
+}
+
+impl Run {
+    /// Write tuples to a new temporary file, each as its length and then its serialised bytes.
+    fn write<I: Iterator<Item = io::Result<Tuple>>>(tuples: I) -> io::Result<Run> {
+        let name = format!("malh-sort-{}-{:016x}.run", process::id(), rand::random::<u64>());
+        let run = Run { path: env::temp_dir().join(name) };
+        let mut writer = BufWriter::new(try!(File::create(&run.path)));
+        for tuple in tuples {
+            let bytes = try!(tuple).serialise();
+            try!(write_u32(&mut writer, bytes.len() as u32));
+            try!(writer.write_all(&bytes));
+        }
+        try!(writer.flush());
+        Ok(run)
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("unable to remove sorted run {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Read the next tuple from a run, if there is one.
+fn read_tuple(reader: &mut BufReader<File>) -> io::Result<Option<Tuple>> {
+    let len = match read_u32(&mut *reader) {
+        Ok(len) => len as usize,
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e)
+    };
+    let mut bytes = vec![0; len];
+    try!(reader.read_exact(&mut bytes));
+    Tuple::deserialise(&bytes).map(Some)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed tuple in sorted run"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schema::Schema;
+    use tuple::Tuple;
+
+    #[test]
+    fn parse_sort_keys() {
+        let schema = Schema::parse("name,age:int").unwrap();
+        let key = |attr: usize, descending: bool| SortKey { attr: attr, descending: descending };
+        assert_eq!(SortKey::parse_list("age DESC, name", &schema), Ok(vec![key(1, true), key(0, false)]));
+        assert_eq!(SortKey::parse_list("0 asc", &schema), Ok(vec![key(0, false)]));
+        assert_eq!(SortKey::parse_list("height", &schema),
+                   Err(ParseError::UnknownAttribute("height".to_string())));
+        assert_eq!(SortKey::parse_list("2", &schema), Err(ParseError::UnknownAttribute("2".to_string())));
+        assert_eq!(SortKey::parse_list("age down", &schema),
+                   Err(ParseError::InvalidKey("age down".to_string())));
+        assert_eq!(SortKey::parse_list("age,", &schema), Err(ParseError::InvalidKey("".to_string())));
+    }
+
+    #[test]
+    fn compare_by_keys() {
+        let schema = Schema::parse("name,age:int").unwrap();
+        let tuple = |input: &str| Tuple::parse(input, 2).unwrap();
+        let keys = SortKey::parse_list("age,name desc", &schema).unwrap();
+        assert_eq!(compare_tuples(&tuple("a,9"), &tuple("a,10"), &keys, &schema), Ordering::Less);
+        assert_eq!(compare_tuples(&tuple("a,\\N"), &tuple("a,-1"), &keys, &schema), Ordering::Less);
+        assert_eq!(compare_tuples(&tuple("a,10"), &tuple("b,010"), &keys, &schema), Ordering::Greater);
+        assert_eq!(compare_tuples(&tuple("a,1"), &tuple("a,01"), &keys, &schema), Ordering::Equal);
+    }
+
+    #[test]
+    fn spill_and_merge_runs() {
+        let schema = Schema::parse("id:int,group:int").unwrap();
+        let keys = SortKey::parse_list("group desc", &schema).unwrap();
+        let tuples: Vec<Tuple> = (0..500)
+            .map(|i| Tuple::parse(&format!("{},{}", i, (i * 7) % 10), 2).unwrap())
+            .collect();
+        let mut expected = tuples.clone();
+        expected.sort_by(|a, b| compare_tuples(a, b, &keys, &schema));
+
+        for &budget in &[DEFAULT_MEMORY_BUDGET, 1000, 0] {
+            let mut sorter = Sorter::new(schema.clone(), keys.clone(), budget);
+            for tuple in &tuples {
+                sorter.add(tuple.clone()).unwrap();
+            }
+            let num_runs = sorter.num_runs();
+            assert_eq!(num_runs == 0, budget == DEFAULT_MEMORY_BUDGET, "{}", budget);
+            let sorted = sorter.finish().unwrap();
+            let paths: Vec<PathBuf> = sorted.merge.as_ref()
+                .map_or(vec![], |merge| merge._runs.iter().map(|run| run.path.clone()).collect());
+            assert_eq!(paths.is_empty(), budget == DEFAULT_MEMORY_BUDGET, "{}", budget);
+            assert!(paths.iter().all(|path| path.is_file()));
+            let sorted: Vec<Tuple> = sorted.map(|t| t.unwrap()).collect();
+            // Stable, so ties stay in the order they were added.
+            assert_eq!(sorted, expected, "{}", budget);
+            assert!(paths.iter().all(|path| !path.exists()));
+        }
+    }
+
+    #[test]
+    fn merge_with_bounded_fan_in() {
+        let schema = Schema::parse("id:int,group:int").unwrap();
+        let keys = SortKey::parse_list("group", &schema).unwrap();
+        let tuples: Vec<Tuple> = (0..500)
+            .map(|i| Tuple::parse(&format!("{},{}", i, (i * 13) % 7), 2).unwrap())
+            .collect();
+        let mut expected = tuples.clone();
+        expected.sort_by(|a, b| compare_tuples(a, b, &keys, &schema));
+
+        // A budget of 0 still puts one tuple in each run, and runs are merged 3 at a time.
+        let mut sorter = Sorter::with_fan_in(schema.clone(), keys.clone(), 0, 3);
+        let mut max_runs = 0;
+        for tuple in &tuples {
+            sorter.add(tuple.clone()).unwrap();
+            max_runs = max_runs.max(sorter.num_runs());
+        }
+        // At most 2 runs of each level, and 500 tuples take fewer than 3^6 runs.
+        assert!(max_runs <= 2 * 6, "{}", max_runs);
+        let sorted = sorter.finish().unwrap();
+        assert!(sorted.merge.as_ref().unwrap()._runs.len() <= 3);
+        let sorted: Vec<Tuple> = sorted.map(|t| t.unwrap()).collect();
+        assert_eq!(sorted, expected);
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+
+use util::*;
+
+/// Policy deciding when a relation splits a bucket (grows) and merges one back (shrinks).
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum SplitPolicy {
+    /// Split once there are `page_size / (10 * num_attrs)` tuples per data page (the default).
+    #[default]
+    TupleCount,
+    /// Split once the stored tuple data exceeds this many bytes per data page.
+    LoadFactor(u32),
+    /// Split whenever an insert has to add an overflow page (uncontrolled splitting).
+    OnOverflow,
+    /// Never split or merge buckets.
+    Never,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum ParseError {
+    UnknownPolicy,
+    InvalidLoadFactor,
+}
+
+impl SplitPolicy {
+    /// Parse a policy: `tuples`, `load=<bytes per page>`, `overflow` or `never`.
+    pub fn parse(input: &str) -> Result<SplitPolicy, ParseError> {
+        match input {
+            "tuples" => Ok(SplitPolicy::TupleCount),
+            "overflow" => Ok(SplitPolicy::OnOverflow),
+            "never" => Ok(SplitPolicy::Never),
+            _ if input.starts_with("load=") => {
+                match input["load=".len()..].parse() {
+                    Ok(0) | Err(_) => Err(ParseError::InvalidLoadFactor),
+                    Ok(bytes) => Ok(SplitPolicy::LoadFactor(bytes)),
+                }
+            }
+            _ => Err(ParseError::UnknownPolicy)
+        }
+    }
+
+    pub fn write(&self, f: &File) -> io::Result<()> {
+        let (tag, param) = match *self {
+            SplitPolicy::TupleCount => (0, 0),
+            SplitPolicy::LoadFactor(bytes) => (1, bytes),
+            SplitPolicy::OnOverflow => (2, 0),
+            SplitPolicy::Never => (3, 0),
+        };
+        try!(write_u8(f, tag));
+        write_u32(f, param)
+    }
+
+    pub fn read(f: &File) -> io::Result<SplitPolicy> {
+        let tag = try!(read_u8(f));
+        let param = try!(read_u32(f));
+        match tag {
+            0 => Ok(SplitPolicy::TupleCount),
+            1 => Ok(SplitPolicy::LoadFactor(param)),
+            2 => Ok(SplitPolicy::OnOverflow),
+            3 => Ok(SplitPolicy::Never),
+            _ => Err(io::Error::new(ErrorKind::InvalidData, "unknown split policy"))
+        }
+    }
+}
+
+impl fmt::Display for SplitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SplitPolicy::TupleCount => write!(f, "tuples"),
+            SplitPolicy::LoadFactor(bytes) => write!(f, "load={}", bytes),
+            SplitPolicy::OnOverflow => write!(f, "overflow"),
+            SplitPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SplitPolicy, ParseError};
+
+    #[test]
+    fn parse_policies() {
+        assert_eq!(SplitPolicy::parse("tuples"), Ok(SplitPolicy::TupleCount));
+        assert_eq!(SplitPolicy::parse("load=800"), Ok(SplitPolicy::LoadFactor(800)));
+        assert_eq!(SplitPolicy::parse("overflow"), Ok(SplitPolicy::OnOverflow));
+        assert_eq!(SplitPolicy::parse("never"), Ok(SplitPolicy::Never));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(SplitPolicy::parse("sometimes"), Err(ParseError::UnknownPolicy));
+        assert_eq!(SplitPolicy::parse("load="), Err(ParseError::InvalidLoadFactor));
+        assert_eq!(SplitPolicy::parse("load=0"), Err(ParseError::InvalidLoadFactor));
+        assert_eq!(SplitPolicy::parse("load=-5"), Err(ParseError::InvalidLoadFactor));
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        let policies = [
+            SplitPolicy::TupleCount,
+            SplitPolicy::LoadFactor(512),
+            SplitPolicy::OnOverflow,
+            SplitPolicy::Never
+        ];
+        for &policy in policies.iter() {
+            assert_eq!(SplitPolicy::parse(&policy.to_string()), Ok(policy));
+        }
+    }
+}
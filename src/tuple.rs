@@ -1,15 +1,205 @@
 use choice_vec::ChoiceVec;
+use schema::Schema;
+use pattern::strip_like;
 use util::*;
+use std::io::{Cursor, Read};
 use std::str;
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Tuple {
-    pub values: Vec<String>
+    /// The tuple's values, with None for NULL.
+    pub values: Vec<Option<String>>
+}
+
+/// Length written in place of a serialised value's length to mark it as NULL.
+pub const NULL_LEN: u32 = 0xffffffff;
+
+/// A comma-separated field of a tuple or query, as written on the command line.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Field {
+    /// A bare `?`, which matches any value.
+    Wildcard,
+    /// A bare `\N`, which stands for NULL.
+    Null,
+    Value(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SyntaxError {
+    UnterminatedQuote,
+    TrailingBackslash,
+    /// A `?` that is neither a whole field, quoted, nor escaped.
+    StrayQuestionMark,
+    /// A `\N` that isn't a whole field.
+    StrayNull,
+}
+
+/// Split a line into comma-separated fields, undoing any quoting and escaping.
+///
+/// Text between double quotes is taken literally, except for backslash escapes.
+/// The escapes `\0`, `\n`, `\r` and `\t` stand for NUL, newline, carriage return and tab,
+/// and a backslash before any other character (such as `,`, `?`, `"` or `\`) stands for
+/// that character, except that a field of just `\N` is NULL.
+pub fn split_fields(s: &str) -> Result<Vec<Field>, SyntaxError> {
+    split_raw(s).into_iter().map(parse_field).collect()
+}
+
+/// Split a line at the commas that aren't quoted or escaped, leaving the fields as written.
+pub fn split_raw(s: &str) -> Vec<&str> {
+    let mut fields = vec![];
+    let mut rest = s;
+    while let Some(i) = find_unquoted(rest, ',') {
+        fields.push(&rest[..i]);
+        rest = &rest[i + 1..];
+    }
+    fields.push(rest);
+    fields
+}
+
+/// Find the byte offset of the first occurrence of `target` that isn't quoted or escaped.
+pub fn find_unquoted(s: &str, target: char) -> Option<usize> {
+    find_unquoted_where(s, |c| c == target)
+}
+
+/// Find the byte offset of the first character matching `pred` that isn't quoted or escaped.
+pub fn find_unquoted_where<P: Fn(char) -> bool>(s: &str, pred: P) -> Option<usize> {
+    let mut quoted = false;
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); }
+            '"' => quoted = !quoted,
+            c if pred(c) && !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Split a field into the words separated by whitespace that isn't quoted or escaped,
+/// leaving the words as written.
+pub fn split_words(s: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let end = find_unquoted_where(rest, char::is_whitespace).unwrap_or(rest.len());
+        words.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    words
+}
+
+/// Parse a single field as written, undoing its quoting and escaping.
+pub fn parse_field(s: &str) -> Result<Field, SyntaxError> {
+    let mut value = String::new();
+    // A bare `?` or `\N` at the start of the field, which must be all there is to it.
+    let mut marker = None;
+    let mut started = false;
+    let mut quoted = false;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match marker {
+            Some(Field::Null) => return Err(SyntaxError::StrayNull),
+            Some(_) => return Err(SyntaxError::StrayQuestionMark),
+            None => {}
+        }
+        match c {
+            '\\' => {
+                let escaped = try!(chars.next().ok_or(SyntaxError::TrailingBackslash));
+                if escaped != 'N' {
+                    value.push(unescape_char(escaped));
+                } else if started {
+                    return Err(SyntaxError::StrayNull);
+                } else {
+                    marker = Some(Field::Null);
+                }
+            }
+            '"' => quoted = !quoted,
+            '?' if !quoted => {
+                if started {
+                    return Err(SyntaxError::StrayQuestionMark);
+                }
+                marker = Some(Field::Wildcard);
+            }
+            c => value.push(c)
+        }
+        started = true;
+    }
+    if quoted {
+        return Err(SyntaxError::UnterminatedQuote);
+    }
+    Ok(marker.unwrap_or(Field::Value(value)))
+}
+
+/// The character that a backslash before `c` stands for.
+pub fn unescape_char(c: char) -> char {
+    match c {
+        '0' => '\0',
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c
+    }
+}
+
+/// Escape a value so that `split_fields` reads it back as a single field,
+/// and queries read it back as a value rather than a pattern or part of an expression.
+pub fn escape_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    // A leading `/` or `LIKE` would start a pattern.
+    let escape_first = value.starts_with('/') || strip_like(value).is_some();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\0' => result.push_str("\\0"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            ',' | '?' | '"' | '\\' | '=' | '<' | '>' | '*' | '(' | ')' => {
+                result.push('\\');
+                result.push(c);
+            }
+            c if i == 0 && escape_first => {
+                result.push('\\');
+                result.push(c);
+            }
+            c => result.push(c)
+        }
+    }
+    result
+}
+
+/// Escape the first value of a tuple like `escape_value`, and also so that a query can't
+/// read it as a named condition, like `x is null` or `not x`, by escaping its whitespace.
+pub fn escape_first_value(value: &str) -> String {
+    let escaped = escape_value(value);
+    let words: Vec<&str> = value.split_whitespace().collect();
+    let is_keyword = |i: usize, keywords: &[&str]| {
+        words.get(i).is_some_and(|word| keywords.iter().any(|k| word.eq_ignore_ascii_case(k)))
+    };
+    let reads_as_condition = is_keyword(0, &["not"]) ||
+                             is_keyword(1, &["is", "between", "like", "in"]);
+    if words.len() < 2 || !reads_as_condition {
+        return escaped;
+    }
+    let mut result = String::with_capacity(escaped.len() + words.len());
+    for c in escaped.chars() {
+        if c.is_whitespace() {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
 }
 
 impl Tuple {
-    pub fn hash(&self, choice_vec: &ChoiceVec) -> u32 {
-        let value_hashes: Vec<u32> = self.values.iter().map(|v| hash(v)).collect();
+    /// Compute the tuple's hash, hashing each value as a value of its attribute's type,
+    /// and NULLs as `NULL_HASH`.
+    pub fn hash(&self, choice_vec: &ChoiceVec, schema: &Schema) -> u32 {
+        let value_hashes: Vec<u32> = self.values.iter()
+            .enumerate()
+            .map(|(i, value)| schema.hash_value(i, value.as_ref().map(|v| v.as_str())))
+            .collect();
 
         let mut result = 0;
 
@@ -20,72 +210,276 @@ impl Tuple {
         result
     }
 
-    /// Parse a string into a tuple, validating that it contains no question marks,
-    /// and is the correct length.
+    /// Parse a line of comma-separated values into a tuple, validating that it contains
+    /// no wildcards, and is the correct length. See `split_fields` for quoting and escaping,
+    /// and for writing NULL.
     pub fn parse(s: &str, num_attrs: u32) -> Option<Tuple> {
-        if s.contains('?') || s.contains('\0') {
-            return None;
+        let fields = match split_fields(s) {
+            Ok(fields) => fields,
+            Err(_) => return None
+        };
+        let mut values = vec![];
+        for field in fields {
+            match field {
+                Field::Value(value) => values.push(Some(value)),
+                Field::Null => values.push(None),
+                Field::Wildcard => return None
+            }
         }
-        let t = Tuple::parse_str(s);
-        if t.values.len() == num_attrs as usize {
-            Some(t)
+        if values.len() == num_attrs as usize {
+            Some(Tuple { values: values })
         } else {
             None
         }
     }
 
-    pub fn parse_str(s: &str) -> Tuple {
-        Tuple {
-            values: s.split(',')
-                     .map(|s| s.to_string())
-                     .collect()
-        }
-    }
-
-    pub fn parse_bytes(bytes: &[u8]) -> Tuple {
-        let s = str::from_utf8(bytes).expect("Non UTF8 data in DB");
-        Tuple::parse_str(s)
+    /// Check that a tuple has the right number of values.
+    pub fn is_valid(&self, num_attrs: u32) -> bool {
+        self.values.len() == num_attrs as usize
     }
 
+    /// Format the tuple as comma-separated values, escaped so that `parse` reads it back,
+    /// and a query by position matches it.
     pub fn to_string(&self) -> String {
-        self.values.join(",")
+        let escaped: Vec<String> = self.values.iter().enumerate()
+            .map(|(i, v)| match (i, v.as_ref()) {
+                (_, None) => "\\N".to_string(),
+                (0, Some(v)) => escape_first_value(v),
+                (_, Some(v)) => escape_value(v)
+            })
+            .collect();
+        escaped.join(",")
     }
 
+    /// Serialise the tuple as its number of values, followed by each value prefixed
+    /// by its length in bytes. NULL values are just a length of `NULL_LEN`.
     pub fn serialise(&self) -> Vec<u8> {
-        let mut result = self.to_string().into_bytes();
-        result.push(0); // NUL byte
+        let size = 4 + self.values.iter().map(|v| 4 + v.as_ref().map_or(0, |v| v.len())).sum::<usize>();
+        let mut result = Vec::with_capacity(size);
+        write_u32(&mut result, self.values.len() as u32).unwrap();
+        for value in &self.values {
+            match *value {
+                Some(ref value) => {
+                    write_u32(&mut result, value.len() as u32).unwrap();
+                    result.extend_from_slice(value.as_bytes());
+                }
+                None => write_u32(&mut result, NULL_LEN).unwrap()
+            }
+        }
         result
     }
+
+    /// Parse a tuple serialised by `serialise`, returning None if the bytes are malformed.
+    pub fn deserialise(bytes: &[u8]) -> Option<Tuple> {
+        Tuple::deserialise_attrs(bytes, None)
+    }
+
+    /// Read the values of a tuple serialised by `serialise` into `values`, borrowing them
+    /// rather than copying. Returns false if the bytes are malformed.
+    pub fn read_values<'b>(bytes: &'b [u8], values: &mut Vec<Option<&'b str>>) -> bool {
+        values.clear();
+        let num_values = match read_u32(bytes) {
+            Ok(n) => n,
+            Err(_) => return false
+        };
+        let mut pos = 4;
+        for _ in 0..num_values {
+            let len = match read_u32(&bytes[pos.min(bytes.len())..]) {
+                Ok(len) => len,
+                Err(_) => return false
+            };
+            pos += 4;
+            if len == NULL_LEN {
+                values.push(None);
+                continue;
+            }
+            let value = match bytes.get(pos..pos + len as usize).map(str::from_utf8) {
+                Some(Ok(value)) => value,
+                _ => return false
+            };
+            values.push(Some(value));
+            pos += len as usize;
+        }
+        pos == bytes.len()
+    }
+
+    /// Parse a tuple serialised by `serialise`, reading only the values of the attributes
+    /// marked in `needed`, or all of them if it's None. The others are skipped and left NULL.
+    pub fn deserialise_attrs(bytes: &[u8], needed: Option<&[bool]>) -> Option<Tuple> {
+        let mut cursor = Cursor::new(bytes);
+        let num_values = match read_u32(&mut cursor) {
+            Ok(n) => n,
+            Err(_) => return None
+        };
+        let mut values = vec![];
+        for i in 0..num_values as usize {
+            let len = match read_u32(&mut cursor) {
+                Ok(NULL_LEN) => {
+                    values.push(None);
+                    continue;
+                }
+                Ok(len) => len as usize,
+                Err(_) => return None
+            };
+            let remaining = bytes.len() - cursor.position() as usize;
+            if len > remaining {
+                return None;
+            }
+            if needed.is_some_and(|needed| !needed.get(i).cloned().unwrap_or(false)) {
+                cursor.set_position(cursor.position() + len as u64);
+                values.push(None);
+                continue;
+            }
+            let mut value = vec![0; len];
+            cursor.read_exact(&mut value).unwrap();
+            match String::from_utf8(value) {
+                Ok(value) => values.push(Some(value)),
+                Err(_) => return None
+            }
+        }
+        if cursor.position() as usize != bytes.len() {
+            return None;
+        }
+        Some(Tuple { values: values })
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use query::Query;
+
+    fn values(values: &[&str]) -> Vec<Option<String>> {
+        values.iter().map(|v| Some(v.to_string())).collect()
+    }
 
     #[test]
     fn parse_question_marks() {
         assert!(Tuple::parse("hello?", 1).is_none());
         assert!(Tuple::parse("hello,?", 2).is_none());
+        assert_eq!(Tuple::parse("hello\\?", 1).unwrap().values, values(&["hello?"]));
+        assert_eq!(Tuple::parse("\"?\"", 1).unwrap().values, values(&["?"]));
     }
 
     #[test]
     fn parse() {
-        assert_eq!(Tuple::parse("hello,world", 2).unwrap().values, vec!["hello", "world"]);
-        assert_eq!(Tuple::parse("hello world,,", 3).unwrap().values, vec!["hello world", "", ""]);
+        assert_eq!(Tuple::parse("hello,world", 2).unwrap().values, values(&["hello", "world"]));
+        assert_eq!(Tuple::parse("hello world,,", 3).unwrap().values, values(&["hello world", "", ""]));
+    }
+
+    #[test]
+    fn parse_quoted_and_escaped() {
+        assert_eq!(Tuple::parse("\"a,b\",c", 2).unwrap().values, values(&["a,b", "c"]));
+        assert_eq!(Tuple::parse("a\\,b,c", 2).unwrap().values, values(&["a,b", "c"]));
+        assert_eq!(Tuple::parse("\"say \\\"hi\\\"\"", 1).unwrap().values, values(&["say \"hi\""]));
+        assert_eq!(Tuple::parse("nul\\0,\\\\", 2).unwrap().values, values(&["nul\0", "\\"]));
+        assert!(Tuple::parse("\"unterminated", 1).is_none());
+        assert!(Tuple::parse("trailing\\", 1).is_none());
+    }
+
+    #[test]
+    fn split_wildcards() {
+        assert_eq!(split_fields("?,a"), Ok(vec![Field::Wildcard, Field::Value("a".to_string())]));
+        assert_eq!(split_fields("??"), Err(SyntaxError::StrayQuestionMark));
+        assert_eq!(split_fields("?a"), Err(SyntaxError::StrayQuestionMark));
+        assert_eq!(split_fields("a?"), Err(SyntaxError::StrayQuestionMark));
+        assert_eq!(split_fields("\"\"?"), Err(SyntaxError::StrayQuestionMark));
+    }
+
+    #[test]
+    fn parse_nulls() {
+        let tuple = Tuple::parse("\\N,,\\\\N,\"N\"", 4).unwrap();
+        assert_eq!(tuple.values, vec![None, Some("".to_string()), Some("\\N".to_string()), Some("N".to_string())]);
+        assert_eq!(tuple.to_string(), "\\N,,\\\\N,N");
+        assert_eq!(Tuple::parse(&tuple.to_string(), 4), Some(tuple));
+        assert_eq!(split_fields("a\\N"), Err(SyntaxError::StrayNull));
+        assert_eq!(split_fields("\\Na"), Err(SyntaxError::StrayNull));
+        assert_eq!(split_fields("\\N?"), Err(SyntaxError::StrayNull));
+        assert_eq!(split_fields("\"\\N\""), Err(SyntaxError::StrayNull));
+    }
+
+    #[test]
+    fn split_raw_fields() {
+        assert_eq!(split_raw("a,\"b,c\",d\\,e"), vec!["a", "\"b,c\"", "d\\,e"]);
+        assert_eq!(split_raw(""), vec![""]);
+        assert_eq!(find_unquoted("\"a=b\"\\==", '='), Some(7));
+        assert_eq!(find_unquoted("a\\=b", '='), None);
+        assert_eq!(split_words(" a  \"b c\"\\ d\te "), vec!["a", "\"b c\"\\ d", "e"]);
     }
 
     #[test]
-    fn serialise_parse_bytes() {
-        use std::str;
+    fn validity() {
+        assert!(Tuple::parse("hello,world", 2).unwrap().is_valid(2));
+        assert!(!Tuple::parse("hello,world", 2).unwrap().is_valid(3));
+    }
+
+    #[test]
+    fn to_string_parse() {
+        let strings = ["plain", "a,b", "why?", "x=y", "a<b>c", "star*", "/slashes/", "like this", "\"quoted\"", "back\\slash", "nul\0", "line\nbreak", ""];
+        let tuple = Tuple { values: values(&strings) };
+        assert_eq!(Tuple::parse(&tuple.to_string(), strings.len() as u32), Some(tuple));
+        assert_eq!(Tuple::parse("hello,world", 2).unwrap().to_string(), "hello,world");
+
+        // First values that read like named conditions still match as values.
+        let schema = Schema::untyped(2);
+        let firsts = ["x is null", "a BETWEEN b AND c", "x Like y", "a in (b)", "not x", "NOT (x)",
+                      "hello world", "is null"];
+        for first in &firsts {
+            let tuple = Tuple { values: values(&[first, "y z"]) };
+            let printed = tuple.to_string();
+            assert_eq!(Tuple::parse(&printed, 2).as_ref(), Some(&tuple), "{}", printed);
+            let query = Query::parse_with_schema(&printed, &schema).unwrap();
+            assert!(query.matches_tuple(&tuple, &schema), "{}", printed);
+        }
+        let tuple = Tuple { values: values(&["hello world", "is null"]) };
+        assert_eq!(tuple.to_string(), "hello world,is null");
+    }
+
+    #[test]
+    fn serialise_deserialise() {
         let data = [
-            "hello,world",
-            "wow",
-            "rust,haskell,c,java,python,lisp"
+            values(&["hello", "world"]),
+            values(&["wow"]),
+            values(&["rust,haskell", "c?", "", "nul\0byte"]),
+            vec![None, Some("".to_string()), None]
         ];
-        for &tuple in &data {
-            let serialised = Tuple::parse_bytes(tuple.as_bytes()).serialise();
-            let roundtrip = str::from_utf8(&serialised[..serialised.len() - 1]).unwrap();
-            assert_eq!(tuple, roundtrip);
+        for values in &data {
+            let tuple = Tuple { values: values.clone() };
+            assert_eq!(Tuple::deserialise(&tuple.serialise()), Some(tuple));
         }
     }
+
+    #[test]
+    fn deserialise_malformed() {
+        let serialised = Tuple { values: values(&["hello"]) }.serialise();
+        assert_eq!(Tuple::deserialise(&serialised[..serialised.len() - 1]), None);
+        assert_eq!(Tuple::deserialise(&[serialised.clone(), vec![0]].concat()), None);
+        assert_eq!(Tuple::deserialise(b""), None);
+        assert_eq!(Tuple::deserialise(&[0, 0, 0, 1, 0, 0, 0, 1, 0xff]), None);
+    }
+
+    #[test]
+    fn read_values_without_copying() {
+        let tuple = Tuple { values: vec![Some("a".to_string()), None, Some("".to_string())] };
+        let serialised = tuple.serialise();
+        let mut values = vec![Some("stale")];
+        assert!(Tuple::read_values(&serialised, &mut values));
+        assert_eq!(values, vec![Some("a"), None, Some("")]);
+        assert!(!Tuple::read_values(&serialised[..serialised.len() - 1], &mut values));
+        let trailing = [serialised.clone(), vec![0]].concat();
+        assert!(!Tuple::read_values(&trailing, &mut values));
+        assert!(!Tuple::read_values(&[0, 0, 0, 1, 0, 0, 0, 1, 0xff], &mut values));
+        assert!(!Tuple::read_values(b"", &mut values));
+    }
+
+    #[test]
+    fn deserialise_some_attrs() {
+        let tuple = Tuple { values: vec![Some("a".to_string()), None, Some("c".to_string())] };
+        let serialised = tuple.serialise();
+        let tuple = Tuple::deserialise_attrs(&serialised, Some(&[false, true, true])).unwrap();
+        assert_eq!(tuple.values, vec![None, None, Some("c".to_string())]);
+        let tuple = Tuple::deserialise_attrs(&serialised, Some(&[true])).unwrap();
+        assert_eq!(tuple.values, vec![Some("a".to_string()), None, None]);
+        assert_eq!(Tuple::deserialise_attrs(&serialised[..serialised.len() - 1], Some(&[])), None);
+    }
 }
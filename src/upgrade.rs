@@ -0,0 +1,474 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::io::ErrorKind::{InvalidData, Other};
+use std::path::Path;
+use std::str;
+
+use relation::*;
+use page::{DEFAULT_PAGE_SIZE, PAGE_HEADER_SIZE, RECORD_HEADER_SIZE, NO_OVFLOW, CorruptPage};
+use choice_vec::ChoiceVec;
+use split_policy::SplitPolicy;
+use schema::Schema;
+use free_list::FreeList;
+use tuple::Tuple;
+use query::Query;
+use util::*;
+
+/// Magic number at the start of every info file.
+pub const INFO_MAGIC: &[u8; 4] = b"MALH";
+
+/// Version of the on-disk format written by this build.
+///
+/// History:
+/// 0. The original format: no magic number or version (info file is exactly `V0_INFO_SIZE`
+///    bytes), and pages of `V0_PAGE_SIZE` bytes with a 12-byte header and no checksum.
+/// 1. Magic number and version added to the info file, which also holds the number of
+///    bytes of tuple data, the split policy and the overflow free list. Pages checksummed.
+/// 2. Page size stored in the info file, after the number of attributes.
+/// 3. Blob file for large tuples, with its free list at the end of the info file.
+/// 4. Length-prefixed binary records, replacing comma-joined, NUL-terminated strings.
+/// 5. Slotted pages, with a directory of records.
+/// 6. Attribute names and types, at the end of the info file.
+/// 7. Typed values hashed by their canonical form.
+/// 8. NULL values, recorded with a length of `NULL_LEN`.
+/// 9. Epoch of the relation's record IDs, at the end of the info file.
+pub const FORMAT_VERSION: u32 = 9;
+
+/// Size of the header holding the magic number and version.
+pub const INFO_HEADER_SIZE: usize = 8;
+
+/// Size of a version 0 info file, which has no header to identify it.
+const V0_INFO_SIZE: u64 = 185;
+
+/// Size of a version 0 page, which was fixed.
+const V0_PAGE_SIZE: u32 = 1024;
+
+/// Size of a version 0 page header: free offset, overflow page ID and number of tuples.
+const V0_PAGE_HEADER_SIZE: usize = 12;
+
+/// Errors for info files that this build can't open as they are.
+#[derive(Debug)]
+pub enum FormatError {
+    /// The info file doesn't belong to a relation, or is from a format version
+    /// too old to identify.
+    NotARelation(String),
+    /// The relation uses an older format version, and must be upgraded.
+    NeedsUpgrade(u32),
+    /// The relation was written by a newer build, using an unknown format version.
+    UnknownVersion(u32),
+    /// An upgrade stopped part way through replacing the relation's files.
+    UnfinishedUpgrade,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FormatError::NotARelation(ref file) => {
+                write!(f, "{} is not a relation info file", file)
+            }
+            FormatError::NeedsUpgrade(version) => {
+                write!(f, "relation uses format version {}, but version {} is required; \
+                           run upgrade on it first", version, FORMAT_VERSION)
+            }
+            FormatError::UnknownVersion(version) => {
+                write!(f, "relation uses unknown format version {} (newest known is {})",
+                       version, FORMAT_VERSION)
+            }
+            FormatError::UnfinishedUpgrade => {
+                write!(f, "relation has an unfinished upgrade; run upgrade on it to finish it")
+            }
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+fn format_error(e: FormatError) -> io::Error {
+    io::Error::new(InvalidData, e)
+}
+
+/// Write the header for the current format version.
+pub fn write_header(mut f: &File) -> io::Result<()> {
+    try!(f.write_all(INFO_MAGIC));
+    write_u32(f, FORMAT_VERSION)
+}
+
+/// Read the header from the start of an info file, failing unless the
+/// format version is the current one.
+pub fn check_header(f: &File, file_name: &str) -> io::Result<()> {
+    check_version(f, file_name, FORMAT_VERSION)
+}
+
+/// Read the header from the start of an info file, failing unless the
+/// format version is `version`.
+pub fn check_version(f: &File, file_name: &str, version: u32) -> io::Result<()> {
+    match try!(read_version(f, file_name)) {
+        v if v == version => Ok(()),
+        v if v < FORMAT_VERSION => Err(format_error(FormatError::NeedsUpgrade(v))),
+        v => Err(format_error(FormatError::UnknownVersion(v)))
+    }
+}
+
+/// Read the format version stored in a relation's info file.
+pub fn stored_version(name: &str) -> io::Result<u32> {
+    let file_name = info_file_name(name);
+    read_version(&try!(File::open(&file_name)), &file_name)
+}
+
+/// Read the format version of an info file, leaving the file positioned after the header.
+fn read_version(mut f: &File, file_name: &str) -> io::Result<u32> {
+    let mut magic = [0; 4];
+    let has_magic = f.read_exact(&mut magic).is_ok() && &magic == INFO_MAGIC;
+    if has_magic {
+        return read_u32(f);
+    }
+    // Without a header, all we can go on for version 0 is the size of the file.
+    let file_length = try!(f.metadata()).len();
+    if file_length == V0_INFO_SIZE {
+        Ok(0)
+    } else {
+        Err(format_error(FormatError::NotARelation(file_name.to_string())))
+    }
+}
+
+/// Fail if an upgrade stopped while replacing the relation's files, which may then be a mix
+/// of old and new ones.
+pub fn check_swap_finished(name: &str) -> io::Result<()> {
+    if Path::new(&swap_file_name(name)).is_file() {
+        return Err(format_error(FormatError::UnfinishedUpgrade));
+    }
+    Ok(())
+}
+
+/// Upgrade a relation to the current format version, returning the version it had before.
+pub fn upgrade(name: &str) -> io::Result<u32> {
+    try!(finish_swap(name));
+    let file_name = info_file_name(name);
+    let old_version = try!(read_version(&try!(File::open(&file_name)), &file_name));
+    if old_version > FORMAT_VERSION {
+        return Err(format_error(FormatError::UnknownVersion(old_version)));
+    }
+    // Some steps jump straight to the current version, so check where each one ends up.
+    let mut version = old_version;
+    while version < FORMAT_VERSION {
+        info!("Upgrading relation {} from format version {}", name, version);
+        try!(upgrade_from(name, version));
+        version = try!(read_version(&try!(File::open(&file_name)), &file_name));
+    }
+    Ok(old_version)
+}
+
+/// Upgrade a relation from `version` to a newer one.
+fn upgrade_from(name: &str, version: u32) -> io::Result<()> {
+    match version {
+        1 => upgrade_v1(name),
+        2 => upgrade_v2(name),
+        0 | 3 | 4 => rebuild(name, version),
+        5 => upgrade_v5(name),
+        6 => upgrade_v6(name),
+        7 => upgrade_v7(name),
+        8 => upgrade_v8(name),
+        _ => unreachable!("no upgrade from format version {}", version)
+    }
+}
+
+/// Version 1 to 2: record the page size, which was always the default.
+fn upgrade_v1(name: &str) -> io::Result<()> {
+    let info = try!(read_info(name));
+    // Header, then the number of attributes.
+    let (start, rest) = info.split_at(INFO_HEADER_SIZE + 4);
+    let mut new_info = vec![];
+    try!(new_info.write_all(INFO_MAGIC));
+    try!(write_u32(&mut new_info, 2));
+    new_info.extend_from_slice(&start[INFO_HEADER_SIZE..]);
+    try!(write_u32(&mut new_info, DEFAULT_PAGE_SIZE));
+    new_info.extend_from_slice(rest);
+    replace_file(&info_file_name(name), &new_info)
+}
+
+/// Version 2 to 3: create an empty blob file, and its free list.
+fn upgrade_v2(name: &str) -> io::Result<()> {
+    let info = try!(read_info(name));
+    let mut new_info = vec![];
+    try!(new_info.write_all(INFO_MAGIC));
+    try!(write_u32(&mut new_info, 3));
+    new_info.extend_from_slice(&info[INFO_HEADER_SIZE..]);
+    let free_list = FreeList::new();
+    try!(write_u32(&mut new_info, free_list.head));
+    try!(write_u32(&mut new_info, free_list.len));
+
+    try!(File::create(blob_file_name(name)));
+    replace_file(&info_file_name(name), &new_info)
+}
+
+/// Version 5 to 6: give the attributes the default names and the text type.
+fn upgrade_v5(name: &str) -> io::Result<()> {
+    let info = try!(read_info(name));
+    let num_attrs = try!(read_u32(&info[INFO_HEADER_SIZE..]));
+    let mut new_info = vec![];
+    try!(new_info.write_all(INFO_MAGIC));
+    try!(write_u32(&mut new_info, 6));
+    new_info.extend_from_slice(&info[INFO_HEADER_SIZE..]);
+    try!(Schema::untyped(num_attrs).write(&mut new_info));
+    replace_file(&info_file_name(name), &new_info)
+}
+
+/// Version 6 to 7: move tuples whose typed values now hash differently.
+///
+/// Rewriting every tuple moves each one to the bucket for its new hash, and the update's
+/// commit writes the info file with the new version.
+fn upgrade_v6(name: &str) -> io::Result<()> {
+    let mut r = try!(Relation::open_version(name, Writing, 6));
+    let num_attrs = r.num_attrs;
+    try!(r.update(&Query::wildcard(num_attrs), |_| ()));
+    Ok(())
+}
+
+/// Version 7 to 8: bump the version, as no version 7 value has a length of `NULL_LEN`.
+fn upgrade_v7(name: &str) -> io::Result<()> {
+    let info = try!(read_info(name));
+    let mut new_info = vec![];
+    try!(new_info.write_all(INFO_MAGIC));
+    try!(write_u32(&mut new_info, 8));
+    new_info.extend_from_slice(&info[INFO_HEADER_SIZE..]);
+    replace_file(&info_file_name(name), &new_info)
+}
+
+/// Version 8 to 9: start the relation's record IDs at epoch 0.
+fn upgrade_v8(name: &str) -> io::Result<()> {
+    let info = try!(read_info(name));
+    let mut new_info = vec![];
+    try!(new_info.write_all(INFO_MAGIC));
+    try!(write_u32(&mut new_info, 9));
+    new_info.extend_from_slice(&info[INFO_HEADER_SIZE..]);
+    try!(write_u32(&mut new_info, 0));
+    replace_file(&info_file_name(name), &new_info)
+}
+
+/// Versions 0, 3 and 4 to current: rebuild the relation, as the layout of every page changes.
+///
+/// The tuples are read from the old files and inserted into a new relation, whose files
+/// then replace the old ones.
+fn rebuild(name: &str, version: u32) -> io::Result<()> {
+    let tmp_name = format!("{}.rebuild", name);
+    if Relation::exists(&tmp_name) {
+        try!(Relation::open(&tmp_name, Writing).and_then(|mut r| r.delete()));
+    }
+
+    let mut info_file = try!(File::open(info_file_name(name)));
+    let (num_attrs, page_size, num_pages, choice_vec, split_policy) = if version == 0 {
+        let num_attrs = try!(read_u32(&info_file));
+        let _depth = try!(read_u8(&info_file));
+        let _split_pointer = try!(read_u32(&info_file));
+        let num_pages = try!(read_u64(&info_file));
+        let _num_tuples = try!(read_u64(&info_file));
+        let choice_vec = try!(ChoiceVec::read(&info_file));
+        (num_attrs, V0_PAGE_SIZE, num_pages, choice_vec, SplitPolicy::default())
+    } else {
+        try!(info_file.seek(SeekFrom::Start(INFO_HEADER_SIZE as u64)));
+        let num_attrs = try!(read_u32(&info_file));
+        let page_size = try!(read_u32(&info_file));
+        let _depth = try!(read_u8(&info_file));
+        let _split_pointer = try!(read_u32(&info_file));
+        let num_pages = try!(read_u64(&info_file));
+        let _num_tuples = try!(read_u64(&info_file));
+        let _num_bytes = try!(read_u64(&info_file));
+        let choice_vec = try!(ChoiceVec::read(&info_file));
+        let split_policy = try!(SplitPolicy::read(&info_file));
+        (num_attrs, page_size, num_pages, choice_vec, split_policy)
+    };
+
+    try!(Relation::new(&tmp_name, Schema::untyped(num_attrs), num_pages, choice_vec, split_policy, page_size)
+        .map_err(|e| io::Error::new(Other, e.to_string())));
+    {
+        let mut new_relation = try!(Relation::open(&tmp_name, Writing));
+        let old_files = OldFiles {
+            name: name.to_string(),
+            version: version,
+            page_size: page_size,
+            data: try!(File::open(data_file_name(name))),
+            ovflow: try!(File::open(ovflow_file_name(name))),
+            // Version 0 relations have no blob file.
+            blob: if version == 0 { None } else { Some(try!(File::open(blob_file_name(name)))) },
+        };
+        try!(new_relation.begin());
+        for bucket in 0..num_pages as u32 {
+            for tuple in try!(old_files.read_bucket(bucket)) {
+                try!(new_relation.insert(tuple));
+            }
+        }
+        try!(new_relation.commit());
+    }
+
+    // The files can't all be replaced at once, so mark the new ones as complete first: if
+    // the swap stops part way, the relation refuses to open until upgrade finishes it.
+    try!(File::create(swap_file_name(name)).and_then(|f| f.sync_all()));
+    finish_swap(name)
+}
+
+/// Move a rebuilt relation's files into place, if a rebuild marked them as complete.
+///
+/// Files already moved are skipped, so a swap that stopped part way can be run again.
+fn finish_swap(name: &str) -> io::Result<()> {
+    let swap_name = swap_file_name(name);
+    if !Path::new(&swap_name).is_file() {
+        return Ok(());
+    }
+    let tmp_name = format!("{}.rebuild", name);
+    for file_name in &[data_file_name, ovflow_file_name, blob_file_name, info_file_name] {
+        if Path::new(&file_name(&tmp_name)).is_file() {
+            try!(fs::rename(file_name(&tmp_name), file_name(name)));
+        }
+    }
+    if Path::new(&wal_file_name(&tmp_name)).is_file() {
+        try!(fs::remove_file(wal_file_name(&tmp_name)));
+    }
+    fs::remove_file(swap_name)
+}
+
+/// Read-only access to the page files of a version 0, 3 or 4 relation, whose pages hold
+/// records packed back to back.
+struct OldFiles {
+    name: String,
+    version: u32,
+    page_size: u32,
+    data: File,
+    ovflow: File,
+    blob: Option<File>,
+}
+
+impl OldFiles {
+    /// Read a page, returning its overflow page ID and used data.
+    fn read_page(&self, mut file: &File, file_name: fn(&str) -> String, page_id: u32)
+    -> io::Result<(u32, Vec<u8>)>
+    {
+        let mut page = vec![0; self.page_size as usize];
+        try!(file.seek(SeekFrom::Start(page_id as u64 * self.page_size as u64)));
+        try!(file.read_exact(&mut page));
+        // Version 0 pages have no checksum, so their header starts with the free offset.
+        let (header, data_start) = if self.version == 0 {
+            (&page[..], V0_PAGE_HEADER_SIZE)
+        } else {
+            if try!(read_u32(&page[..4])) != crc32(&page[4..]) {
+                let error = CorruptPage { file: file_name(&self.name), page_id: page_id };
+                return Err(io::Error::new(InvalidData, error));
+            }
+            (&page[4..], PAGE_HEADER_SIZE as usize)
+        };
+        let free = try!(read_u32(&header[..4])) as usize;
+        let ovflow = try!(read_u32(&header[4..8]));
+        if data_start + free > page.len() {
+            return Err(io::Error::new(InvalidData, "page free offset out of range"));
+        }
+        Ok((ovflow, page[data_start..data_start + free].to_vec()))
+    }
+
+    /// Read all the tuples in a bucket, following its overflow chain.
+    fn read_bucket(&self, bucket: u32) -> io::Result<Vec<Tuple>> {
+        let mut tuples = vec![];
+        let (mut next_page_id, data) = try!(self.read_page(&self.data, data_file_name, bucket));
+        try!(self.read_records(&data, &mut tuples));
+        while next_page_id != NO_OVFLOW {
+            let (ovflow, data) = try!(self.read_page(&self.ovflow, ovflow_file_name, next_page_id));
+            try!(self.read_records(&data, &mut tuples));
+            next_page_id = ovflow;
+        }
+        Ok(tuples)
+    }
+
+    /// Parse the records in a page, adding their tuples to `tuples`.
+    fn read_records(&self, data: &[u8], tuples: &mut Vec<Tuple>) -> io::Result<()> {
+        if self.version <= 3 {
+            self.read_v3_records(data, tuples)
+        } else {
+            self.read_v4_records(data, tuples)
+        }
+    }
+
+    /// Parse NUL-terminated records, which hold either comma-joined values, or (from version
+    /// 3) a blob reference written as a 0xff byte and 24 hex digits.
+    fn read_v3_records(&self, data: &[u8], tuples: &mut Vec<Tuple>) -> io::Result<()> {
+        for record in data.split(|&b| b == 0).filter(|r| !r.is_empty()) {
+            let text = if record[0] == 0xff {
+                let hex = &record[1..];
+                let field = |i: usize| {
+                    hex.get(8 * i..8 * (i + 1))
+                        .and_then(|digits| str::from_utf8(digits).ok())
+                        .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                        .ok_or_else(|| io::Error::new(InvalidData, "malformed blob reference"))
+                };
+                try!(self.read_blob(try!(field(0)), try!(field(1))))
+            } else {
+                record.to_vec()
+            };
+            let text = try!(str::from_utf8(&text).map_err(|e| io::Error::new(InvalidData, e)));
+            tuples.push(Tuple { values: text.split(',').map(|v| Some(v.to_string())).collect() });
+        }
+        Ok(())
+    }
+
+    /// Parse length-prefixed records, which hold either a serialised tuple, or a blob
+    /// reference (first page, length and hash).
+    fn read_v4_records(&self, data: &[u8], tuples: &mut Vec<Tuple>) -> io::Result<()> {
+        let malformed = || io::Error::new(InvalidData, "malformed record");
+        let mut start = 0;
+        while start < data.len() {
+            let (kind, payload) = {
+                let record = &data[start..];
+                if record.len() < RECORD_HEADER_SIZE {
+                    return Err(malformed());
+                }
+                let len = try!(read_u32(&record[1..])) as usize;
+                let payload = try!(record[RECORD_HEADER_SIZE..].get(..len).ok_or_else(malformed));
+                (record[0], payload)
+            };
+            start += RECORD_HEADER_SIZE + payload.len();
+            let tuple = match (kind, payload.len()) {
+                (0, _) => Tuple::deserialise(payload),
+                (1, 12) => {
+                    let blob = try!(self.read_blob(try!(read_u32(payload)), try!(read_u32(&payload[4..]))));
+                    Tuple::deserialise(&blob)
+                }
+                _ => None
+            };
+            tuples.push(try!(tuple.ok_or_else(malformed)));
+        }
+        Ok(())
+    }
+
+    /// Read the contents of a blob, given its first page and length.
+    fn read_blob(&self, first_page: u32, len: u32) -> io::Result<Vec<u8>> {
+        let blob = try!(self.blob.as_ref()
+            .ok_or_else(|| io::Error::new(InvalidData, "blob reference without a blob file")));
+        let mut next_page_id = first_page;
+        let mut contents = vec![];
+        while next_page_id != NO_OVFLOW {
+            let (ovflow, data) = try!(self.read_page(blob, blob_file_name, next_page_id));
+            contents.extend_from_slice(&data);
+            next_page_id = ovflow;
+        }
+        if contents.len() != len as usize {
+            return Err(io::Error::new(InvalidData, "blob length doesn't match its reference"));
+        }
+        Ok(contents)
+    }
+}
+
+fn read_info(name: &str) -> io::Result<Vec<u8>> {
+    let mut info = vec![];
+    try!(try!(File::open(info_file_name(name))).read_to_end(&mut info));
+    Ok(info)
+}
+
+/// Atomically replace the contents of a file, via a temporary file and a rename.
+fn replace_file(file_name: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp_name = format!("{}.tmp", file_name);
+    {
+        let mut tmp_file = try!(File::create(&tmp_name));
+        try!(tmp_file.write_all(contents));
+        try!(tmp_file.sync_all());
+    }
+    fs::rename(tmp_name, file_name)
+}
@@ -47,6 +47,34 @@ pub fn hash<T: Hash + ?Sized>(t: &T) -> u32 {
     s.finish() as u32
 }
 
+/// Table for the CRC-32 (IEEE) checksum, generated at compile time.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// Compute the CRC-32 checksum of some bytes.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0;
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
 /// Grab the ith bit of a value.
 #[inline]
 pub fn bit(i: u8, val: u32) -> u32 {
@@ -130,6 +158,12 @@ mod test {
         assert_eq!(highest_set_bit(1 << 31), 32);
     }
 
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
     #[test]
     fn test_lower_bits() {
         assert_eq!(lower_bits(2, 0b111101), 0b01);
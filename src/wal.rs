@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io::{self, Read, Write, Seek, SeekFrom, Cursor};
+use std::collections::HashSet;
+
+use page::DEFAULT_PAGE_SIZE;
+use util::*;
+
+const BEGIN_RECORD: u8 = b'T';
+/// Begin records written before relations had blob files (`S`), and before page sizes
+/// were configurable (`B`), which implies the default size.
+const V2_BEGIN_RECORD: u8 = b'S';
+const V1_BEGIN_RECORD: u8 = b'B';
+const PAGE_RECORD: u8 = b'P';
+
+/// The kinds of page file that belong to a relation, used to tag logged pages.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum FileKind {
+    Data = 0,
+    Ovflow = 1,
+    Blob = 2,
+}
+
+pub const NUM_FILE_KINDS: usize = 3;
+
+impl FileKind {
+    pub const ALL: [FileKind; NUM_FILE_KINDS] = [FileKind::Data, FileKind::Ovflow, FileKind::Blob];
+
+    fn from_u8(tag: u8) -> Option<FileKind> {
+        FileKind::ALL.get(tag as usize).cloned()
+    }
+}
+
+/// Contents of the record starting a transaction.
+struct BeginRecord<'a> {
+    info: &'a [u8],
+    page_size: u32,
+    /// Length in pages of each page file, if the log records it.
+    lengths: [Option<u32>; NUM_FILE_KINDS],
+}
+
+/// Write-ahead (undo) log for a relation.
+///
+/// When a transaction begins, the contents of the info file, the lengths of the page files
+/// and their page size are written to the log. Before a page that existed at the start of
+/// the transaction is first overwritten (or truncated away), its old contents are appended to
+/// the log. Committing syncs the relation's files and empties the log, so a non-empty log
+/// always describes an incomplete transaction, which can be rolled back by restoring
+/// everything it recorded.
+pub struct Wal {
+    /// The log file itself, absent for relations opened for reading.
+    file: Option<File>,
+    /// Whether a transaction is in progress.
+    active: bool,
+    /// Length in pages of each page file when the transaction began.
+    orig_lengths: [u32; NUM_FILE_KINDS],
+    /// Size of the pages in the page files.
+    page_size: u32,
+    /// Pages whose original contents have already been logged in this transaction.
+    logged: HashSet<(FileKind, u32)>,
+}
+
+impl Wal {
+    pub fn new(file: Option<File>) -> Wal {
+        Wal {
+            file: file,
+            active: false,
+            orig_lengths: [0; NUM_FILE_KINDS],
+            page_size: DEFAULT_PAGE_SIZE,
+            logged: HashSet::new(),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Begin a transaction, logging the current info file and page file lengths
+    /// (indexed by `FileKind`).
+    pub fn begin(&mut self, info_file: &File, lengths: [u32; NUM_FILE_KINDS], page_size: u32)
+    -> io::Result<()>
+    {
+        assert!(!self.active, "transaction already in progress");
+        let mut info = vec![];
+        let mut info_file = info_file;
+        try!(info_file.seek(SeekFrom::Start(0)));
+        try!(info_file.read_to_end(&mut info));
+
+        let mut record = vec![BEGIN_RECORD];
+        try!(write_u32(&mut record, info.len() as u32));
+        record.extend_from_slice(&info);
+        try!(write_u32(&mut record, page_size));
+        for &length in &lengths {
+            try!(write_u32(&mut record, length));
+        }
+        try!(self.append(&record));
+
+        self.active = true;
+        self.orig_lengths = lengths;
+        self.page_size = page_size;
+        self.logged.clear();
+        Ok(())
+    }
+
+    /// Log the contents of a page before it is overwritten, if it needs logging.
+    pub fn log_page(&mut self, kind: FileKind, mut file: &File, page_id: u32) -> io::Result<()> {
+        if !self.active ||
+           page_id >= self.orig_lengths[kind as usize] ||
+           self.logged.contains(&(kind, page_id)) {
+            return Ok(());
+        }
+
+        let mut record = vec![PAGE_RECORD, kind as u8];
+        try!(write_u32(&mut record, page_id));
+        let header_len = record.len();
+        record.resize(header_len + self.page_size as usize, 0);
+        try!(file.seek(SeekFrom::Start(page_id as u64 * self.page_size as u64)));
+        try!(file.read_exact(&mut record[header_len..]));
+        try!(self.append(&record));
+
+        self.logged.insert((kind, page_id));
+        Ok(())
+    }
+
+    /// Append a record to the log, and make sure it reaches the disk.
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        let mut file = try!(self.file.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "relation is read-only")
+        }));
+        try!(file.seek(SeekFrom::End(0)));
+        try!(file.write_all(record));
+        file.sync_data()
+    }
+
+    /// Commit the current transaction. The caller must have already written the info file.
+    pub fn commit(&mut self, files: &[&File]) -> io::Result<()> {
+        assert!(self.active, "no transaction in progress");
+        for file in files {
+            try!(file.sync_data());
+        }
+        try!(self.clear());
+        self.active = false;
+        Ok(())
+    }
+
+    /// Undo everything recorded in the log, returning whether there was anything to undo.
+    ///
+    /// This works both for the current transaction, and for one left incomplete by a crash.
+    /// The page files are indexed by `FileKind`, and may be missing if they don't exist yet.
+    pub fn rollback(&mut self, info_file: &File, files: [Option<&File>; NUM_FILE_KINDS])
+    -> io::Result<bool>
+    {
+        let mut log = vec![];
+        {
+            let mut file = match self.file.as_ref() {
+                Some(f) => f,
+                None => return Ok(false)
+            };
+            try!(file.seek(SeekFrom::Start(0)));
+            try!(file.read_to_end(&mut log));
+        }
+        self.active = false;
+        self.logged.clear();
+
+        // If the begin record is incomplete, no changes were made.
+        let mut cursor = Cursor::new(&log[..]);
+        let begin = match read_begin_record(&mut cursor) {
+            Some(begin) => begin,
+            None => {
+                try!(self.clear());
+                return Ok(false);
+            }
+        };
+        info!("Rolling back incomplete transaction");
+
+        // Chop off any pages added by the transaction, and restore any that were removed.
+        let page_size = begin.page_size as u64;
+        for (file, length) in files.iter().zip(begin.lengths.iter()) {
+            if let (Some(file), Some(length)) = (*file, *length) {
+                try!(file.set_len(length as u64 * page_size));
+            }
+        }
+
+        // Restore the logged pages. An incomplete final record is ignored, as the page
+        // it describes was never written.
+        while let Some((kind, page_id, image)) = read_page_record(&mut cursor, page_size as usize) {
+            debug!("Restoring page {} of {:?} file", page_id, kind);
+            let mut file = try!(files[kind as usize].ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no {:?} file to restore", kind))
+            }));
+            try!(file.seek(SeekFrom::Start(page_id as u64 * page_size)));
+            try!(file.write_all(image));
+        }
+
+        let mut info_file = info_file;
+        try!(info_file.seek(SeekFrom::Start(0)));
+        try!(info_file.write_all(begin.info));
+        try!(info_file.set_len(begin.info.len() as u64));
+
+        try!(info_file.sync_data());
+        for file in files.iter().filter_map(|f| *f) {
+            try!(file.sync_data());
+        }
+        try!(self.clear());
+        Ok(true)
+    }
+
+    /// Empty the log.
+    fn clear(&mut self) -> io::Result<()> {
+        if let Some(ref file) = self.file {
+            try!(file.set_len(0));
+            try!(file.sync_data());
+        }
+        Ok(())
+    }
+}
+
+/// Parse the record starting a transaction, returning None if it is incomplete.
+fn read_begin_record<'a>(cursor: &mut Cursor<&'a [u8]>) -> Option<BeginRecord<'a>> {
+    let log = *cursor.get_ref();
+    let tag = match log.first() {
+        Some(&tag) if tag == BEGIN_RECORD || tag == V2_BEGIN_RECORD || tag == V1_BEGIN_RECORD => tag,
+        _ => return None
+    };
+    cursor.set_position(1);
+    let info_len = match read_u32(&mut *cursor) {
+        Ok(len) => len as usize,
+        Err(_) => return None
+    };
+    let info_start = cursor.position() as usize;
+    let info_end = info_start + info_len;
+    if info_end > log.len() {
+        return None;
+    }
+    cursor.set_position(info_end as u64);
+
+    let mut fields = vec![];
+    let num_fields = match tag {
+        BEGIN_RECORD => 1 + NUM_FILE_KINDS,
+        V2_BEGIN_RECORD => 3,
+        _ => 2
+    };
+    for _ in 0..num_fields {
+        match read_u32(&mut *cursor) {
+            Ok(field) => fields.push(field),
+            Err(_) => return None
+        }
+    }
+    let (page_size, lengths) = match tag {
+        BEGIN_RECORD => (fields[0], [Some(fields[1]), Some(fields[2]), Some(fields[3])]),
+        V2_BEGIN_RECORD => (fields[2], [Some(fields[0]), Some(fields[1]), None]),
+        _ => (DEFAULT_PAGE_SIZE, [Some(fields[0]), Some(fields[1]), None])
+    };
+    Some(BeginRecord { info: &log[info_start..info_end], page_size: page_size, lengths: lengths })
+}
+
+/// Parse a logged page, returning (file kind, page ID, page contents), or None if
+/// there are no complete records left.
+fn read_page_record<'a>(cursor: &mut Cursor<&'a [u8]>, page_size: usize)
+-> Option<(FileKind, u32, &'a [u8])>
+{
+    let log = *cursor.get_ref();
+    let start = cursor.position() as usize;
+    let image_start = start + 6;
+    let image_end = image_start + page_size;
+    if image_end > log.len() || log[start] != PAGE_RECORD {
+        return None;
+    }
+    let kind = match FileKind::from_u8(log[start + 1]) {
+        Some(kind) => kind,
+        None => return None
+    };
+    cursor.set_position(start as u64 + 2);
+    let page_id = read_u32(&mut *cursor).unwrap();
+    cursor.set_position(image_end as u64);
+    Some((kind, page_id, &log[image_start..image_end]))
+}
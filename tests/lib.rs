@@ -3,8 +3,14 @@ extern crate uuid;
 extern crate quickcheck;
 extern crate rand;
 
-use std::iter::repeat;
+use std::env;
 use std::io;
+use std::mem;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::io::{Read, Write, Seek, SeekFrom};
 use uuid::Uuid;
 use quickcheck::{Arbitrary, StdGen, Gen};
 use rand::thread_rng;
@@ -13,15 +19,47 @@ use malh::relation::*;
 use malh::choice_vec::*;
 use malh::tuple::*;
 use malh::page::*;
-use malh::query::Query;
+use malh::query::{Query, AttrMatch};
+use malh::aggregate::{Aggregate, aggregate};
+use malh::sort::SortKey;
+use malh::split_policy::SplitPolicy;
+use malh::schema::*;
+use malh::util::crc32;
+use malh::upgrade::{FormatError, FORMAT_VERSION, stored_version};
+
+/// Space for tuple data in pages of the default size.
+const PAGE_DATA_SIZE: usize = page_data_size(DEFAULT_PAGE_SIZE);
+
+/// Unique name for a test relation, in the temporary directory so that the files of a
+/// failed test are never left in the working tree.
+fn test_relation_name() -> String {
+    let file_name = format!("malh-test-{}", Uuid::new_v4().simple());
+    env::temp_dir().join(file_name).to_string_lossy().into_owned()
+}
 
-/// A test relation with a random name.
 struct TestRelation(pub Relation);
 
 impl TestRelation {
     fn new(num_attrs: u32) -> TestRelation {
-        let name = format!("{}", Uuid::new_v4().simple());
-        Relation::new(&name, num_attrs, 1, ChoiceVec::new(vec![], 1)).unwrap();
+        TestRelation::with_policy(num_attrs, SplitPolicy::default())
+    }
+
+    fn with_policy(num_attrs: u32, split_policy: SplitPolicy) -> TestRelation {
+        TestRelation::with_options(num_attrs, split_policy, DEFAULT_PAGE_SIZE)
+    }
+
+    fn with_page_size(num_attrs: u32, page_size: u32) -> TestRelation {
+        TestRelation::with_options(num_attrs, SplitPolicy::default(), page_size)
+    }
+
+    fn with_options(num_attrs: u32, split_policy: SplitPolicy, page_size: u32) -> TestRelation {
+        TestRelation::with_schema(Schema::untyped(num_attrs), split_policy, page_size)
+    }
+
+    fn with_schema(schema: Schema, split_policy: SplitPolicy, page_size: u32) -> TestRelation {
+        let name = test_relation_name();
+        let choice_vec = ChoiceVec::new(vec![], schema.num_attrs());
+        Relation::new(&name, schema, 1, choice_vec, split_policy, page_size).unwrap();
         TestRelation(Relation::open(&name, Writing).unwrap())
     }
 
@@ -34,15 +72,16 @@ impl TestRelation {
 #[test]
 fn insert_oversize_tuple() {
     let mut r = TestRelation::new(1);
-    let oversized = Tuple { values: vec![repeat('A').take(PAGE_SIZE as usize).collect()] };
-    r.0.insert(oversized).unwrap_err();
+    let oversized = Tuple { values: vec![Some("A".repeat(DEFAULT_PAGE_SIZE as usize))] };
+    r.0.insert(oversized.clone()).unwrap();
+    assert_eq!(r.0.select(&Query::wildcard(1)).next().unwrap().unwrap(), oversized);
     r.close();
 }
 
 #[test]
 fn insert_largest_tuple() {
     let mut r = TestRelation::new(1);
-    let large = Tuple { values: vec![repeat('A').take(PAGE_DATA_SIZE - 1).collect()] };
+    let large = Tuple { values: vec![Some("A".repeat(PAGE_DATA_SIZE - 1))] };
     r.0.insert(large).unwrap();
     r.close();
 }
@@ -96,11 +135,13 @@ fn select_single() {
 // NOTE: Truly random UTF8 characters were too brutal and difficult to debug, so we just
 // generate random strings of A's.
 fn random_tuple<G: Gen>(num_attrs: u32, gen: &mut G) -> Tuple {
-    use std::iter::repeat;
     loop {
-        let tuple_values: Vec<String> = (0..num_attrs).map(|_| {
+        let tuple_values: Vec<Option<String>> = (0..num_attrs).map(|_| {
+            if u8::arbitrary(gen) % 8 == 0 {
+                return None;
+            }
             let tuple_len = usize::arbitrary(gen) % (PAGE_DATA_SIZE / num_attrs as usize);
-            repeat('A').take(tuple_len).collect()
+            Some("A".repeat(tuple_len))
         }).collect();
         let tuple = Tuple { values: tuple_values };
         if tuple.serialise().len() <= PAGE_DATA_SIZE {
@@ -109,29 +150,34 @@ fn random_tuple<G: Gen>(num_attrs: u32, gen: &mut G) -> Tuple {
     }
 }
 
-fn append_to_query<'a>(elem: Option<&'a str>, q: Query<'a>) -> Query<'a> {
-    let mut matches = q.matches;
+fn append_to_query<'a>(elem: AttrMatch<'a>, mut matches: Vec<AttrMatch<'a>>) -> Vec<AttrMatch<'a>> {
     matches.push(elem);
-    Query {
-        matches: matches
-    }
+    matches
 }
 
-fn queries_for_tuple<'a>(tuple: &'a [String]) -> Vec<Query<'a>> {
+fn matches_for_tuple<'a>(tuple: &'a [Option<String>]) -> Vec<Vec<AttrMatch<'a>>> {
     match tuple.split_last() {
-        None => vec![Query::wildcard(0)],
+        None => vec![vec![]],
         Some((last, init)) => {
-            let init_queries = queries_for_tuple(init);
-            let mut none_queries: Vec<Query<'a>> =
-                init_queries.iter().map(|q| append_to_query(None, q.clone())).collect();
-            let some_queries: Vec<Query<'a>> =
-                init_queries.iter().map(|q| append_to_query(Some(&last), q.clone())).collect();
-            none_queries.extend(some_queries);
-            none_queries
+            let init_matches = matches_for_tuple(init);
+            let mut none_matches: Vec<Vec<AttrMatch<'a>>> =
+                init_matches.iter().map(|m| append_to_query(AttrMatch::Any, m.clone())).collect();
+            let last_match = match *last {
+                Some(ref value) => AttrMatch::Value(Cow::from(value.as_str())),
+                None => AttrMatch::IsNull
+            };
+            let some_matches: Vec<Vec<AttrMatch<'a>>> =
+                init_matches.iter().map(|m| append_to_query(last_match.clone(), m.clone())).collect();
+            none_matches.extend(some_matches);
+            none_matches
         }
     }
 }
 
+fn queries_for_tuple<'a>(tuple: &'a [Option<String>]) -> Vec<Query<'a>> {
+    matches_for_tuple(tuple).into_iter().map(Query::from_matches).collect()
+}
+
 /// Compute the 2^n queries that should match a given tuple.
 fn all_queries_for_tuple<'a>(tuple: &'a Tuple) -> Vec<Query<'a>> {
     queries_for_tuple(&tuple.values[..])
@@ -156,7 +202,7 @@ fn insert_select(num_attrs: u32, num_tuples: u32) {
 
     for t in &tuples {
         println!("{}", t.to_string());
-        r.0.insert(t.clone()).expect(&format!("inserting tuple failed, tuple: {:?}", t));
+        r.0.insert(t.clone()).unwrap_or_else(|_| panic!("inserting tuple failed, tuple: {:?}", t));
     }
     println!("END TUPLE INSERTIONS");
 
@@ -179,3 +225,1367 @@ fn random_insert_select() {
     insert_select(8, 5);
     insert_select(3, 300);
 }
+
+#[test]
+fn delete_matching() {
+    let num_attrs = 2;
+    let mut r = TestRelation::new(num_attrs);
+    let tuples: Vec<Tuple> = (0..500)
+        .map(|i| Tuple::parse(&format!("{},{}", i % 3, i), num_attrs).unwrap())
+        .collect();
+    for t in &tuples {
+        r.0.insert(t.clone()).unwrap();
+    }
+
+    let query = Query::parse("1,?", num_attrs).unwrap();
+    let expected = tuples.iter().filter(|t| query.matches_tuple(t, &r.0.schema)).count() as u64;
+    assert_eq!(r.0.delete_matching(&query).unwrap(), expected);
+    assert_eq!(r.0.num_tuples, 500 - expected);
+    assert_eq!(r.0.select(&query).count(), 0);
+
+    // Deleting again removes nothing, and the other tuples are all still there.
+    assert_eq!(r.0.delete_matching(&query).unwrap(), 0);
+    for t in tuples.iter().filter(|t| !query.matches_tuple(t, &r.0.schema)) {
+        let s = t.to_string();
+        let exact = Query::parse(&s, num_attrs).unwrap();
+        assert_eq!(r.0.select(&exact).count(), 1);
+    }
+    r.close();
+}
+
+#[test]
+fn delete_everything() {
+    let num_attrs = 3;
+    let mut r = TestRelation::new(num_attrs);
+    for i in 0..200 {
+        r.0.insert(Tuple::parse(&format!("a{},b,c", i), num_attrs).unwrap()).unwrap();
+    }
+    assert_eq!(r.0.delete_matching(&Query::wildcard(num_attrs)).unwrap(), 200);
+    assert_eq!(r.0.num_tuples, 0);
+    r.close();
+}
+
+fn record_ids(r: &TestRelation) -> HashMap<String, RecordId> {
+    r.0.select(&Query::wildcard(r.0.num_attrs))
+        .with_ids()
+        .map(|item| {
+            let (rid, tuple) = item.unwrap();
+            (tuple.to_string(), rid)
+        })
+        .collect()
+}
+
+#[test]
+fn record_ids_survive_other_changes() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::Never);
+    insert_numbered(&mut r, 200);
+    let before = record_ids(&r);
+    assert_eq!(before.len(), 200);
+    // Every ID points at a distinct record.
+    assert_eq!(before.values().collect::<HashSet<_>>().len(), 200);
+
+    for i in 0..100 {
+        r.0.delete_matching(&Query::parse(&format!("{},?", 2 * i), 2).unwrap()).unwrap();
+    }
+    r.0.update(&Query::parse("1,?", 2).unwrap(), |t| t.values[1] = Some("y".repeat(20))).unwrap();
+    for i in 200..250 {
+        r.0.insert(Tuple { values: vec![Some(i.to_string()), Some("z".to_string())] }).unwrap();
+    }
+
+    let after = record_ids(&r);
+    assert_eq!(after.len(), 150);
+    for (tuple, rid) in &before {
+        if let Some(new_rid) = after.get(tuple) {
+            assert_eq!(new_rid, rid, "record ID of {} changed", tuple);
+        }
+    }
+    let updated = format!("1,{}", "y".repeat(20));
+    assert_eq!(after[&updated], before[&format!("1,{}", "x".repeat(20))]);
+    r.close();
+}
+
+#[test]
+fn record_ids_from_before_a_split_are_rejected() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 10);
+    let before = record_ids(&r);
+    let epoch = r.0.epoch;
+    let num_pages = r.0.num_pages;
+    insert_numbered(&mut r, 500);
+    assert!(r.0.num_pages > num_pages);
+    assert!(r.0.epoch > epoch);
+
+    for rid in before.values() {
+        let err = r.0.fetch(*rid).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.get_ref().unwrap().downcast_ref::<StaleRecordId>().is_some());
+        assert_eq!(r.0.delete_rid(*rid).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+    assert_eq!(r.0.num_tuples, 510);
+
+    // IDs handed out now are good, including once the relation is reopened.
+    let after = record_ids(&r);
+    let name = r.0.name.clone();
+    mem::drop(r);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    for (tuple, rid) in &after {
+        assert_eq!(rid.epoch, r.0.epoch);
+        assert_eq!(r.0.fetch(*rid).unwrap().unwrap().to_string(), *tuple);
+    }
+    r.close();
+}
+
+#[test]
+fn record_ids_of_moved_or_deleted_tuples_are_never_reused() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::Never);
+    insert_numbered(&mut r, 10);
+    let rids = record_ids(&r);
+    let x = "x".repeat(20);
+
+    // A deleted record's slot isn't reused by later inserts.
+    let deleted_rid = rids[&format!("3,{}", x)];
+    assert!(r.0.delete_rid(deleted_rid).unwrap());
+    insert_numbered(&mut r, 10);
+    assert_eq!(r.0.epoch, deleted_rid.epoch);
+    assert_eq!(r.0.fetch(deleted_rid).unwrap(), None);
+    assert_eq!(r.0.fetch(rids[&format!("4,{}", x)]).unwrap().unwrap().to_string(), format!("4,{}", x));
+
+    // Growing a tuple so that it no longer fits in its page moves it, and makes its ID stale.
+    let moved_rid = rids[&format!("5,{}", x)];
+    let query = Query::parse("5,?", 2).unwrap();
+    r.0.update(&query, |t| t.values[1] = Some("y".repeat(PAGE_DATA_SIZE - 100))).unwrap();
+    insert_numbered(&mut r, 10);
+    let err = r.0.fetch(moved_rid).unwrap_err();
+    assert!(err.get_ref().unwrap().downcast_ref::<StaleRecordId>().is_some());
+    assert_eq!(r.0.delete_rid(moved_rid).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(r.0.select(&query).count(), 3);
+
+    // Compacting frees the slots of deleted records, which makes every ID stale.
+    let rids = record_ids(&r);
+    r.0.delete_matching(&Query::parse("6,?", 2).unwrap()).unwrap();
+    let epoch = r.0.epoch;
+    r.0.compact_overflow().unwrap();
+    assert!(r.0.epoch > epoch);
+    assert!(r.0.fetch(rids[&format!("7,{}", x)]).is_err());
+    r.close();
+}
+
+fn people() -> TestRelation {
+    let schema = Schema::parse("name,age:int,member:bool").unwrap();
+    TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE)
+}
+
+#[test]
+fn schema_is_stored_and_enforced() {
+    let mut r = people();
+    for (i, name) in ["alice", "bob", "carol"].iter().enumerate() {
+        r.0.insert(Tuple::parse(&format!("{},{},{}", name, 30 + i, i == 1), 3).unwrap()).unwrap();
+    }
+    for invalid in &["dave,old,true", "dave,40,yes", "dave,40"] {
+        let tuple = Tuple { values: invalid.split(',').map(|v| Some(v.to_string())).collect() };
+        let err = r.0.insert(tuple).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.get_ref().unwrap().downcast_ref::<SchemaError>().is_some());
+    }
+    let err = r.0.update(&Query::wildcard(3), |t| t.values[1] = Some("?".to_string())).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(r.0.num_tuples, 3);
+
+    let name = r.0.name.clone();
+    mem::drop(r);
+    let r = TestRelation(Relation::open(&name, Reading).unwrap());
+    assert_eq!(r.0.schema.to_string(), "name:text,age:int,member:bool");
+    let query = Query::parse_with_schema("member=true", &r.0.schema).unwrap();
+    let found: Vec<Tuple> = r.0.select(&query).map(|t| t.unwrap()).collect();
+    assert_eq!(found, vec![Tuple::parse("bob,31,true", 3).unwrap()]);
+    let query = Query::parse_with_schema("name=carol,age=32", &r.0.schema).unwrap();
+    assert_eq!(r.0.select(&query).count(), 1);
+    r.close();
+}
+
+#[test]
+fn fetch_and_delete_by_record_id() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::Never);
+    insert_numbered(&mut r, 200);
+    let large = Tuple { values: vec![Some("large".to_string()), Some("A".repeat(DEFAULT_PAGE_SIZE as usize))] };
+    r.0.insert(large.clone()).unwrap();
+    let rids = record_ids(&r);
+    // With a single bucket, most of the records are in overflow pages.
+    assert!(rids.values().any(|rid| rid.page_in_file != PRIMARY_PAGE));
+    for (tuple, rid) in &rids {
+        assert_eq!(r.0.fetch(*rid).unwrap().unwrap().to_string(), *tuple);
+    }
+
+    let large_rid = rids[&large.to_string()];
+    assert!(r.0.delete_rid(large_rid).unwrap());
+    assert_eq!(r.0.blob_free_list.len, get_next_page_id(&r.0.blob_file).unwrap());
+    let mut rids = rids;
+    for i in 0..200 {
+        // Freeing an emptied overflow page makes every ID stale, so look them up again.
+        if rids.values().next().unwrap().epoch != r.0.epoch {
+            rids = record_ids(&r);
+        }
+        let rid = rids[&format!("{},{}", i, "x".repeat(20))];
+        assert!(r.0.delete_rid(rid).unwrap());
+        if rid.epoch == r.0.epoch {
+            // Deleting or fetching the ID of a deleted record finds nothing.
+            assert!(!r.0.delete_rid(rid).unwrap());
+            assert_eq!(r.0.fetch(rid).unwrap(), None);
+        } else {
+            assert_eq!(r.0.fetch(rid).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+    assert_eq!(r.0.num_tuples, 0);
+    assert_eq!(r.0.select(&Query::wildcard(2)).count(), 0);
+    // The emptied overflow pages are freed.
+    assert_eq!(r.0.free_list.len, get_next_page_id(&r.0.ovflow_file).unwrap());
+    r.close();
+}
+
+#[test]
+fn fetch_invalid_record_ids() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 10);
+    let epoch = r.0.epoch;
+    let invalid = [
+        RecordId { epoch: epoch, bucket: r.0.num_pages as u32, page_in_file: PRIMARY_PAGE, slot: 0 },
+        RecordId { epoch: epoch, bucket: 0, page_in_file: 0, slot: 0 },
+        RecordId { epoch: epoch, bucket: 0, page_in_file: PRIMARY_PAGE, slot: 1000 },
+    ];
+    for rid in &invalid {
+        assert_eq!(r.0.fetch(*rid).unwrap(), None);
+        assert!(!r.0.delete_rid(*rid).unwrap());
+    }
+    assert_eq!(r.0.num_tuples, 10);
+    r.close();
+}
+
+#[test]
+fn update_moves_rehashed_tuples() {
+    let num_attrs = 2;
+    let mut r = TestRelation::new(num_attrs);
+    for i in 0..300 {
+        r.0.insert(Tuple::parse(&format!("k{},{}", i, i % 2), num_attrs).unwrap()).unwrap();
+    }
+
+    // Rewrite the first attribute of every odd tuple, which changes its hash.
+    let odd = Query::parse("?,1", num_attrs).unwrap();
+    let num_updated = r.0.update(&odd, |t| t.values[0] = t.values[0].take().map(|v| format!("new-{}", v))).unwrap();
+    assert_eq!(num_updated, 150);
+    assert_eq!(r.0.num_tuples, 300);
+
+    for i in 0..300 {
+        let old = format!("k{}", i);
+        let new = format!("new-k{}", i);
+        let old_query = Query::from_matches(vec![AttrMatch::Value(old.as_str().into()), AttrMatch::Any]);
+        let new_query = Query::from_matches(vec![AttrMatch::Value(new.as_str().into()), AttrMatch::Any]);
+        let (old_count, new_count) = if i % 2 == 1 { (0, 1) } else { (1, 0) };
+        assert_eq!(r.0.select(&old_query).count(), old_count);
+        assert_eq!(r.0.select(&new_query).count(), new_count);
+    }
+    r.close();
+}
+
+#[test]
+fn update_rejects_invalid_tuples() {
+    let num_attrs = 2;
+    let mut r = TestRelation::new(num_attrs);
+    r.0.insert(Tuple::parse("a,b", num_attrs).unwrap()).unwrap();
+    r.0.update(&Query::wildcard(num_attrs), |t| t.values.push(Some("c".to_string()))).unwrap_err();
+    assert_eq!(r.0.select(&Query::parse("a,b", num_attrs).unwrap()).count(), 1);
+    r.close();
+}
+
+#[test]
+fn shrink_to_single_page() {
+    let num_attrs = 2;
+    let mut r = TestRelation::new(num_attrs);
+    let tuples: Vec<Tuple> = (0..400)
+        .map(|i| Tuple::parse(&format!("{},{}", i, i * 7), num_attrs).unwrap())
+        .collect();
+    for t in &tuples {
+        r.0.insert(t.clone()).unwrap();
+    }
+    assert!(r.0.num_pages > 1);
+
+    while r.0.num_pages > 1 {
+        r.0.shrink().unwrap();
+        r.0.is_sane();
+    }
+    assert_eq!(r.0.depth, 0);
+    for t in &tuples {
+        let s = t.to_string();
+        assert_eq!(r.0.select(&Query::parse(&s, num_attrs).unwrap()).count(), 1);
+    }
+    r.close();
+}
+
+#[test]
+fn delete_triggers_shrink() {
+    let num_attrs = 2;
+    let mut r = TestRelation::new(num_attrs);
+    for i in 0..1000 {
+        r.0.insert(Tuple::parse(&format!("{},{}", i % 50, i), num_attrs).unwrap()).unwrap();
+    }
+    let num_pages = r.0.num_pages;
+
+    let keep = "7".to_string();
+    let mut deleted = 0;
+    for i in 0..50 {
+        let value = format!("{}", i);
+        if value != keep {
+            deleted += r.0.delete_matching(&Query::from_matches(vec![AttrMatch::Value(value.as_str().into()), AttrMatch::Any])).unwrap();
+        }
+    }
+    assert_eq!(deleted, 980);
+    assert!(r.0.num_pages < num_pages);
+    assert_eq!(r.0.select(&Query::from_matches(vec![AttrMatch::Value(keep.as_str().into()), AttrMatch::Any])).count(), 20);
+    r.close();
+}
+
+fn insert_numbered(r: &mut TestRelation, num_tuples: u32) {
+    for i in 0..num_tuples {
+        r.0.insert(Tuple::parse(&format!("{},{}", i, "x".repeat(20)), 2).unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn split_policy_never() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::Never);
+    insert_numbered(&mut r, 500);
+    assert_eq!(r.0.num_pages, 1);
+    r.close();
+}
+
+#[test]
+fn split_policy_load_factor() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::LoadFactor(500));
+    insert_numbered(&mut r, 500);
+    // Each tuple is 24 or 25 bytes, so splitting at 500 bytes per page gives at least 23 pages.
+    assert!(r.0.num_bytes <= 500 * r.0.num_pages);
+    assert!(r.0.num_pages >= 23);
+    r.close();
+}
+
+#[test]
+fn split_policy_on_overflow() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::OnOverflow);
+    insert_numbered(&mut r, 500);
+    assert!(r.0.num_pages > 1);
+    r.close();
+}
+
+#[test]
+fn split_policy_tuple_count_with_wide_tuples() {
+    // Fewer than one tuple of 60 attributes is expected to fit in a 512 byte page, but the
+    // relation still waits for a tuple per page before growing.
+    let mut r = TestRelation::with_page_size(60, MIN_PAGE_SIZE);
+    assert_eq!(r.0.split_policy, SplitPolicy::TupleCount);
+    let tuple = Tuple { values: vec![Some("a".to_string()); 60] };
+    r.0.insert(tuple.clone()).unwrap();
+    assert_eq!(r.0.num_pages, 1);
+    for _ in 0..49 {
+        r.0.insert(tuple.clone()).unwrap();
+    }
+    assert!(r.0.num_pages <= 50);
+    r.close();
+}
+
+#[test]
+fn split_policy_persists() {
+    let r = TestRelation::with_policy(3, SplitPolicy::LoadFactor(123));
+    let name = r.0.name.clone();
+    let reopened = Relation::open(&name, Reading).unwrap();
+    assert_eq!(reopened.split_policy, SplitPolicy::LoadFactor(123));
+    r.close();
+}
+
+fn ovflow_file_pages(r: &TestRelation) -> u32 {
+    get_next_page_id(&r.0.ovflow_file).unwrap()
+}
+
+#[test]
+fn freed_overflow_pages_are_reused() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::Never);
+    insert_numbered(&mut r, 300);
+    let num_ovflow_pages = ovflow_file_pages(&r);
+    assert!(num_ovflow_pages > 0);
+
+    r.0.delete_matching(&Query::wildcard(2)).unwrap();
+    assert_eq!(r.0.free_list.len, num_ovflow_pages);
+    r.0.is_sane();
+
+    insert_numbered(&mut r, 300);
+    assert_eq!(ovflow_file_pages(&r), num_ovflow_pages);
+    r.close();
+}
+
+#[test]
+fn compact_overflow() {
+    let num_attrs = 2;
+    let mut r = TestRelation::with_policy(num_attrs, SplitPolicy::Never);
+    insert_numbered(&mut r, 600);
+
+    // Delete runs of tuples, emptying pages throughout the overflow file.
+    let mut kept = vec![];
+    for i in 0..600 {
+        let value = format!("{}", i);
+        if i % 200 < 20 {
+            kept.push(value);
+        } else {
+            r.0.delete_matching(&Query::from_matches(vec![AttrMatch::Value(value.as_str().into()), AttrMatch::Any])).unwrap();
+        }
+    }
+    let num_ovflow_pages = ovflow_file_pages(&r);
+    let reclaimed = r.0.compact_overflow().unwrap();
+    assert!(reclaimed > 0);
+    assert_eq!(ovflow_file_pages(&r), num_ovflow_pages - reclaimed);
+    assert_eq!(r.0.free_list.len, 0);
+
+    for value in &kept {
+        assert_eq!(r.0.select(&Query::from_matches(vec![AttrMatch::Value(value.as_str().into()), AttrMatch::Any])).count(), 1);
+    }
+    r.close();
+}
+
+#[test]
+fn crash_mid_transaction_is_rolled_back() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 100);
+    let name = r.0.name.clone();
+    let (num_pages, num_ovflow_pages) = (r.0.num_pages, ovflow_file_pages(&r));
+
+    r.0.begin().unwrap();
+    insert_numbered(&mut r, 500);
+    r.0.delete_matching(&Query::parse("7,?", 2).unwrap()).unwrap();
+    assert!(r.0.num_pages > num_pages);
+
+    // Simulate a crash by copying the files as they are mid-transaction, which also leaves
+    // the original's lock behind.
+    let crashed_name = test_relation_name();
+    for ext in &["info", "data", "ovflow", "blob", "wal"] {
+        fs::copy(format!("{}.{}", name, ext), format!("{}.{}", crashed_name, ext)).unwrap();
+    }
+    r.0.rollback().unwrap();
+    r.close();
+
+    let r = TestRelation(Relation::open(&crashed_name, Writing).unwrap());
+    assert_eq!(r.0.num_tuples, 100);
+    assert_eq!(r.0.num_pages, num_pages);
+    assert_eq!(ovflow_file_pages(&r), num_ovflow_pages);
+    assert_eq!(r.0.select(&Query::parse("7,?", 2).unwrap()).count(), 1);
+    r.close();
+}
+
+#[test]
+fn readers_leave_live_transactions_alone() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 100);
+    let name = r.0.name.clone();
+
+    r.0.begin().unwrap();
+    insert_numbered(&mut r, 500);
+    r.0.flush().unwrap();
+    {
+        let reader = Relation::open(&name, Reading).unwrap();
+        assert_eq!(reader.num_tuples, 100);
+    }
+    let err = Relation::open(&name, Writing).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    Relation::recover(&name).unwrap_err();
+
+    assert!(r.0.in_transaction());
+    r.0.commit().unwrap();
+    assert_eq!(r.0.num_tuples, 600);
+    assert_eq!(r.0.select(&Query::wildcard(2)).count(), 600);
+    r.close();
+}
+
+#[test]
+fn dropping_rolls_back_open_transaction() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 50);
+    let name = r.0.name.clone();
+    r.0.begin().unwrap();
+    insert_numbered(&mut r, 300);
+    mem::drop(r);
+
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    assert_eq!(r.0.num_tuples, 50);
+    assert_eq!(r.0.select(&Query::wildcard(2)).count(), 50);
+    r.close();
+}
+
+#[test]
+fn explicit_rollback() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 50);
+    r.0.begin().unwrap();
+    insert_numbered(&mut r, 300);
+    r.0.rollback().unwrap();
+    assert!(!r.0.in_transaction());
+    assert_eq!(r.0.num_tuples, 50);
+    r.close();
+}
+
+#[test]
+fn failed_update_is_rolled_back() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 300);
+    let result = r.0.update(&Query::wildcard(2), |t| {
+        if t.values[0].as_ref().unwrap() == "250" {
+            t.values.push(Some("extra".to_string()));
+        } else {
+            t.values[1] = Some("changed".to_string());
+        }
+    });
+    result.unwrap_err();
+    assert_eq!(r.0.select(&Query::parse("?,changed", 2).unwrap()).count(), 0);
+    assert_eq!(r.0.num_tuples, 300);
+    r.close();
+}
+
+#[test]
+fn corrupt_page_detected() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 10);
+    let data_file_name = format!("{}.data", r.0.name);
+
+    // Flip the bits of a byte in the middle of the only data page, then re-open the
+    // relation so that the page isn't served from the buffer pool.
+    let name = r.0.name.clone();
+    mem::drop(r);
+    {
+        let mut f = OpenOptions::new().read(true).write(true).open(&data_file_name).unwrap();
+        let mut byte = [0];
+        f.seek(SeekFrom::Start(100)).unwrap();
+        f.read_exact(&mut byte).unwrap();
+        f.seek(SeekFrom::Start(100)).unwrap();
+        f.write_all(&[!byte[0]]).unwrap();
+    }
+    let mut r = TestRelation(Relation::open(&name, Writing).unwrap());
+
+    let err = r.0.select(&Query::wildcard(2)).next().unwrap().unwrap_err();
+    {
+        let corrupt_page = err.get_ref().unwrap().downcast_ref::<CorruptPage>().unwrap();
+        assert_eq!(corrupt_page.page_id, 0);
+        assert_eq!(corrupt_page.file, data_file_name);
+    }
+    r.0.delete().unwrap();
+}
+
+/// Close a test relation, and replace its info file with the output of `f`.
+fn rewrite_info_file<F>(r: TestRelation, f: F) -> String where F: FnOnce(Vec<u8>) -> Vec<u8> {
+    let name = r.0.name.clone();
+    mem::drop(r);
+    let info_file_name = format!("{}.info", name);
+    let mut info = vec![];
+    File::open(&info_file_name).unwrap().read_to_end(&mut info).unwrap();
+    File::create(&info_file_name).unwrap().write_all(&f(info)).unwrap();
+    name
+}
+
+/// Strip the epoch from the end of an info file, as in versions before 9.
+fn without_epoch(mut info: Vec<u8>) -> Vec<u8> {
+    let len = info.len() - 4;
+    info.truncate(len);
+    info
+}
+
+/// Strip the untyped schema from the end of an info file, as in versions before 6.
+fn without_schema(mut info: Vec<u8>, num_attrs: u32) -> Vec<u8> {
+    let mut schema = vec![];
+    Schema::untyped(num_attrs).write(&mut schema).unwrap();
+    assert!(info.ends_with(&schema));
+    let len = info.len() - schema.len();
+    info.truncate(len);
+    info
+}
+
+fn format_error(err: &io::Error) -> &FormatError {
+    err.get_ref().unwrap().downcast_ref::<FormatError>().unwrap()
+}
+
+/// Build a page in the format used up to version 3, holding NUL-terminated records.
+fn legacy_page(records: &[Vec<u8>], ovflow: u32) -> Vec<u8> {
+    let data: Vec<u8> = records.iter().flat_map(|r| r.iter().cloned().chain(Some(0))).collect();
+    raw_page(&data, records.len() as u32, ovflow)
+}
+
+/// Build a page of the default size around some data.
+fn raw_page(data: &[u8], num_tuples: u32, ovflow: u32) -> Vec<u8> {
+    let mut page = vec![0; DEFAULT_PAGE_SIZE as usize];
+    page[4..8].copy_from_slice(&(data.len() as u32).to_be_bytes());
+    page[8..12].copy_from_slice(&ovflow.to_be_bytes());
+    page[12..16].copy_from_slice(&num_tuples.to_be_bytes());
+    page[16..16 + data.len()].copy_from_slice(data);
+    let checksum = crc32(&page[4..]);
+    page[..4].copy_from_slice(&checksum.to_be_bytes());
+    page
+}
+
+fn write_file(name: &str, ext: &str, contents: &[u8]) {
+    File::create(format!("{}.{}", name, ext)).unwrap().write_all(contents).unwrap();
+}
+
+#[test]
+fn upgrade_from_v0() {
+    // A relation written by the original build, before any format version existed, from
+    // `create baseline 2 1 <choice vec>` and an insert of the tuples below. Its info file
+    // has no header, and its pages have 12-byte headers and no checksums.
+    let name = test_relation_name();
+    for ext in &["info", "data", "ovflow"] {
+        let fixture = format!("{}/tests/data/baseline.{}", env!("CARGO_MANIFEST_DIR"), ext);
+        fs::copy(fixture, format!("{}.{}", name, ext)).unwrap();
+    }
+    let expected: HashSet<String> = (0..150)
+        .map(|i| format!("{},v{}-{}", i, i % 7, "x".repeat(20 + i % 30)))
+        .collect();
+
+    assert_eq!(stored_version(&name).unwrap(), 0);
+    let err = Relation::open(&name, Reading).err().unwrap();
+    match *format_error(&err) {
+        FormatError::NeedsUpgrade(0) => (),
+        ref e => panic!("unexpected error: {:?}", e)
+    }
+
+    assert_eq!(Relation::upgrade(&name).unwrap(), 0);
+    assert_eq!(stored_version(&name).unwrap(), FORMAT_VERSION);
+    assert_eq!(Relation::upgrade(&name).unwrap(), FORMAT_VERSION);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    assert_eq!(r.0.num_tuples, 150);
+    assert_eq!(r.0.page_size, DEFAULT_PAGE_SIZE);
+    let tuples: HashSet<String> = r.0.select(&Query::wildcard(2)).map(|t| t.unwrap().to_string()).collect();
+    assert_eq!(tuples, expected);
+    assert_eq!(r.0.select(&Query::parse("7,?", 2).unwrap()).count(), 1);
+    r.close();
+}
+
+#[test]
+fn upgrade_from_v3_rewrites_records() {
+    let r = TestRelation::new(2);
+    let name = rewrite_info_file(r, |mut info| {
+        info[4..8].copy_from_slice(&3u32.to_be_bytes());
+        info
+    });
+    // A bucket with an overflow page, one of whose tuples is stored out of line.
+    let large = format!("large,{}", "y".repeat(1500)).into_bytes();
+    let mut blob_ref = vec![0xff];
+    blob_ref.extend(format!("{:08x}{:08x}{:08x}", 0, large.len(), 0).bytes());
+    let mut blob_pages = raw_page(&large[..PAGE_DATA_SIZE], 0, 1);
+    blob_pages.extend(raw_page(&large[PAGE_DATA_SIZE..], 0, NO_OVFLOW));
+    write_file(&name, "blob", &blob_pages);
+    write_file(&name, "data", &legacy_page(&[b"a,b".to_vec()], 0));
+    write_file(&name, "ovflow", &legacy_page(&[b"c,d".to_vec(), blob_ref], NO_OVFLOW));
+
+    assert_eq!(Relation::upgrade(&name).unwrap(), 3);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    let mut tuples: Vec<String> = r.0.select(&Query::wildcard(2))
+        .map(|t| t.unwrap().to_string())
+        .collect();
+    tuples.sort();
+    let expected = vec!["a,b".to_string(), "c,d".to_string(), String::from_utf8(large).unwrap()];
+    assert_eq!(tuples, expected);
+    r.close();
+}
+
+#[test]
+fn upgrade_from_v4_moves_to_slotted_pages() {
+    let r = TestRelation::new(2);
+    let name = rewrite_info_file(r, |mut info| {
+        info[4..8].copy_from_slice(&4u32.to_be_bytes());
+        info
+    });
+    // Version 4 pages hold length-prefixed records packed back to back.
+    let tuples: Vec<Tuple> = (0..20).map(|i| Tuple { values: vec![Some(i.to_string()), Some("a,b?".to_string())] }).collect();
+    let data: Vec<u8> = tuples.iter()
+        .flat_map(|t| encode_record(RecordKind::Tuple, &t.serialise()))
+        .collect();
+    write_file(&name, "data", &raw_page(&data, tuples.len() as u32, NO_OVFLOW));
+
+    assert_eq!(Relation::upgrade(&name).unwrap(), 4);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    let mut found: Vec<Tuple> = r.0.select(&Query::wildcard(2)).map(|t| t.unwrap()).collect();
+    found.sort_by_key(|t| t.values[0].as_ref().unwrap().parse::<u32>().unwrap());
+    assert_eq!(found, tuples);
+    r.close();
+}
+
+#[test]
+fn upgrade_finishes_interrupted_rebuild() {
+    let r = TestRelation::new(2);
+    let name = rewrite_info_file(r, |mut info| {
+        info[4..8].copy_from_slice(&4u32.to_be_bytes());
+        info
+    });
+    // Leave the rebuilt relation marked as complete, with only its data file moved into place.
+    let tmp_name = format!("{}.rebuild", name);
+    let mut rebuilt = TestRelation::new(2);
+    let tuple = Tuple { values: vec![Some("a".to_string()), Some("b".to_string())] };
+    rebuilt.0.insert(tuple.clone()).unwrap();
+    let rebuilt_name = rewrite_info_file(rebuilt, |info| info);
+    for ext in &["info", "data", "ovflow", "blob", "wal"] {
+        fs::rename(format!("{}.{}", rebuilt_name, ext), format!("{}.{}", tmp_name, ext)).unwrap();
+    }
+    File::create(swap_file_name(&name)).unwrap();
+    fs::rename(data_file_name(&tmp_name), data_file_name(&name)).unwrap();
+
+    let err = Relation::open(&name, Reading).err().unwrap();
+    match *format_error(&err) {
+        FormatError::UnfinishedUpgrade => (),
+        ref e => panic!("unexpected error: {:?}", e)
+    }
+    assert_eq!(Relation::upgrade(&name).unwrap(), FORMAT_VERSION);
+    assert!(!Path::new(&swap_file_name(&name)).exists());
+    assert!(!Relation::exists(&tmp_name));
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    let found: Vec<Tuple> = r.0.select(&Query::wildcard(2)).map(|t| t.unwrap()).collect();
+    assert_eq!(found, vec![tuple]);
+    r.close();
+}
+
+#[test]
+fn upgrade_from_v5_adds_schema() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 50);
+    let name = rewrite_info_file(r, |info| {
+        let mut info = without_schema(without_epoch(info), 2);
+        info[4..8].copy_from_slice(&5u32.to_be_bytes());
+        info
+    });
+
+    assert_eq!(Relation::upgrade(&name).unwrap(), 5);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    assert_eq!(r.0.schema, Schema::untyped(2));
+    assert_eq!(r.0.select(&Query::parse_named("attr0=7", &r.0.schema).unwrap()).count(), 1);
+    assert_eq!(r.0.num_tuples, 50);
+    r.close();
+}
+
+#[test]
+fn upgrade_from_v6_rehashes_typed_values() {
+    // Version 6 hashed the text of every value, so store padded numbers as text,
+    // then make the attribute an integer.
+    let schema = Schema::parse("id,val").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..300 {
+        r.0.insert(Tuple { values: vec![Some(format!("{:03}", i)), Some("x".to_string())] }).unwrap();
+    }
+    let name = rewrite_info_file(r, |info| {
+        let mut info = without_epoch(info);
+        info[4..8].copy_from_slice(&6u32.to_be_bytes());
+        // The schema ends with `id`'s type, then `val`'s name and type.
+        let id_type = info.len() - (4 + "val".len() + 1) - 1;
+        info[id_type] = 1;
+        info
+    });
+
+    assert_eq!(Relation::upgrade(&name).unwrap(), 6);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    assert_eq!(r.0.schema.to_string(), "id:int,val:text");
+    assert_eq!(r.0.num_tuples, 300);
+    for i in 0..300 {
+        let query = Query::parse_named(&format!("id={}", i), &r.0.schema).unwrap();
+        let found: Vec<Tuple> = r.0.select(&query).map(|t| t.unwrap()).collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].values[0], Some(format!("{:03}", i)));
+    }
+    r.close();
+}
+
+#[test]
+fn upgrade_from_v7_keeps_tuples() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 50);
+    let name = rewrite_info_file(r, |info| {
+        let mut info = without_epoch(info);
+        info[4..8].copy_from_slice(&7u32.to_be_bytes());
+        info
+    });
+
+    assert!(Relation::open(&name, Reading).is_err());
+    assert_eq!(Relation::upgrade(&name).unwrap(), 7);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    assert_eq!(r.0.num_tuples, 50);
+    assert_eq!(r.0.select(&Query::parse("7,?", 2).unwrap()).count(), 1);
+    r.close();
+}
+
+#[test]
+fn upgrade_from_v8_starts_first_epoch() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 50);
+    let name = rewrite_info_file(r, |info| {
+        let mut info = without_epoch(info);
+        info[4..8].copy_from_slice(&8u32.to_be_bytes());
+        info
+    });
+
+    assert!(Relation::open(&name, Reading).is_err());
+    assert_eq!(Relation::upgrade(&name).unwrap(), 8);
+    let r = TestRelation(Relation::open(&name, Writing).unwrap());
+    assert_eq!(r.0.epoch, 0);
+    assert_eq!(r.0.num_tuples, 50);
+    r.close();
+}
+
+#[test]
+fn typed_values_match_by_value() {
+    let schema = Schema::parse("id:int,score:float,tag:bytes").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..200 {
+        let tuple = format!("{:04},{}.50,{:02X}", i, i, i % 256);
+        r.0.insert(Tuple::parse(&tuple, 3).unwrap()).unwrap();
+    }
+    for &(query, count) in &[("id=42", 1), ("id=+0042", 1), ("score=42.5", 1), ("tag=2a", 1),
+                             ("id=42,score=4.25e1", 1), ("id=42,score=42", 0)] {
+        let query = Query::parse_named(query, &r.0.schema).unwrap();
+        assert_eq!(r.0.select(&query).count(), count, "{:?}", query);
+    }
+    assert_eq!(r.0.delete_matching(&Query::parse("7,?,?", 3).unwrap()).unwrap(), 1);
+    r.close();
+}
+
+#[test]
+fn nulls_are_stored_and_matched() {
+    let mut r = people();
+    for i in 0..100 {
+        let age = if i % 3 == 0 { "\\N".to_string() } else { (i % 50).to_string() };
+        let name = if i % 10 == 0 { "\"\"".to_string() } else { format!("p{}", i) };
+        r.0.insert(Tuple::parse(&format!("{},{},\\N", name, age), 3).unwrap()).unwrap();
+    }
+    r.0.update(&Query::parse_with_schema("name=p1", &r.0.schema).unwrap(), |t| {
+        t.values[2] = Some("true".to_string());
+    }).unwrap();
+
+    let name = r.0.name.clone();
+    mem::drop(r);
+    let r = TestRelation(Relation::open(&name, Reading).unwrap());
+    for &(query, count) in &[("age IS NULL", 34), ("age is not null", 66), ("age=\\N", 34),
+                             ("age=0", 1), ("age=1", 1), ("name=\"\"", 10), ("name=\\N", 0),
+                             ("member IS NOT NULL", 1), ("name=\"\",age is null", 4),
+                             ("?,\\N,\\N", 34)] {
+        let query = Query::parse_with_schema(query, &r.0.schema).unwrap();
+        assert_eq!(r.0.select(&query).count(), count, "{:?}", query);
+    }
+    let query = Query::parse_with_schema("name=p3", &r.0.schema).unwrap();
+    let found: Vec<Tuple> = r.0.select(&query).map(|t| t.unwrap()).collect();
+    assert_eq!(found, vec![Tuple { values: vec![Some("p3".to_string()), None, None] }]);
+    assert_eq!(found[0].to_string(), "p3,\\N,\\N");
+    r.close();
+}
+
+#[test]
+fn range_conditions_select_by_typed_order() {
+    let schema = Schema::parse("id:int,score:float,name").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in -100..100 {
+        let score = if i % 10 == 0 { "\\N".to_string() } else { format!("{}.5", i) };
+        r.0.insert(Tuple::parse(&format!("{},{},n{}", i, score, i), 3).unwrap()).unwrap();
+    }
+    for &(query, count) in &[("id<0", 100), ("id>=-10", 110), ("id>9,name<n2", 10), ("id!=5", 199),
+                             ("id BETWEEN -5 AND 5", 11), ("id between 5 and -5", 0),
+                             ("score>=0", 90), ("score<-0.5", 90), ("score!=0.5", 180),
+                             ("id>=10,score BETWEEN 9 AND 20", 9), ("name<n2", 112)] {
+        let query = Query::parse_named(query, &r.0.schema).unwrap();
+        assert_eq!(r.0.select(&query).count(), count, "{:?}", query);
+    }
+    let query = Query::parse_named("id>90,score IS NOT NULL", &r.0.schema).unwrap();
+    let mut ids: Vec<String> = r.0.select(&query)
+        .map(|t| t.unwrap().values[0].clone().unwrap())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec!["91", "92", "93", "94", "95", "96", "97", "98", "99"]);
+    r.close();
+}
+
+#[test]
+fn pattern_conditions_select_matching_text() {
+    let schema = Schema::parse("level,msg,line:int").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    let levels = ["INFO", "WARN", "ERROR"];
+    for i in 0..300 {
+        let msg = format!("\"request {} took {}ms, 100% done\"", i, i % 7);
+        r.0.insert(Tuple::parse(&format!("{},{},{}", levels[i % 3], msg, i), 3).unwrap()).unwrap();
+    }
+    for &(query, count) in &[("level=ERR*", 100), ("msg LIKE request 1%", 111),
+                             ("msg LIKE %took _ms%", 300), ("msg LIKE %\"% \"done", 300),
+                             ("msg LIKE %5\\%%", 0), ("msg=/took [56]ms/", 85),
+                             ("msg=/^request \\d{2} /,level=INFO", 30), ("line=29*", 11),
+                             ("level=\"ERR*\"", 0)] {
+        let query = Query::parse_named(query, &r.0.schema).unwrap();
+        assert_eq!(r.0.select(&query).count(), count, "{:?}", query);
+    }
+    let query = Query::parse("/R$/,?,?", 3).unwrap();
+    assert_eq!(r.0.select(&query).count(), 100);
+    r.close();
+}
+
+#[test]
+fn in_lists_select_each_tuple_once() {
+    let schema = Schema::parse("id:int,group:int,tag").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..500 {
+        r.0.insert(Tuple::parse(&format!("{},{},t{}", i, i % 10, i % 4), 3).unwrap()).unwrap();
+    }
+    let ids = |r: &TestRelation, query: &str| -> Vec<String> {
+        let query = Query::parse_named(query, &r.0.schema).unwrap();
+        let mut ids: Vec<String> = r.0.select(&query)
+            .map(|t| t.unwrap().values[0].clone().unwrap())
+            .collect();
+        ids.sort();
+        ids
+    };
+    let mut separately: Vec<String> = ["group=1,tag=t1", "group=1,tag=t3", "group=7,tag=t1",
+                                       "group=7,tag=t3", "group=8,tag=t1", "group=8,tag=t3"]
+        .iter()
+        .flat_map(|query| ids(&r, query))
+        .collect();
+    separately.sort();
+    assert_eq!(ids(&r, "group IN (1, 7, 8, 07),tag IN (t1, t3)"), separately);
+    assert_eq!(separately.len(), 100);
+    assert_eq!(ids(&r, "id IN (3, 300, 3000, 003)"), vec!["3", "300"]);
+
+    // Updates and deletes visit each bucket once, so each tuple changes once.
+    let query = Query::parse_named("group IN (2, 3)", &r.0.schema).unwrap();
+    let num_updated = r.0.update(&query, |t| {
+        let id: i64 = t.values[0].as_ref().unwrap().parse().unwrap();
+        t.values[0] = Some((id + 1000).to_string());
+    }).unwrap();
+    assert_eq!(num_updated, 100);
+    assert_eq!(ids(&r, "id>=1000").len(), 100);
+    let query = Query::parse_named("tag IN (t0, t2, t9)", &r.0.schema).unwrap();
+    assert_eq!(r.0.delete_matching(&query).unwrap(), 250);
+    assert_eq!(r.0.num_tuples, 250);
+    r.close();
+}
+
+#[test]
+fn boolean_queries_select_like_their_parts() {
+    let schema = Schema::parse("id:int,group:int,tag").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..500 {
+        let tag = if i % 5 == 0 { "\\N".to_string() } else { format!("t{}", i % 4) };
+        r.0.insert(Tuple::parse(&format!("{},{},{}", i, i % 10, tag), 3).unwrap()).unwrap();
+    }
+    let ids = |r: &TestRelation, query: &str| -> HashSet<String> {
+        let query = Query::parse_named(query, &r.0.schema).unwrap();
+        r.0.select(&query).map(|t| t.unwrap().values[0].clone().unwrap()).collect()
+    };
+    let all = ids(&r, "id>=0");
+    let or = ids(&r, "(group=1 OR group=2) AND tag!=t1");
+    let separately: HashSet<String> = ids(&r, "group=1,tag!=t1")
+        .union(&ids(&r, "group=2,tag!=t1"))
+        .cloned()
+        .collect();
+    assert_eq!(or, separately);
+    assert_eq!(or.len(), 75);
+
+    // NOT takes every tuple that doesn't match, except those with NULLs that leave the
+    // condition unknown, just like !=.
+    let not = ids(&r, "NOT tag=t1");
+    let not_null = ids(&r, "tag IS NOT NULL");
+    assert_eq!(not, not_null.difference(&ids(&r, "tag=t1")).cloned().collect());
+    assert_eq!(not, ids(&r, "tag!=t1"));
+    // NULL tags still match when the rest of the condition is false without them.
+    assert_eq!(ids(&r, "NOT (tag=t1 AND group=1)").len(), all.len() - 25);
+    assert_eq!(ids(&r, "NOT tag IS NOT NULL").len(), 100);
+    assert!(ids(&r, "group=1 AND group=2").is_empty());
+
+    let query = Query::parse_named("group=3 OR tag IS NULL", &r.0.schema).unwrap();
+    assert_eq!(r.0.delete_matching(&query).unwrap(), 150);
+    assert_eq!(r.0.num_tuples, 350);
+    r.close();
+}
+
+#[test]
+fn projection_yields_requested_attributes() {
+    let schema = Schema::parse("id:int,group:int,tag").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..200 {
+        r.0.insert(Tuple::parse(&format!("{},{},t{}", i, i % 10, i % 4), 3).unwrap()).unwrap();
+    }
+    // A tuple too large for a page, stored as a blob.
+    let large = Tuple {
+        values: vec![Some("200".to_string()), Some("3".to_string()), Some("A".repeat(DEFAULT_PAGE_SIZE as usize))]
+    };
+    r.0.insert(large).unwrap();
+
+    let columns = Query::parse_columns("tag,id", &r.0.schema).unwrap();
+    let query = Query::parse_named("group=3 AND id<100", &r.0.schema).unwrap().project(columns);
+    let mut selected: Vec<String> = r.0.select(&query).map(|t| t.unwrap().to_string()).collect();
+    selected.sort();
+    let mut expected: Vec<String> = (0..100)
+        .filter(|i| i % 10 == 3)
+        .map(|i| format!("t{},{}", i % 4, i))
+        .collect();
+    expected.sort();
+    assert_eq!(selected, expected);
+
+    let query = Query::parse_named("group=3", &r.0.schema).unwrap().project(vec![0]);
+    let ids: HashSet<String> = r.0.select(&query).map(|t| t.unwrap().to_string()).collect();
+    assert_eq!(ids.len(), 21);
+    assert!(ids.contains("200"));
+    r.close();
+}
+
+#[test]
+fn select_with_order_limit_and_offset() {
+    let schema = Schema::parse("id:int,group:int,tag").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..300 {
+        let group = if i % 11 == 0 { "\\N".to_string() } else { (i % 7).to_string() };
+        r.0.insert(Tuple::parse(&format!("{},{},t{}", i, group, i % 4), 3).unwrap()).unwrap();
+    }
+    let query = Query::parse_named("id>=50", &r.0.schema).unwrap();
+    let select_with = |query: &Query, options: &SelectOptions| -> Vec<Tuple> {
+        r.0.select_with(query, options).unwrap().map(|t| t.unwrap()).collect()
+    };
+
+    // Without an order, the limit and offset page through tuples in storage order.
+    let all: Vec<Tuple> = r.0.select(&query).map(|t| t.unwrap()).collect();
+    assert_eq!(all.len(), 250);
+    let options = SelectOptions { offset: 10, limit: Some(20), ..SelectOptions::default() };
+    assert_eq!(select_with(&query, &options), &all[10..30]);
+
+    // Ordered by group descending with NULLs last, then by id, in memory and spilled.
+    let mut expected: Vec<(Option<i32>, i32)> = (50..300)
+        .map(|i| (if i % 11 == 0 { None } else { Some(i % 7) }, i))
+        .collect();
+    expected.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    let expected: Vec<String> = expected.iter().map(|&(_, i)| i.to_string()).collect();
+    let order_by = SortKey::parse_list("group DESC,id", &r.0.schema).unwrap();
+    for &memory_budget in &[SelectOptions::default().memory_budget, 500] {
+        let options = SelectOptions { order_by: order_by.clone(), memory_budget: memory_budget,
+                                      ..SelectOptions::default() };
+        let ids: Vec<String> = select_with(&query, &options).iter()
+            .map(|t| t.values[0].clone().unwrap())
+            .collect();
+        assert_eq!(ids, expected);
+
+        // Projected away from the sort keys, with a page of the results.
+        let projected = query.clone().project(vec![2, 0]);
+        let options = SelectOptions { offset: 100, limit: Some(5), ..options };
+        let page: Vec<String> = select_with(&projected, &options).iter().map(|t| t.to_string()).collect();
+        let expected_page: Vec<String> = expected[100..105].iter()
+            .map(|id| format!("t{},{}", id.parse::<u32>().unwrap() % 4, id))
+            .collect();
+        assert_eq!(page, expected_page);
+    }
+    r.close();
+}
+
+#[test]
+fn aggregates_agree_with_select() {
+    let schema = Schema::parse("id:int,group:int,score:float").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..300 {
+        let score = if i % 7 == 0 { "\\N".to_string() } else { format!("{}.5", i % 5) };
+        r.0.insert(Tuple::parse(&format!("{},{},{}", i, i % 3, score), 3).unwrap()).unwrap();
+    }
+    let aggregates = Aggregate::parse_list("count(*),count(score),min(id),max(score),sum(id)", &r.0.schema).unwrap();
+    let query = Query::parse_named("id<100 OR group=0", &r.0.schema).unwrap();
+    let rows = aggregate(&r.0, &query, aggregates.clone(), vec![1]).unwrap();
+    assert_eq!(rows.len(), 3);
+    for (group, row) in rows.iter().enumerate() {
+        let matching: Vec<Tuple> = r.0.select(&query)
+            .map(|t| t.unwrap())
+            .filter(|t| t.values[1] == Some(group.to_string()))
+            .collect();
+        let ids: Vec<i64> = matching.iter().map(|t| t.values[0].as_ref().unwrap().parse().unwrap()).collect();
+        let num_scores = matching.iter().filter(|t| t.values[2].is_some()).count();
+        assert_eq!(row.values, vec![
+            Some(group.to_string()),
+            Some(matching.len().to_string()),
+            Some(num_scores.to_string()),
+            Some(ids.iter().min().unwrap().to_string()),
+            Some("4.5".to_string()),
+            Some(ids.iter().sum::<i64>().to_string()),
+        ]);
+    }
+    let rows = aggregate(&r.0, &Query::wildcard(3), aggregates[..1].to_vec(), vec![]).unwrap();
+    assert_eq!(rows, vec![Tuple { values: vec![Some("300".to_string())] }]);
+    r.close();
+}
+
+#[test]
+fn count_agrees_with_select() {
+    let schema = Schema::parse("id:int,group:int,tag").unwrap();
+    let mut r = TestRelation::with_schema(schema, SplitPolicy::default(), DEFAULT_PAGE_SIZE);
+    for i in 0..400 {
+        let tag = if i % 6 == 0 { "\\N".to_string() } else { format!("t{}", i % 4) };
+        r.0.insert(Tuple::parse(&format!("{},{},{}", i, i % 10, tag), 3).unwrap()).unwrap();
+    }
+    // Tuples too large for a page are counted from their blobs.
+    for i in 0..3 {
+        let values = vec![(400 + i).to_string(), "3".to_string(), "A".repeat(DEFAULT_PAGE_SIZE as usize)];
+        r.0.insert(Tuple { values: values.into_iter().map(Some).collect() }).unwrap();
+    }
+    let queries = ["?,?,?", "group=3", "group=03,tag=t1", "tag IS NULL", "id>=100 AND NOT group=3",
+                   "group IN (1, 3) OR tag LIKE t_", "?,3,?", "id=999"];
+    for input in queries.iter() {
+        let query = Query::parse_with_schema(input, &r.0.schema).unwrap();
+        assert_eq!(r.0.count(&query).unwrap(), r.0.select(&query).count() as u64, "{}", input);
+    }
+    assert_eq!(r.0.count(&Query::wildcard(3)).unwrap(), 403);
+    let query = Query::parse_named("group=3", &r.0.schema).unwrap();
+    assert_eq!(r.0.count(&query).unwrap(), 43);
+
+    r.0.delete_matching(&query).unwrap();
+    assert_eq!(r.0.count(&Query::wildcard(3)).unwrap(), 360);
+    assert_eq!(r.0.count(&query).unwrap(), 0);
+    let aggregates = Aggregate::parse_list("count(*)", &r.0.schema).unwrap();
+    let query = Query::parse_named("tag=t2", &r.0.schema).unwrap();
+    let rows = aggregate(&r.0, &query, aggregates, vec![]).unwrap();
+    assert_eq!(rows, vec![Tuple { values: vec![Some("67".to_string())] }]);
+    r.close();
+}
+
+#[test]
+fn unknown_format_version() {
+    let r = TestRelation::new(2);
+    let name = rewrite_info_file(r, |mut info| {
+        info[4..8].copy_from_slice(&[0, 0, 0, 99]);
+        info
+    });
+
+    let err = Relation::open(&name, Reading).err().unwrap();
+    match *format_error(&err) {
+        FormatError::UnknownVersion(99) => (),
+        ref e => panic!("unexpected error: {:?}", e)
+    }
+    let err = Relation::upgrade(&name).unwrap_err();
+    match *format_error(&err) {
+        FormatError::UnknownVersion(99) => (),
+        ref e => panic!("unexpected error: {:?}", e)
+    }
+    for ext in &["info", "data", "ovflow", "blob", "wal"] {
+        fs::remove_file(format!("{}.{}", name, ext)).unwrap();
+    }
+}
+
+#[test]
+fn old_format_version_is_checked_before_creating_log() {
+    let r = TestRelation::new(2);
+    let name = rewrite_info_file(r, |info| {
+        let mut info = without_epoch(info);
+        info[4..8].copy_from_slice(&8u32.to_be_bytes());
+        info
+    });
+    fs::remove_file(wal_file_name(&name)).unwrap();
+
+    let err = Relation::open(&name, Writing).err().unwrap();
+    match *format_error(&err) {
+        FormatError::NeedsUpgrade(8) => (),
+        ref e => panic!("unexpected error: {:?}", e)
+    }
+    assert!(!Path::new(&wal_file_name(&name)).exists());
+    for ext in &["info", "data", "ovflow", "blob"] {
+        fs::remove_file(format!("{}.{}", name, ext)).unwrap();
+    }
+}
+
+#[test]
+fn not_a_relation() {
+    let r = TestRelation::new(2);
+    let name = rewrite_info_file(r, |_| b"definitely not a relation".to_vec());
+
+    let err = Relation::open(&name, Reading).err().unwrap();
+    match *format_error(&err) {
+        FormatError::NotARelation(ref file) => assert_eq!(*file, format!("{}.info", name)),
+        ref e => panic!("unexpected error: {:?}", e)
+    }
+    for ext in &["info", "data", "ovflow", "blob", "wal"] {
+        fs::remove_file(format!("{}.{}", name, ext)).unwrap();
+    }
+}
+
+#[test]
+fn buffer_pool_serves_repeated_reads() {
+    let mut r = TestRelation::new(2);
+    insert_numbered(&mut r, 100);
+    let query = Query::wildcard(2);
+    assert_eq!(r.0.select(&query).count(), 100);
+
+    let before = r.0.pool_stats();
+    assert_eq!(r.0.select(&query).count(), 100);
+    let after = r.0.pool_stats();
+    assert!(after.hits > before.hits);
+    assert_eq!(after.misses, before.misses);
+    assert_eq!(after.num_dirty, 0);
+    r.close();
+}
+
+#[test]
+fn commit_writes_back_cached_pages() {
+    let mut r = TestRelation::with_policy(2, SplitPolicy::Never);
+    r.0.begin().unwrap();
+    insert_numbered(&mut r, 300);
+    assert!(r.0.pool_stats().num_dirty > 0);
+    r.0.commit().unwrap();
+    assert_eq!(r.0.pool_stats().num_dirty, 0);
+
+    let name = r.0.name.clone();
+    mem::drop(r);
+    let r = TestRelation(Relation::open(&name, Reading).unwrap());
+    assert_eq!(r.0.select(&Query::wildcard(2)).count(), 300);
+    r.close();
+}
+
+#[test]
+fn special_characters_round_trip() {
+    let mut r = TestRelation::new(2);
+    let values = ["a,b", "why?", "?", "nul\0byte", "\"quoted\"", "back\\slash", ""];
+    for (i, value) in values.iter().enumerate() {
+        r.0.insert(Tuple { values: vec![Some(i.to_string()), Some(value.to_string())] }).unwrap();
+    }
+    for (i, value) in values.iter().enumerate() {
+        let tuple = Tuple { values: vec![Some(i.to_string()), Some(value.to_string())] };
+        let query = Query::parse(&tuple.to_string(), 2).unwrap();
+        let found: Vec<Tuple> = r.0.select(&query).map(|t| t.unwrap()).collect();
+        assert_eq!(found, vec![tuple]);
+    }
+    assert_eq!(r.0.select(&Query::parse("?,\\?", 2).unwrap()).count(), 1);
+    r.close();
+}
+
+#[test]
+fn large_pages_hold_large_tuples() {
+    let page_size = 16 * 1024;
+    let mut r = TestRelation::with_page_size(1, page_size);
+    // Leave room for the record header, and the tuple's value count and value length.
+    let large = Tuple { values: vec![Some("A".repeat(max_record_size(page_size) - RECORD_HEADER_SIZE - 8))] };
+    r.0.insert(large.clone()).unwrap();
+    // The tuple fits in a page, so it isn't stored out of line.
+    assert_eq!(get_next_page_id(&r.0.blob_file).unwrap(), 0);
+
+    let name = r.0.name.clone();
+    mem::drop(r);
+    let r = TestRelation(Relation::open(&name, Reading).unwrap());
+    assert_eq!(r.0.page_size, page_size);
+    assert_eq!(r.0.select(&Query::wildcard(1)).next().unwrap().unwrap(), large);
+    r.close();
+}
+
+#[test]
+fn small_pages_split_sooner() {
+    let mut small = TestRelation::with_page_size(2, MIN_PAGE_SIZE);
+    let mut default = TestRelation::new(2);
+    insert_numbered(&mut small, 500);
+    insert_numbered(&mut default, 500);
+    assert!(small.0.num_pages > default.0.num_pages);
+    assert_eq!(small.0.select(&Query::wildcard(2)).count(), 500);
+    small.close();
+    default.close();
+}
+
+#[test]
+fn invalid_page_sizes_rejected() {
+    for &page_size in &[0, 256, 1000, 128 * 1024] {
+        let name = test_relation_name();
+        let choice_vec = ChoiceVec::new(vec![], 1);
+        assert!(Relation::new(&name, Schema::untyped(1), 1, choice_vec, SplitPolicy::default(), page_size).is_err());
+        assert!(!Relation::exists(&name));
+    }
+}
+
+/// Insert tuples keyed by number, where every third tuple is too large for a page.
+fn insert_large_numbered(r: &mut TestRelation, num_tuples: u32) -> Vec<Tuple> {
+    let tuples: Vec<Tuple> = (0..num_tuples).map(|i| {
+        let len = if i % 3 == 0 { 1000 + 37 * i as usize } else { 20 };
+        Tuple { values: vec![Some(i.to_string()), Some("x".repeat(len))] }
+    }).collect();
+    for tuple in &tuples {
+        r.0.insert(tuple.clone()).unwrap();
+    }
+    tuples
+}
+
+#[test]
+fn large_tuples_round_trip() {
+    let mut r = TestRelation::new(2);
+    let tuples = insert_large_numbered(&mut r, 200);
+    assert!(r.0.num_pages > 1);
+    assert!(get_next_page_id(&r.0.blob_file).unwrap() > 0);
+
+    for tuple in &tuples {
+        let query = Query::from_matches(vec![AttrMatch::Value(tuple.values[0].as_ref().unwrap().as_str().into()), AttrMatch::Any]);
+        let results: Vec<Tuple> = r.0.select(&query).map(|t| t.unwrap()).collect();
+        assert_eq!(results, vec![tuple.clone()]);
+    }
+    r.close();
+}
+
+#[test]
+fn deleted_large_tuples_free_blob_pages() {
+    let mut r = TestRelation::new(2);
+    insert_large_numbered(&mut r, 60);
+    let num_blob_pages = get_next_page_id(&r.0.blob_file).unwrap();
+
+    r.0.delete_matching(&Query::wildcard(2)).unwrap();
+    assert_eq!(r.0.blob_free_list.len, num_blob_pages);
+    r.0.is_sane();
+
+    insert_large_numbered(&mut r, 60);
+    assert_eq!(get_next_page_id(&r.0.blob_file).unwrap(), num_blob_pages);
+    assert_eq!(r.0.blob_free_list.len, 0);
+    r.close();
+}
+
+#[test]
+fn cyclic_blob_chain_is_an_error() {
+    let mut r = TestRelation::new(2);
+    let large = Tuple { values: vec![Some("0".to_string()), Some("x".repeat(3 * PAGE_DATA_SIZE))] };
+    r.0.insert(large).unwrap();
+    let name = r.0.name.clone();
+    let last_page_id = get_next_page_id(&r.0.blob_file).unwrap() - 1;
+    drop(r);
+
+    // Point the last page of the chain back at itself.
+    let mut blob_pages = vec![];
+    File::open(format!("{}.blob", name)).unwrap().read_to_end(&mut blob_pages).unwrap();
+    for page in blob_pages.chunks_mut(DEFAULT_PAGE_SIZE as usize) {
+        if page[4..8] == NO_OVFLOW.to_be_bytes() {
+            page[4..8].copy_from_slice(&last_page_id.to_be_bytes());
+            let checksum = crc32(&page[4..]);
+            page[..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+    }
+    write_file(&name, "blob", &blob_pages);
+
+    let mut r = Relation::open(&name, Writing).unwrap();
+    let error = r.select(&Query::wildcard(2)).next().unwrap().unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    r.delete().unwrap();
+}
+
+#[test]
+fn update_large_tuples() {
+    let mut r = TestRelation::new(2);
+    insert_large_numbered(&mut r, 30);
+
+    // Shrink the large tuples, releasing their blobs, and grow the small ones.
+    r.0.update(&Query::wildcard(2), |t| {
+        let large = t.values[1].as_ref().unwrap().len() > 20;
+        t.values[1] = Some(if large { "small".to_string() } else { "y".repeat(3000) });
+    }).unwrap();
+    r.0.is_sane();
+    assert_eq!(r.0.select(&Query::parse("?,small", 2).unwrap()).count(), 10);
+    let large: Vec<Tuple> = r.0.select(&Query::wildcard(2))
+        .map(|t| t.unwrap())
+        .filter(|t| t.values[1].as_ref().unwrap().len() == 3000)
+        .collect();
+    assert_eq!(large.len(), 20);
+    r.close();
+}
+
+#[test]
+fn large_insert_rolled_back() {
+    let mut r = TestRelation::new(2);
+    r.0.begin().unwrap();
+    insert_large_numbered(&mut r, 10);
+    r.0.rollback().unwrap();
+    assert_eq!(r.0.num_tuples, 0);
+    assert_eq!(get_next_page_id(&r.0.blob_file).unwrap(), 0);
+    r.close();
+}